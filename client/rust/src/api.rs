@@ -0,0 +1,164 @@
+//! What the server sends and receives, shared by both sides
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+/// Body of `POST /api/batch`, for beats that were queued while offline
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BeatBatch {
+    /// the beats. named for when batches were only timestamps, which still works
+    pub timestamps: Vec<BatchBeat>,
+}
+
+/// A beat in a [`BeatBatch`]. a bare timestamp is a beat without metadata
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(from = "BatchBeatRepr")]
+pub struct BatchBeat {
+    pub timestamp: NaiveDateTime,
+    #[serde(flatten)]
+    pub meta: BeatMeta,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BatchBeatRepr {
+    Timestamp(NaiveDateTime),
+    Beat {
+        timestamp: NaiveDateTime,
+        #[serde(flatten)]
+        meta: BeatMeta,
+    },
+}
+
+impl From<BatchBeatRepr> for BatchBeat {
+    fn from(repr: BatchBeatRepr) -> Self {
+        match repr {
+            BatchBeatRepr::Timestamp(timestamp) => timestamp.into(),
+            BatchBeatRepr::Beat { timestamp, meta } => Self { timestamp, meta },
+        }
+    }
+}
+
+impl From<NaiveDateTime> for BatchBeat {
+    fn from(timestamp: NaiveDateTime) -> Self {
+        Self {
+            timestamp,
+            meta: BeatMeta::default(),
+        }
+    }
+}
+
+/// What a client knows about a beat, all of it optional. `POST /api/beat` takes it as a json
+/// body or as query parameters
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BeatMeta {
+    /// like `heartbeat-daemon 0.1.0`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<Source>,
+    /// seconds since the last input when the beat was sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_seconds: Option<i64>,
+}
+
+/// What made the client send a beat
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    Keyboard,
+    Mouse,
+    /// the screen was unlocked, like a phone being picked up
+    ScreenUnlock,
+    /// sent by hand
+    Manual,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceStats {
+    pub id: i64,
+    pub name: String,
+    pub beat_count: i64,
+    /// missing from servers that don't track it
+    #[serde(default)]
+    pub health: Option<DeviceHealth>,
+}
+
+/// Whether a device beats the way it usually does
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceHealth {
+    pub status: HealthStatus,
+    /// usual seconds between beats while the device is used
+    pub interval: Option<i64>,
+    /// longest the device went without beats lately, in seconds
+    pub longest_silence: Option<i64>,
+    /// seconds since the last beat
+    pub silent_for: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HealthStatus {
+    Ok,
+    /// there aren't enough beats yet to know what's usual
+    Learning,
+    /// quiet for longer than it ever was lately, the client might have stopped
+    Silent,
+    /// beating more than once a minute
+    TooFast,
+}
+
+/// Body of `GET /api/stats`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Stats {
+    pub active: bool,
+    pub first_beat: Option<NaiveDateTime>,
+    pub last_beat: Option<NaiveDateTime>,
+    /// seconds since the last beat
+    pub time_since_last_beat: Option<i64>,
+    pub total_beats: i32,
+    /// longest absence in seconds
+    pub longest_absence: i64,
+    pub devices: Vec<DeviceStats>,
+    pub status: Option<String>,
+    /// planned absences that haven't ended yet
+    pub planned_absences: Vec<PlannedAbsence>,
+}
+
+/// A time the user knows they'll be away
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlannedAbsence {
+    pub id: i64,
+    pub starts_at: NaiveDateTime,
+    pub ends_at: NaiveDateTime,
+    pub note: String,
+    /// whether absences during this are left out of the longest absence
+    pub exclude_from_record: bool,
+}
+
+/// Most timestamps a [`BeatBatch`] can have. longer queues are sent in chunks
+pub const MAX_BATCH_SIZE: usize = 1000;
+
+/// Answer to a [`BeatBatch`]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchReceipt {
+    /// how many beats were stored
+    pub stored: usize,
+    /// how many were left out, because the device already had beats at those times
+    pub duplicates: usize,
+    /// latest beat of the device after this batch, to resume from
+    pub last_beat: Option<NaiveDateTime>,
+}
+
+/// Body of `GET /api/devices/me`, the device of the token
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    pub id: i64,
+    pub name: String,
+    /// latest beat of this device. queued beats up to this were already stored
+    pub last_beat: Option<NaiveDateTime>,
+    /// seconds this device's clock is ahead of the server's. stored beats are corrected by it, so
+    /// `last_beat` plus this is the device's own time
+    #[serde(default)]
+    pub clock_skew: i64,
+}
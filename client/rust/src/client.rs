@@ -0,0 +1,111 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use reqwest::{
+    header::{ACCEPT, AUTHORIZATION, DATE},
+    RequestBuilder, Response,
+};
+
+use crate::api::{BatchBeat, BatchReceipt, BeatBatch, BeatMeta, DeviceInfo, Stats, MAX_BATCH_SIZE};
+
+/// Talks to the api of a heartbeat server
+///
+/// beats are sent with a device token, or an api token with the `beat:write` scope. stats need an
+/// api token with the `stats:read` scope
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    /// url of the server, without a trailing slash
+    base_url: String,
+    token: String,
+}
+
+impl Client {
+    /// `base_url` is where the server is, including the base path if it has one, like
+    /// `https://example.com/heartbeat`
+    pub fn new(base_url: &str, token: &str) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+        }
+    }
+
+    /// Sends a beat for now, returning the time the server recorded
+    pub async fn beat(&self, meta: &BeatMeta) -> Result<DateTime<Utc>> {
+        let response = self
+            .send(self.http.post(self.url("/api/beat")).json(meta))
+            .await?;
+        let timestamp = response.text().await?;
+
+        timestamp
+            .trim()
+            .parse()
+            .ok()
+            .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+            .ok_or_else(|| anyhow!("the server answered with {timestamp}, which isn't a time"))
+    }
+
+    /// Sends beats from the past. at most [`MAX_BATCH_SIZE`] can be sent at once
+    ///
+    /// sending the same beats again is safe, the ones the server already has are left out
+    pub async fn batch(&self, beats: &[BatchBeat]) -> Result<BatchReceipt> {
+        if beats.len() > MAX_BATCH_SIZE {
+            return Err(anyhow!(
+                "batches can have at most {MAX_BATCH_SIZE} beats, got {}",
+                beats.len()
+            ));
+        }
+
+        let batch = BeatBatch {
+            timestamps: beats.to_vec(),
+        };
+        let response = self
+            .send(
+                self.http
+                    .post(self.url("/api/batch"))
+                    .header(ACCEPT, "application/json")
+                    .json(&batch),
+            )
+            .await?;
+
+        Ok(response.json().await?)
+    }
+
+    /// The device of the token, with its latest beat
+    pub async fn device(&self) -> Result<DeviceInfo> {
+        let response = self
+            .send(self.http.get(self.url("/api/devices/me")))
+            .await?;
+
+        Ok(response.json().await?)
+    }
+
+    pub async fn stats(&self) -> Result<Stats> {
+        let response = self.send(self.http.get(self.url("/api/stats"))).await?;
+
+        Ok(response.json().await?)
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base_url)
+    }
+
+    /// Sends the request with the token, turning error responses into errors
+    async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        // the server measures how far off this computer's clock is from the date
+        let date = Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        let response = request
+            .header(AUTHORIZATION, &self.token)
+            .header(DATE, date)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow!("the server answered {status}: {body}"));
+        }
+
+        Ok(response)
+    }
+}
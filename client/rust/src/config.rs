@@ -0,0 +1,56 @@
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::{anyhow, Result};
+
+/// Settings of the daemon, read from the environment, or from `$HEARTBEAT_HOME/config`:
+///
+/// - `HEARTBEAT_HOSTNAME`: url of the server, like `https://your.heartbeat.domain`
+/// - `HEARTBEAT_AUTH`: token of this device
+/// - `HEARTBEAT_HOME`: where the config and the queue of unsent beats are. defaults to
+///   `~/.heartbeat`
+/// - `HEARTBEAT_IDLE_SECONDS`: no beats are sent after this long without input. defaults to 120
+/// - `HEARTBEAT_INTERVAL_SECONDS`: how often to check for input. defaults to 60
+pub struct Config {
+    pub server: String,
+    pub token: String,
+    pub home: PathBuf,
+    pub idle: Duration,
+    pub interval: Duration,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self> {
+        let home = match std::env::var_os("HEARTBEAT_HOME") {
+            Some(home) => PathBuf::from(home),
+            None => std::env::var_os("HOME")
+                .map(|home| PathBuf::from(home).join(".heartbeat"))
+                .ok_or_else(|| anyhow!("neither HEARTBEAT_HOME nor HOME are set"))?,
+        };
+
+        // the same file the macos script reads. variables that are already set win
+        let config = home.join("config");
+        if config.exists() {
+            dotenv::from_path(&config)
+                .map_err(|err| anyhow!("couldn't read {}: {err}", config.display()))?;
+        }
+
+        let var = |name: &str| std::env::var(name).map_err(|_| anyhow!("{name} isn't set"));
+        let seconds = |name: &str, default: u64| -> Result<Duration> {
+            match std::env::var(name) {
+                Ok(seconds) => seconds
+                    .parse()
+                    .map(Duration::from_secs)
+                    .map_err(|_| anyhow!("{name} {seconds} isn't a number of seconds")),
+                Err(_) => Ok(Duration::from_secs(default)),
+            }
+        };
+
+        Ok(Self {
+            server: var("HEARTBEAT_HOSTNAME")?,
+            token: var("HEARTBEAT_AUTH")?,
+            home,
+            idle: seconds("HEARTBEAT_IDLE_SECONDS", 120)?,
+            interval: seconds("HEARTBEAT_INTERVAL_SECONDS", 60)?,
+        })
+    }
+}
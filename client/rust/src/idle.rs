@@ -0,0 +1,120 @@
+//! How long the user hasn't touched the computer for
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Time since the last input, or `None` if there's no way to tell on this system
+pub fn idle_time() -> Option<Duration> {
+    #[cfg(target_os = "linux")]
+    {
+        x11_idle_time().or_else(logind_idle_time)
+    }
+    #[cfg(target_os = "macos")]
+    {
+        macos_idle_time()
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    {
+        None
+    }
+}
+
+/// Asks the X server through the screensaver extension
+#[cfg(target_os = "linux")]
+fn x11_idle_time() -> Option<Duration> {
+    use x11rb::{connection::Connection, protocol::screensaver::ConnectionExt};
+
+    std::env::var_os("DISPLAY")?;
+    let (connection, screen) = x11rb::connect(None).ok()?;
+    let root = connection.setup().roots.get(screen)?.root;
+    let info = connection.screensaver_query_info(root).ok()?.reply().ok()?;
+
+    Some(Duration::from_millis(info.ms_since_user_input.into()))
+}
+
+/// Asks logind, which desktops (wayland ones too) tell when the session goes idle or gets locked
+#[cfg(target_os = "linux")]
+fn logind_idle_time() -> Option<Duration> {
+    let session = std::env::var("XDG_SESSION_ID").unwrap_or_else(|_| "auto".to_string());
+    let output = std::process::Command::new("loginctl")
+        .args(["show-session", &session])
+        .args(["-p", "IdleHint", "-p", "IdleSinceHint", "-p", "LockedHint"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    parse_logind(&String::from_utf8_lossy(&output.stdout), SystemTime::now())
+}
+
+/// Parses `loginctl show-session` output, like `IdleHint=yes`, one property per line
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_logind(output: &str, now: SystemTime) -> Option<Duration> {
+    let property = |name: &str| {
+        output.lines().find_map(|line| {
+            let (key, value) = line.split_once('=')?;
+            (key == name).then_some(value.trim())
+        })
+    };
+
+    let idle = property("IdleHint")? == "yes";
+    let locked = property("LockedHint") == Some("yes");
+    if !idle && !locked {
+        return Some(Duration::ZERO);
+    }
+
+    // microseconds since the epoch. a locked session that isn't idle yet has been idle since
+    // before it was locked
+    let since = property("IdleSinceHint")
+        .and_then(|since| since.parse().ok())
+        .filter(|&since| idle && since > 0)
+        .map(|since| UNIX_EPOCH + Duration::from_micros(since));
+    match since {
+        Some(since) => Some(now.duration_since(since).unwrap_or_default()),
+        None => Some(Duration::MAX),
+    }
+}
+
+/// Reads how long ago the last input event was from IOKit
+#[cfg(target_os = "macos")]
+fn macos_idle_time() -> Option<Duration> {
+    let output = std::process::Command::new("ioreg")
+        .args(["-c", "IOHIDSystem"])
+        .output()
+        .ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+
+    // "HIDIdleTime" = 1234567, in nanoseconds
+    let line = output
+        .lines()
+        .find(|line| line.contains("\"HIDIdleTime\""))?;
+    let nanos = line.rsplit_once('=')?.1.trim().parse().ok()?;
+    Some(Duration::from_nanos(nanos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_logind_hints() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        assert_eq!(
+            Some(Duration::ZERO),
+            parse_logind("IdleHint=no\nIdleSinceHint=0\nLockedHint=no\n", now)
+        );
+        assert_eq!(
+            Some(Duration::from_secs(300)),
+            parse_logind(
+                "IdleHint=yes\nIdleSinceHint=999700000000\nLockedHint=no\n",
+                now
+            )
+        );
+        assert_eq!(
+            Some(Duration::MAX),
+            parse_logind("IdleHint=no\nIdleSinceHint=0\nLockedHint=yes\n", now)
+        );
+        assert_eq!(None, parse_logind("Failed to get session", now));
+    }
+}
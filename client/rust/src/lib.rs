@@ -0,0 +1,16 @@
+//! Client for a heartbeat server
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! let client = heartbeat_client::Client::new("https://your.heartbeat.domain", "device token");
+//! client.beat(&Default::default()).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod api;
+#[cfg(feature = "client")]
+mod client;
+
+#[cfg(feature = "client")]
+pub use client::Client;
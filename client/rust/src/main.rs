@@ -0,0 +1,128 @@
+//! Sends a beat every minute while the computer is being used, and keeps them for later while
+//! the server can't be reached
+
+mod config;
+mod idle;
+mod queue;
+
+use anyhow::Result;
+use chrono::{TimeDelta, Utc};
+use heartbeat_client::{
+    api::{BatchBeat, BeatMeta, MAX_BATCH_SIZE},
+    Client,
+};
+
+use config::Config;
+use queue::Queue;
+
+const CLIENT_VERSION: &str = concat!("heartbeat-daemon ", env!("CARGO_PKG_VERSION"));
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    tracing_subscriber::fmt().init();
+
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(err) = run(config).await {
+        tracing::error!("{err}");
+        std::process::exit(1);
+    }
+}
+
+async fn run(config: Config) -> Result<()> {
+    std::fs::create_dir_all(&config.home)?;
+    let mut queue = Queue::load(config.home.join("queue.json"))?;
+    let client = Client::new(&config.server, &config.token);
+
+    // beats the server got before the daemon stopped, without it knowing
+    match client.device().await {
+        Ok(device) => {
+            if let Some(last_beat) = device.last_beat {
+                queue.remove_until(last_beat + TimeDelta::seconds(device.clock_skew));
+            }
+        }
+        Err(err) => tracing::warn!("couldn't reach the server: {err}"),
+    }
+
+    if tokio::task::spawn_blocking(idle::idle_time)
+        .await?
+        .is_none()
+    {
+        tracing::warn!("can't tell when this computer is idle, so beats are always sent");
+    }
+
+    let mut shutdown = std::pin::pin!(shutdown_signal());
+    let mut interval = tokio::time::interval(config.interval);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.as_mut() => break,
+        }
+
+        let idle = tokio::task::spawn_blocking(idle::idle_time).await?;
+        if idle.is_none_or(|idle| idle < config.idle) {
+            queue.push(BatchBeat {
+                timestamp: Utc::now().naive_utc(),
+                meta: BeatMeta {
+                    client_version: Some(CLIENT_VERSION.to_string()),
+                    source: None,
+                    idle_seconds: idle.map(|idle| idle.as_secs() as i64),
+                },
+            });
+        }
+
+        send(&client, &mut queue).await;
+        queue.save()?;
+    }
+
+    queue.save()
+}
+
+/// Sends the queue in chunks the server accepts. what couldn't be sent is tried again next time
+async fn send(client: &Client, queue: &mut Queue) {
+    let queued = queue.beats().len();
+    while !queue.beats().is_empty() {
+        let chunk = &queue.beats()[..queue.beats().len().min(MAX_BATCH_SIZE)];
+        match client.batch(chunk).await {
+            Ok(_) => queue.remove_sent(chunk.len()),
+            Err(err) => {
+                tracing::warn!("couldn't send {} beats: {err}", queue.beats().len());
+                return;
+            }
+        }
+    }
+
+    if queued > 1 {
+        tracing::info!("sent {queued} queued beats");
+    }
+}
+
+/// Resolves on ctrl-c or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("couldn't listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("couldn't listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
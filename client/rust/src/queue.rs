@@ -0,0 +1,101 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use chrono::NaiveDateTime;
+use heartbeat_client::api::BatchBeat;
+
+/// Beats that haven't reached the server yet, kept in a file so they survive restarts
+pub struct Queue {
+    path: PathBuf,
+    beats: Vec<BatchBeat>,
+}
+
+impl Queue {
+    /// Loads the queue at `path`, which is empty if the file isn't there
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let beats = match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json)
+                .with_context(|| format!("{} isn't a queue of beats", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Self { path, beats })
+    }
+
+    pub fn push(&mut self, beat: BatchBeat) {
+        self.beats.push(beat);
+    }
+
+    pub fn beats(&self) -> &[BatchBeat] {
+        &self.beats
+    }
+
+    /// Removes the first `count` beats, once they were sent
+    pub fn remove_sent(&mut self, count: usize) {
+        self.beats.drain(..count.min(self.beats.len()));
+    }
+
+    /// Removes the beats up to the latest one the server has, which were sent before a restart
+    /// that lost the answer
+    pub fn remove_until(&mut self, last_beat: NaiveDateTime) {
+        self.beats.retain(|beat| beat.timestamp > last_beat);
+    }
+
+    /// Writes the queue to its file
+    pub fn save(&self) -> Result<()> {
+        // written next to it and renamed, so a crash can't leave half a file
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string(&self.beats)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::{TimeDelta, Utc};
+    use heartbeat_client::api::BeatMeta;
+
+    #[test]
+    fn survives_restarts() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("heartbeat-queue-{}.json", std::process::id()));
+        let now = Utc::now().naive_utc();
+
+        let idle = BatchBeat {
+            timestamp: now,
+            meta: BeatMeta {
+                idle_seconds: Some(30),
+                ..Default::default()
+            },
+        };
+
+        let mut queue = Queue::load(path.clone())?;
+        assert!(queue.beats().is_empty());
+        queue.push((now - TimeDelta::minutes(1)).into());
+        queue.push(idle.clone());
+        queue.save()?;
+
+        let mut queue = Queue::load(path.clone())?;
+        assert_eq!(
+            &[BatchBeat::from(now - TimeDelta::minutes(1)), idle.clone()],
+            queue.beats()
+        );
+        queue.remove_until(now - TimeDelta::minutes(1));
+        assert_eq!(&[idle], queue.beats());
+        queue.remove_sent(1);
+        queue.save()?;
+        assert!(Queue::load(path.clone())?.beats().is_empty());
+
+        // queues from before beats had metadata
+        std::fs::write(&path, serde_json::to_string(&[now])?)?;
+        assert_eq!(&[BatchBeat::from(now)], Queue::load(path.clone())?.beats());
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
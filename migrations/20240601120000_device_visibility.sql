@@ -0,0 +1 @@
+ALTER TABLE devices ADD COLUMN visible BOOLEAN NOT NULL DEFAULT TRUE;
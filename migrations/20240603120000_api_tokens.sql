@@ -0,0 +1,9 @@
+CREATE TABLE api_tokens (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  name TEXT NOT NULL,
+  token TEXT NOT NULL UNIQUE,
+  scopes TEXT NOT NULL,         -- space separated
+  device BIGINT REFERENCES devices(id) ON DELETE CASCADE, -- device beats are sent as, for beat:write
+  expires_at DATETIME,
+  created_at DATETIME NOT NULL
+);
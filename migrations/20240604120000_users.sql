@@ -0,0 +1,31 @@
+-- everything that already exists belongs to the first user. if there's data but no user yet, one is
+-- created here. its password can be set with `heartbeat set-password owner`
+INSERT INTO users (name, password_hash)
+SELECT 'owner', '' WHERE NOT EXISTS (SELECT 1 FROM users) AND EXISTS (SELECT 1 FROM devices);
+
+-- columns added with a foreign key can't have a default other than null
+ALTER TABLE devices ADD COLUMN user BIGINT REFERENCES users(id) ON DELETE CASCADE;
+ALTER TABLE beats ADD COLUMN user BIGINT REFERENCES users(id) ON DELETE CASCADE;
+ALTER TABLE absences ADD COLUMN user BIGINT REFERENCES users(id) ON DELETE CASCADE;
+ALTER TABLE api_tokens ADD COLUMN user BIGINT REFERENCES users(id) ON DELETE CASCADE;
+
+UPDATE devices SET user = (SELECT min(id) FROM users);
+UPDATE beats SET user = (SELECT user FROM devices WHERE devices.id = beats.device);
+UPDATE absences SET user = (SELECT user FROM beats WHERE beats.id = absences.end_beat);
+UPDATE api_tokens SET user = (SELECT min(id) FROM users);
+
+CREATE INDEX devices_user_idx ON devices (user);
+CREATE INDEX beats_user_timestamp_idx ON beats (user, timestamp);
+CREATE INDEX absences_user_timestamp_idx ON absences (user, timestamp);
+
+-- settings are per user now
+CREATE TABLE user_settings (
+  user BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  key TEXT NOT NULL,
+  value TEXT NOT NULL,
+  PRIMARY KEY (user, key)
+);
+INSERT INTO user_settings (user, key, value)
+SELECT (SELECT min(id) FROM users), key, value FROM settings WHERE EXISTS (SELECT 1 FROM users);
+DROP TABLE settings;
+ALTER TABLE user_settings RENAME TO settings;
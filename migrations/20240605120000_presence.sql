@@ -0,0 +1,16 @@
+CREATE TABLE statuses (
+  user BIGINT PRIMARY KEY NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  message TEXT NOT NULL,
+  updated_at DATETIME NOT NULL
+);
+
+CREATE TABLE planned_absences (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  starts_at DATETIME NOT NULL,
+  ends_at DATETIME NOT NULL,
+  note TEXT NOT NULL DEFAULT '',
+  -- absences during this don't count for the longest absence
+  exclude_from_record BOOLEAN NOT NULL DEFAULT FALSE
+);
+CREATE INDEX planned_absences_user_idx ON planned_absences (user, ends_at);
@@ -0,0 +1,2 @@
+-- number of beats a row stands for. old beats get merged into one row per interval by the retention job
+ALTER TABLE beats ADD COLUMN weight INTEGER NOT NULL DEFAULT 1;
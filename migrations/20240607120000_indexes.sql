@@ -0,0 +1,20 @@
+-- device pages and per device stats
+CREATE INDEX beats_device_timestamp_idx ON beats (device, timestamp);
+-- the retention job looks at old beats of every user
+CREATE INDEX beats_timestamp_idx ON beats (timestamp);
+-- deleting beats deletes the absences that start or end on them
+CREATE INDEX absences_begin_beat_idx ON absences (begin_beat);
+CREATE INDEX absences_end_beat_idx ON absences (end_beat);
+
+-- keep the beat count of devices right however beats are added, compacted or removed, so totals
+-- don't need to count every beat
+UPDATE devices SET beat_count = (SELECT coalesce(sum(weight), 0) FROM beats WHERE device = devices.id);
+CREATE TRIGGER beats_insert_count AFTER INSERT ON beats BEGIN
+  UPDATE devices SET beat_count = beat_count + new.weight WHERE id = new.device;
+END;
+CREATE TRIGGER beats_update_count AFTER UPDATE OF weight ON beats BEGIN
+  UPDATE devices SET beat_count = beat_count + new.weight - old.weight WHERE id = new.device;
+END;
+CREATE TRIGGER beats_delete_count AFTER DELETE ON beats BEGIN
+  UPDATE devices SET beat_count = beat_count - old.weight WHERE id = old.device;
+END;
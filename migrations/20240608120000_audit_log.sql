@@ -0,0 +1,10 @@
+CREATE TABLE audit_log (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  timestamp DATETIME NOT NULL,
+  -- null when it's not known who it was, like failed device authentication
+  user BIGINT REFERENCES users(id) ON DELETE CASCADE,
+  ip TEXT,
+  event TEXT NOT NULL,
+  details TEXT NOT NULL -- json
+);
+CREATE INDEX audit_log_user_timestamp_idx ON audit_log (user, timestamp);
@@ -0,0 +1,11 @@
+-- versions repeat in every beat, so they're stored once
+CREATE TABLE client_versions (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  version TEXT NOT NULL UNIQUE
+);
+
+-- 1 keyboard, 2 mouse, 3 screen unlock, 4 manual
+ALTER TABLE beats ADD COLUMN source INTEGER;
+-- seconds since the last input when the beat was sent
+ALTER TABLE beats ADD COLUMN idle_seconds INTEGER;
+ALTER TABLE beats ADD COLUMN client_version INTEGER REFERENCES client_versions(id);
@@ -0,0 +1,6 @@
+-- how far ahead the clock of a device is, in seconds. negative when it's behind
+CREATE TABLE device_clocks (
+  device INTEGER PRIMARY KEY REFERENCES devices(id) ON DELETE CASCADE,
+  skew INTEGER NOT NULL,
+  measured_at DATETIME NOT NULL
+);
@@ -0,0 +1,12 @@
+-- webhooks of third parties that send beats for a device through /api/ingest/<name>
+CREATE TABLE ingest_sources (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  device INTEGER NOT NULL REFERENCES devices(id) ON DELETE CASCADE,
+  name TEXT NOT NULL UNIQUE,
+  secret TEXT NOT NULL,
+  -- json pointers into the payloads, see IngestSource
+  timestamp_pointer TEXT,
+  require_pointer TEXT,
+  created_at DATETIME NOT NULL
+);
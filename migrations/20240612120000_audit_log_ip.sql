@@ -0,0 +1,2 @@
+-- repeated authentication failures from an address are looked up to store them only once
+CREATE INDEX audit_log_ip_timestamp_idx ON audit_log (ip, timestamp);
@@ -1,10 +1,16 @@
+use std::{collections::HashMap, sync::Mutex};
+
 use anyhow::Result;
 use chrono::{DateTime, Duration, NaiveDateTime, Utc};
 use sqlx::{Executor, Sqlite};
 
-use crate::helpers::{date_matches, format_relative, RangeDays};
+use crate::{
+    helpers::{date_matches, format_relative, RangeDays},
+    presence::PlannedAbsence,
+    viewer::Viewer,
+};
 
-#[derive(Debug)]
+#[derive(Debug, serde::Serialize)]
 pub struct Absence {
     pub id: i64,
     /// time this absence ended at
@@ -34,16 +40,25 @@ impl Absence {
         )
     }
 
+    /// rounds the start and end of this absence to what `viewer` can see
+    pub fn coarsen(&mut self, viewer: Viewer) {
+        let start = viewer.coarsen(self.start());
+        let end = viewer.coarsen(self.end());
+        self.timestamp = end.naive_utc();
+        self.duration = (end - start).num_seconds();
+    }
+
     pub fn contains(&self, timestamp: &DateTime<Utc>) -> bool {
-        (self.timestamp.and_utc() - timestamp).num_seconds() < self.duration
+        let before_end = (self.timestamp.and_utc() - *timestamp).num_seconds();
+        before_end > 0 && before_end < self.duration
     }
 
     #[allow(dead_code)]
-    pub async fn count<'c, E>(executor: E) -> Result<i32>
+    pub async fn count<'c, E>(user: i64, executor: E) -> Result<i32>
     where
         E: Executor<'c, Database = Sqlite>,
     {
-        let count = sqlx::query_scalar!("select count(*) from absences")
+        let count = sqlx::query_scalar!("select count(*) from absences where user = ?", user)
             .fetch_one(executor)
             .await?;
         Ok(count)
@@ -53,12 +68,15 @@ impl Absence {
     where
         E: Executor<'c, Database = Sqlite>,
     {
+        // absences belong to the user of the beat that ended them
         let id = sqlx::query!(
-            "insert into absences (timestamp, duration, begin_beat, end_beat) values (?, ?, ?, ?)",
+            "insert into absences (timestamp, duration, begin_beat, end_beat, user)
+            values (?, ?, ?, ?, (select user from beats where id = ?))",
             self.timestamp,
             self.duration,
             self.begin_beat,
             self.end_beat,
+            self.end_beat,
         )
         .execute(executor)
         .await?
@@ -80,49 +98,138 @@ impl Absence {
         Ok(())
     }
 
-    pub async fn get_all_before<'c, E>(timestamp: &NaiveDateTime, executor: E) -> Result<Vec<Self>>
+    /// Gets all absences of `user` that end after `timestamp`
+    pub async fn get_all_after<'c, E>(
+        user: i64,
+        timestamp: &NaiveDateTime,
+        executor: E,
+    ) -> Result<Vec<Self>>
     where
         E: Executor<'c, Database = Sqlite>,
     {
         let beats = sqlx::query_as!(
             Self,
-            "select * from absences where timestamp > ?",
+            "select id as \"id!\", timestamp, duration, begin_beat, end_beat from absences
+            where user = ? and timestamp > ?",
+            user,
             timestamp
         )
         .fetch_all(executor)
         .await?;
         Ok(beats)
     }
+
+    /// Gets all absences of `user` that end from `since` on and before `until`, oldest first
+    pub async fn get_all_between<'c, E>(
+        user: i64,
+        since: &NaiveDateTime,
+        until: Option<&NaiveDateTime>,
+        executor: E,
+    ) -> Result<Vec<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let absences = sqlx::query_as!(
+            Self,
+            "select id as \"id!\", timestamp, duration, begin_beat, end_beat from absences
+            where user = ? and timestamp >= ? and (? is null or timestamp < ?)
+            order by timestamp asc",
+            user,
+            since,
+            until,
+            until
+        )
+        .fetch_all(executor)
+        .await?;
+        Ok(absences)
+    }
+}
+
+/// Which absences to load in [`LongAbsences::get_filtered`]
+pub struct AbsenceFilter {
+    /// only absences that end after this
+    pub from: DateTime<Utc>,
+    /// only absences that start before this
+    pub to: DateTime<Utc>,
+    /// minimum duration in seconds. absences are only stored from an hour, which is the default
+    pub min_duration: i64,
+}
+
+impl Default for AbsenceFilter {
+    fn default() -> Self {
+        Self {
+            from: DateTime::UNIX_EPOCH,
+            to: Utc::now() + Duration::days(1),
+            min_duration: 60 * 60, // 1h
+        }
+    }
 }
 
 pub struct LongAbsences {
     absences: Vec<Absence>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
 }
 
 impl LongAbsences {
-    pub async fn get<'c, E>(executor: E) -> Result<Self>
+    pub async fn get<'c, E>(user: i64, executor: E) -> Result<Self>
     where
         E: Executor<'c, Database = Sqlite>,
     {
+        Self::get_filtered(user, &AbsenceFilter::default(), executor).await
+    }
+
+    pub async fn get_filtered<'c, E>(user: i64, filter: &AbsenceFilter, executor: E) -> Result<Self>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let from = filter.from.naive_utc();
+        let to = filter.to.naive_utc();
         let absences = sqlx::query_as!(
             Absence,
-            "select * from absences where duration > ? order by timestamp desc",
-            60 * 60 // 1h
+            "select id as \"id!\", timestamp, duration, begin_beat, end_beat from absences
+            where user = ? and duration > ?
+            and julianday(timestamp) >= julianday(?)
+            and julianday(timestamp) - duration / 86400.0 <= julianday(?)
+            order by timestamp desc",
+            user,
+            filter.min_duration,
+            from,
+            to,
         )
         .fetch_all(executor)
         .await?;
-        Ok(Self { absences })
+        Ok(Self {
+            absences,
+            from: filter.from,
+            to: filter.to,
+        })
+    }
+
+    /// rounds all absences to what `viewer` can see
+    pub fn coarsen(&mut self, viewer: Viewer) {
+        for absence in &mut self.absences {
+            absence.coarsen(viewer);
+        }
     }
 
-    /// get a range of days that encompasses all the absences
+    /// all long absences, newest first
+    pub fn absences(&self) -> &[Absence] {
+        &self.absences
+    }
+
+    /// get a range of days that encompasses all the absences, clamped to the filtered range
     pub fn range(&self) -> Option<RangeDays> {
         let newest = self.absences.first()?;
         let oldest = self.absences.last()?;
 
-        Some(RangeDays::new(oldest.start(), newest.end()))
+        Some(RangeDays::new(
+            oldest.start().max(self.from),
+            newest.end().min(self.to),
+        ))
     }
 
-    /// get absences that start or end on this day
+    /// get absences that overlap this day
     pub fn absences_on(&self, d: DateTime<Utc>) -> Vec<&Absence> {
         let mut a = self
             .absences
@@ -130,7 +237,7 @@ impl LongAbsences {
             .filter(|abs| {
                 let t1 = abs.start();
                 let t2 = abs.end();
-                date_matches(t1, d) || date_matches(t2, d)
+                date_matches(t1, d) || date_matches(t2, d) || (t1 < d && d < t2)
             })
             .collect::<Vec<_>>();
         a.sort_unstable_by_key(|abs| abs.start());
@@ -138,6 +245,97 @@ impl LongAbsences {
     }
 }
 
+/// Longest absence of each user in seconds
+#[derive(Default)]
+pub struct LongestAbsences(Mutex<HashMap<i64, i64>>);
+
+impl LongestAbsences {
+    /// Loads the longest logged absence of every user, leaving out absences during planned absences
+    /// that are excluded from the record
+    pub async fn load<'c, E>(executor: E) -> Result<Self>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let longest = sqlx::query!(
+            "select user as \"user!\", max(duration) as \"duration!: i64\"
+            from absences where user is not null and not exists (
+                select 1 from planned_absences p
+                where p.user = absences.user and p.exclude_from_record
+                and julianday(p.starts_at) < julianday(absences.timestamp)
+                and julianday(p.ends_at) > julianday(absences.timestamp) - absences.duration / 86400.0
+            )
+            group by user"
+        )
+        .fetch_all(executor)
+        .await?
+        .into_iter()
+        .map(|row| (row.user, row.duration))
+        .collect();
+
+        Ok(Self(Mutex::new(longest)))
+    }
+
+    /// Loads the longest logged absence of `user` again, after their planned absences changed
+    pub async fn refresh<'c, E>(&self, user: i64, executor: E) -> Result<()>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let duration = sqlx::query_scalar!(
+            "select max(duration) as \"duration: i64\" from absences where user = ? and not exists (
+                select 1 from planned_absences p
+                where p.user = absences.user and p.exclude_from_record
+                and julianday(p.starts_at) < julianday(absences.timestamp)
+                and julianday(p.ends_at) > julianday(absences.timestamp) - absences.duration / 86400.0
+            )",
+            user
+        )
+        .fetch_one(executor)
+        .await?
+        .unwrap_or_default();
+
+        let mut longest = self.0.lock().unwrap();
+        longest.insert(user, duration);
+
+        Ok(())
+    }
+
+    pub fn get(&self, user: i64) -> i64 {
+        let longest = self.0.lock().unwrap();
+        longest.get(&user).copied().unwrap_or_default()
+    }
+
+    /// stores `duration` if it's longer than the longest absence of `user`
+    pub fn update(&self, user: i64, duration: i64) {
+        let mut longest = self.0.lock().unwrap();
+        let entry = longest.entry(user).or_default();
+        *entry = (*entry).max(duration);
+    }
+
+    /// Stores the absence of `user` between `start` and `end` if it's their longest one, unless it
+    /// happened during a planned absence that's excluded from the record
+    pub async fn record<'c, E>(
+        &self,
+        user: i64,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        executor: E,
+    ) -> Result<()>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let duration = (end - start).num_seconds();
+        if duration <= self.get(user) {
+            return Ok(());
+        }
+
+        if !PlannedAbsence::excludes(user, start, end, executor).await? {
+            self.update(user, duration);
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use chrono::TimeDelta;
@@ -162,5 +360,30 @@ mod tests {
 
         assert!(!absence.contains(&(now - TimeDelta::seconds(400))));
         assert!(!absence.contains(&(now - TimeDelta::seconds(401))));
+        assert!(!absence.contains(&now));
+        assert!(!absence.contains(&(now + TimeDelta::seconds(1))));
+    }
+
+    #[test]
+    fn multi_day_absences_are_on_every_day() {
+        let end = RangeDays::new(Utc::now(), Utc::now()).next().unwrap() + TimeDelta::hours(2);
+
+        let absences = LongAbsences {
+            absences: vec![Absence {
+                id: 0,
+                timestamp: end.naive_utc(),
+                duration: TimeDelta::days(3).num_seconds(),
+                begin_beat: 0,
+                end_beat: 0,
+            }],
+            from: DateTime::UNIX_EPOCH,
+            to: end,
+        };
+
+        let days = absences.range().unwrap().collect::<Vec<_>>();
+        assert_eq!(4, days.len());
+        for day in days {
+            assert_eq!(1, absences.absences_on(day).len());
+        }
     }
 }
@@ -0,0 +1,313 @@
+use std::{marker::PhantomData, sync::Arc};
+
+use anyhow::Result;
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, StatusCode},
+};
+use chrono::{NaiveDateTime, Utc};
+use sqlx::{Executor, Sqlite};
+
+use crate::{helpers::random_token, AppState};
+
+/// What an api token is allowed to do
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Scope {
+    /// send beats as the token's device
+    #[serde(rename = "beat:write")]
+    BeatWrite,
+    /// read the stats api
+    #[serde(rename = "stats:read")]
+    StatsRead,
+    /// export raw beats and absences
+    #[serde(rename = "export:read")]
+    ExportRead,
+    /// set the status message and planned absences
+    #[serde(rename = "status:write")]
+    StatusWrite,
+    /// everything, including managing tokens
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 5] = [
+        Scope::BeatWrite,
+        Scope::StatsRead,
+        Scope::ExportRead,
+        Scope::StatusWrite,
+        Scope::Admin,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::BeatWrite => "beat:write",
+            Scope::StatsRead => "stats:read",
+            Scope::ExportRead => "export:read",
+            Scope::StatusWrite => "status:write",
+            Scope::Admin => "admin",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|scope| scope.as_str() == s)
+    }
+}
+
+/// A token used to access the api, separate from device tokens
+#[derive(Debug, serde::Serialize)]
+pub struct ApiToken {
+    pub id: i64,
+    /// user whose data this token can access
+    pub user: i64,
+    pub name: String,
+    #[serde(skip)]
+    pub token: String,
+    pub scopes: Vec<Scope>,
+    /// device that beats sent with this token belong to
+    pub device: Option<i64>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+/// Marker for the scope a route requires, see [`Scoped`]
+pub trait RequiredScope {
+    const SCOPE: Scope;
+}
+
+macro_rules! required_scope {
+    ($name:ident) => {
+        pub struct $name;
+        impl RequiredScope for $name {
+            const SCOPE: Scope = Scope::$name;
+        }
+    };
+}
+
+required_scope!(StatsRead);
+required_scope!(ExportRead);
+required_scope!(StatusWrite);
+required_scope!(Admin);
+
+/// Extracts an api token from the `Authorization` header, rejecting the request if the token
+/// doesn't have the `S` scope
+pub struct Scoped<S: RequiredScope>(pub ApiToken, pub PhantomData<S>);
+
+#[async_trait]
+impl<S: RequiredScope> FromRequestParts<Arc<AppState>> for Scoped<S> {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(auth) = parts.headers.get("Authorization") else {
+            return Err((StatusCode::BAD_REQUEST, "authorization header is missing"));
+        };
+
+        let Ok(auth) = auth.to_str() else {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "failed to read Authorization header as string",
+            ));
+        };
+
+        let Ok(Some(token)) = ApiToken::get_valid(auth, &state.pool).await else {
+            return Err((StatusCode::UNAUTHORIZED, "no valid api token found"));
+        };
+
+        if !token.allows(S::SCOPE) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "this token doesn't have the required scope",
+            ));
+        }
+
+        Ok(Scoped(token, PhantomData))
+    }
+}
+
+struct ApiTokenRow {
+    id: i64,
+    user: i64,
+    name: String,
+    token: String,
+    scopes: String,
+    device: Option<i64>,
+    expires_at: Option<NaiveDateTime>,
+    created_at: NaiveDateTime,
+}
+
+impl From<ApiTokenRow> for ApiToken {
+    fn from(row: ApiTokenRow) -> Self {
+        ApiToken {
+            id: row.id,
+            user: row.user,
+            name: row.name,
+            token: row.token,
+            scopes: row
+                .scopes
+                .split_whitespace()
+                .filter_map(Scope::parse)
+                .collect(),
+            device: row.device,
+            expires_at: row.expires_at,
+            created_at: row.created_at,
+        }
+    }
+}
+
+impl ApiToken {
+    /// whether this token can be used for things that need `scope`
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin)
+    }
+
+    pub async fn create<'c, E>(
+        user: i64,
+        name: &str,
+        scopes: &[Scope],
+        device: Option<i64>,
+        expires_at: Option<NaiveDateTime>,
+        executor: E,
+    ) -> Result<Self>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let token = random_token();
+        let scope_list = scopes
+            .iter()
+            .map(|scope| scope.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let created_at = Utc::now().naive_utc();
+
+        let id = sqlx::query!(
+            "insert into api_tokens (user, name, token, scopes, device, expires_at, created_at)
+            values (?, ?, ?, ?, ?, ?, ?)",
+            user,
+            name,
+            token,
+            scope_list,
+            device,
+            expires_at,
+            created_at,
+        )
+        .execute(executor)
+        .await?
+        .last_insert_rowid();
+
+        Ok(ApiToken {
+            id,
+            user,
+            name: name.to_string(),
+            token,
+            scopes: scopes.to_vec(),
+            device,
+            expires_at,
+            created_at,
+        })
+    }
+
+    /// Gets all tokens of `user`
+    pub async fn get_all<'c, E>(user: i64, executor: E) -> Result<Vec<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let tokens = sqlx::query_as!(
+            ApiTokenRow,
+            "select id as \"id!\", user as \"user!\", name, token, scopes, device, expires_at, created_at
+            from api_tokens where user = ? order by id",
+            user
+        )
+        .fetch_all(executor)
+        .await?;
+
+        Ok(tokens.into_iter().map(Into::into).collect())
+    }
+
+    /// Gets a token that hasn't expired yet
+    pub async fn get_valid<'c, E>(token: &str, executor: E) -> Result<Option<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let now = Utc::now().naive_utc();
+        let token = sqlx::query_as!(
+            ApiTokenRow,
+            "select id as \"id!\", user as \"user!\", name, token, scopes, device, expires_at, created_at
+            from api_tokens where token = ? and (expires_at is null or expires_at > ?)",
+            token,
+            now
+        )
+        .fetch_optional(executor)
+        .await?;
+
+        Ok(token.map(Into::into))
+    }
+
+    /// Deletes a token of `user`, returning whether there was one to delete
+    pub async fn delete<'c, E>(user: i64, id: i64, executor: E) -> Result<bool>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let result = sqlx::query!("delete from api_tokens where id = ? and user = ?", id, user)
+            .execute(executor)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::init_state;
+
+    use chrono::TimeDelta;
+
+    #[tokio::test]
+    async fn expired_tokens_are_invalid() -> Result<()> {
+        let state = init_state().await;
+
+        let valid =
+            ApiToken::create(1, "valid", &[Scope::StatsRead], None, None, &state.pool).await?;
+        let expired = ApiToken::create(
+            1,
+            "expired",
+            &[Scope::StatsRead],
+            None,
+            Some((Utc::now() - TimeDelta::days(1)).naive_utc()),
+            &state.pool,
+        )
+        .await?;
+
+        let token = ApiToken::get_valid(&valid.token, &state.pool)
+            .await?
+            .unwrap();
+        assert_eq!(vec![Scope::StatsRead], token.scopes);
+        assert!(ApiToken::get_valid(&expired.token, &state.pool)
+            .await?
+            .is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn admin_allows_everything() {
+        let token = ApiToken {
+            id: 0,
+            user: 1,
+            name: String::new(),
+            token: String::new(),
+            scopes: vec![Scope::Admin],
+            device: None,
+            expires_at: None,
+            created_at: Utc::now().naive_utc(),
+        };
+
+        for scope in Scope::ALL {
+            assert!(token.allows(scope));
+        }
+    }
+}
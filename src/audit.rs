@@ -0,0 +1,192 @@
+use std::net::IpAddr;
+
+use anyhow::Result;
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use sqlx::{Executor, Sqlite};
+
+use crate::api_token::Scope;
+
+/// seconds in which failed authentications from an address for the same reason are stored only
+/// once, so guessing tokens doesn't flood the audit log
+const FAILURE_WINDOW: i64 = 10 * 60;
+
+/// Something worth keeping a record of
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// a request to send beats didn't have a valid device or token
+    DeviceAuthFailed {
+        reason: String,
+    },
+    TokenCreated {
+        token: i64,
+        name: String,
+        scopes: Vec<Scope>,
+    },
+    TokenDeleted {
+        token: i64,
+    },
+    DeviceTokenRegenerated {
+        device: i64,
+    },
+    IngestSourceCreated {
+        source: i64,
+        name: String,
+    },
+    IngestSourceDeleted {
+        source: i64,
+    },
+    BatchUploaded {
+        device: i64,
+        count: usize,
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+    },
+    /// an absence was deleted because beats sent later fell inside it
+    AbsenceDeleted {
+        absence: i64,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+    },
+}
+
+#[derive(Debug)]
+pub struct AuditEntry {
+    pub timestamp: NaiveDateTime,
+    pub ip: Option<String>,
+    pub event: AuditEvent,
+}
+
+impl AuditEvent {
+    /// Stores the event in the audit log, and logs it. repeated authentication failures are
+    /// logged every time, but stored once per [`FAILURE_WINDOW`]
+    pub async fn record<'c, E>(
+        &self,
+        user: Option<i64>,
+        ip: Option<IpAddr>,
+        executor: E,
+    ) -> Result<()>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let details = serde_json::to_value(self)?;
+        let event = details["event"].as_str().unwrap_or_default().to_string();
+        let details = details.to_string();
+        tracing::info!(
+            target: "audit",
+            user,
+            ip = ip.map(tracing::field::display),
+            details,
+            "{self}"
+        );
+
+        let now = Utc::now().naive_utc();
+        let ip = ip.map(|ip| ip.to_string());
+        // nothing is newer than null, so other events are always stored
+        let since = match self {
+            AuditEvent::DeviceAuthFailed { .. } => Some(now - TimeDelta::seconds(FAILURE_WINDOW)),
+            _ => None,
+        };
+        sqlx::query!(
+            "insert into audit_log (timestamp, user, ip, event, details)
+            select ?, ?, ?, ?, ? where not exists (
+                select 1 from audit_log
+                where ip is ? and timestamp > ? and user is ? and event = ? and details = ?
+            )",
+            now,
+            user,
+            ip,
+            event,
+            details,
+            ip,
+            since,
+            user,
+            event,
+            details,
+        )
+        .execute(executor)
+        .await?;
+
+        Ok(())
+    }
+}
+
+impl AuditEntry {
+    /// Gets the latest entries of `user`, newest first
+    pub async fn get_recent<'c, E>(user: i64, limit: i64, executor: E) -> Result<Vec<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let entries = sqlx::query!(
+            "select timestamp, ip, details from audit_log
+            where user = ? order by timestamp desc, id desc limit ?",
+            user,
+            limit
+        )
+        .fetch_all(executor)
+        .await?
+        .into_iter()
+        // entries from newer versions might not parse
+        .filter_map(|row| {
+            Some(AuditEntry {
+                timestamp: row.timestamp,
+                ip: row.ip,
+                event: serde_json::from_str(&row.details).ok()?,
+            })
+        })
+        .collect();
+
+        Ok(entries)
+    }
+}
+
+impl std::fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuditEvent::DeviceAuthFailed { reason } => {
+                write!(f, "device authentication failed: {reason}")
+            }
+            AuditEvent::TokenCreated {
+                token,
+                name,
+                scopes,
+            } => {
+                let scopes = scopes.iter().map(Scope::as_str).collect::<Vec<_>>();
+                write!(
+                    f,
+                    "created token {token} ({name}) with {}",
+                    scopes.join(" ")
+                )
+            }
+            AuditEvent::TokenDeleted { token } => write!(f, "deleted token {token}"),
+            AuditEvent::DeviceTokenRegenerated { device } => {
+                write!(f, "regenerated the token of device {device}")
+            }
+            AuditEvent::IngestSourceCreated { source, name } => {
+                write!(f, "created webhook {source} ({name})")
+            }
+            AuditEvent::IngestSourceDeleted { source } => write!(f, "deleted webhook {source}"),
+            AuditEvent::BatchUploaded {
+                device,
+                count,
+                from,
+                to,
+            } => write!(
+                f,
+                "device {device} sent {count} beats from {} to {}",
+                from.format("%Y/%m/%d %H:%M UTC"),
+                to.format("%Y/%m/%d %H:%M UTC")
+            ),
+            AuditEvent::AbsenceDeleted {
+                absence,
+                start,
+                end,
+            } => write!(
+                f,
+                "deleted absence {absence} from {} to {}",
+                start.format("%Y/%m/%d %H:%M UTC"),
+                end.format("%Y/%m/%d %H:%M UTC")
+            ),
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::{Datelike, NaiveDate, Utc};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    Row, SqlitePool,
+};
+
+/// Writes a consistent copy of the database to `path`, while the server keeps running
+pub async fn backup(pool: &SqlitePool, path: &Path) -> Result<()> {
+    if path.exists() {
+        bail!("{} already exists", path.display());
+    }
+
+    sqlx::query("vacuum into ?")
+        .bind(path.to_string_lossy())
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Checks that `path` is a backup this version can restore: it has to be intact, and every
+/// migration in it has to be one we know about. missing migrations are run on the next start
+pub async fn validate(path: &Path) -> Result<()> {
+    if !path.is_file() {
+        bail!("{} doesn't exist", path.display());
+    }
+
+    let options = SqliteConnectOptions::new().filename(path).read_only(true);
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
+        .await?;
+
+    let integrity: String = sqlx::query_scalar("pragma integrity_check")
+        .fetch_one(&pool)
+        .await?;
+    if integrity != "ok" {
+        bail!("the backup is corrupted: {integrity}");
+    }
+
+    let applied = sqlx::query("select version, success from _sqlx_migrations")
+        .fetch_all(&pool)
+        .await
+        .context("the backup has no migrations table")?;
+    pool.close().await;
+
+    let known = sqlx::migrate!()
+        .iter()
+        .map(|migration| migration.version)
+        .collect::<HashSet<_>>();
+    for row in applied {
+        let version: i64 = row.try_get("version")?;
+        let success: bool = row.try_get("success")?;
+        if !success {
+            bail!("migration {version} failed in the backup");
+        }
+        if !known.contains(&version) {
+            bail!("the backup has migration {version}, which is from a newer version");
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces the database at `database_url` with the backup at `path`. the old database is kept
+/// next to it, ending in `.before-restore-` and the time of the restore
+pub async fn restore(pool: &SqlitePool, database_url: &str, path: &Path) -> Result<()> {
+    validate(path).await?;
+
+    let database = SqliteConnectOptions::from_str(database_url)?
+        .get_filename()
+        .to_path_buf();
+
+    // closing the last connection checkpoints the wal into the database
+    pool.close().await;
+
+    let with_suffix = |suffix: &str| {
+        let mut name = database.clone().into_os_string();
+        name.push(suffix);
+        PathBuf::from(name)
+    };
+
+    // copy first, so the database is only replaced once the whole backup is there
+    let copy = with_suffix(".restoring");
+    std::fs::copy(path, &copy)?;
+    if database.exists() {
+        // every restore keeps its own copy, so restoring twice doesn't lose the original
+        let suffix = format!(
+            ".before-restore-{}",
+            Utc::now().format("%Y-%m-%dT%H-%M-%S%.f")
+        );
+        std::fs::rename(&database, with_suffix(&suffix))?;
+    }
+    for leftover in [with_suffix("-wal"), with_suffix("-shm")] {
+        if leftover.exists() {
+            std::fs::remove_file(leftover)?;
+        }
+    }
+    std::fs::rename(&copy, &database)?;
+
+    Ok(())
+}
+
+/// Daily backups into a directory, read from the environment:
+///
+/// - `BACKUP_DIR`: directory to keep backups in. the job doesn't run if this isn't set
+/// - `BACKUP_KEEP_DAILY`: number of daily backups to keep, defaults to 7
+/// - `BACKUP_KEEP_WEEKLY`: number of weeks to keep one backup of after that, defaults to 4
+#[derive(Clone, Debug)]
+pub struct Backups {
+    pub dir: PathBuf,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+}
+
+impl Backups {
+    pub fn from_env() -> Option<Self> {
+        let dir = std::env::var("BACKUP_DIR").ok()?.into();
+        let keep = |var: &str, default: usize| {
+            std::env::var(var)
+                .ok()
+                .and_then(|a| a.parse().ok())
+                .unwrap_or(default)
+        };
+
+        Some(Self {
+            dir,
+            keep_daily: keep("BACKUP_KEEP_DAILY", 7),
+            keep_weekly: keep("BACKUP_KEEP_WEEKLY", 4),
+        })
+    }
+
+    fn path(&self, date: NaiveDate) -> PathBuf {
+        self.dir
+            .join(format!("heartbeat-{}.sqlite", date.format("%Y-%m-%d")))
+    }
+
+    /// Dates of the backups in the directory
+    fn dates(&self) -> Result<Vec<NaiveDate>> {
+        let mut dates = vec![];
+        for entry in std::fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            let date = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("heartbeat-"))
+                .and_then(|name| name.strip_suffix(".sqlite"))
+                .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok());
+            dates.extend(date);
+        }
+        Ok(dates)
+    }
+
+    /// Makes today's backup if there isn't one yet, and deletes the ones that aren't kept anymore.
+    /// returns the path of the new backup
+    pub async fn run(&self, pool: &SqlitePool) -> Result<Option<PathBuf>> {
+        std::fs::create_dir_all(&self.dir)?;
+
+        let today = Utc::now().date_naive();
+        let path = self.path(today);
+        let created = if path.exists() {
+            None
+        } else {
+            backup(pool, &path).await?;
+            Some(path)
+        };
+
+        for date in rotate(self.dates()?, self.keep_daily, self.keep_weekly) {
+            std::fs::remove_file(self.path(date))?;
+        }
+
+        Ok(created)
+    }
+
+    /// Checks for a new day every hour in the background
+    pub fn spawn(self, pool: SqlitePool) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+            loop {
+                interval.tick().await;
+
+                match self.run(&pool).await {
+                    Ok(Some(path)) => tracing::info!("backed up to {}", path.display()),
+                    Ok(None) => {}
+                    Err(err) => tracing::error!("backup failed: {err}"),
+                }
+            }
+        });
+    }
+}
+
+/// Picks the backups to delete: the newest `daily` are kept, and after those the newest of each of
+/// the `weekly` weeks before
+fn rotate(mut dates: Vec<NaiveDate>, daily: usize, weekly: usize) -> Vec<NaiveDate> {
+    dates.sort_unstable_by(|a, b| b.cmp(a));
+
+    let mut weeks = HashSet::new();
+    let mut delete = vec![];
+    for (i, date) in dates.into_iter().enumerate() {
+        if i < daily {
+            continue;
+        }
+        if weeks.len() < weekly && weeks.insert(date.iso_week()) {
+            continue;
+        }
+        delete.push(date);
+    }
+    delete
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{helpers::random_token, user::User};
+
+    use chrono::Days;
+
+    #[test]
+    fn keeps_daily_and_weekly_backups() {
+        // a wednesday
+        let start = NaiveDate::from_ymd_opt(2024, 6, 5).unwrap();
+        let dates = (0..30)
+            .map(|days| start - Days::new(days))
+            .collect::<Vec<_>>();
+
+        let mut delete = rotate(dates.clone(), 3, 2);
+        delete.sort();
+
+        let mut kept = dates
+            .into_iter()
+            .filter(|date| !delete.contains(date))
+            .collect::<Vec<_>>();
+        kept.sort();
+        assert_eq!(
+            vec![
+                // sundays of the two weeks before
+                NaiveDate::from_ymd_opt(2024, 5, 26).unwrap(),
+                NaiveDate::from_ymd_opt(2024, 6, 2).unwrap(),
+                NaiveDate::from_ymd_opt(2024, 6, 3).unwrap(),
+                NaiveDate::from_ymd_opt(2024, 6, 4).unwrap(),
+                NaiveDate::from_ymd_opt(2024, 6, 5).unwrap(),
+            ],
+            kept
+        );
+    }
+
+    #[tokio::test]
+    async fn backups_can_be_restored() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("heartbeat-test-{}", random_token()));
+        std::fs::create_dir_all(&dir)?;
+
+        // in memory databases can't be backed up
+        let options = SqliteConnectOptions::new()
+            .filename(dir.join("live.sqlite"))
+            .create_if_missing(true);
+        let live = SqlitePoolOptions::new().connect_with(options).await?;
+        sqlx::migrate!().run(&live).await?;
+        User::set_password("annie", "hunter2", &live).await?;
+
+        let path = dir.join("backup.sqlite");
+        backup(&live, &path).await?;
+        validate(&path).await?;
+        // backups don't overwrite anything
+        assert!(backup(&live, &path).await.is_err());
+        live.close().await;
+
+        // a database without migrations can't be restored
+        let empty = dir.join("empty.sqlite");
+        let options = SqliteConnectOptions::new()
+            .filename(&empty)
+            .create_if_missing(true);
+        let pool = SqlitePoolOptions::new().connect_with(options).await?;
+        sqlx::query("create table beats (id integer)")
+            .execute(&pool)
+            .await?;
+        pool.close().await;
+        assert!(validate(&empty).await.is_err());
+
+        let database = dir.join("database.sqlite");
+        let database_url = format!("sqlite://{}", database.display());
+        let options = SqliteConnectOptions::from_str(&database_url)?.create_if_missing(true);
+        let pool = SqlitePoolOptions::new().connect_with(options).await?;
+        restore(&pool, &database_url, &path).await?;
+
+        let pool = SqlitePool::connect(&database_url).await?;
+        let name: String = sqlx::query_scalar("select name from users where id = 1")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!("annie", name);
+
+        // restoring again keeps both of the old databases
+        restore(&pool, &database_url, &path).await?;
+        let kept = std::fs::read_dir(&dir)?
+            .filter(|entry| {
+                entry.as_ref().is_ok_and(|entry| {
+                    entry
+                        .file_name()
+                        .to_string_lossy()
+                        .starts_with("database.sqlite.before-restore-")
+                })
+            })
+            .count();
+        assert_eq!(2, kept);
+
+        std::fs::remove_dir_all(dir)?;
+
+        Ok(())
+    }
+}
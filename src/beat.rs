@@ -1,54 +1,235 @@
+use std::collections::HashMap;
+
 use anyhow::Result;
-use chrono::{DateTime, NaiveDateTime, Utc};
-use sqlx::{Executor, QueryBuilder, Row, Sqlite};
+use chrono::{NaiveDate, NaiveDateTime};
+use heartbeat_client::api::{BatchBeat, BeatMeta, Source};
+use sqlx::{Executor, QueryBuilder, Row, Sqlite, SqliteConnection};
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
 pub struct Beat {
     pub id: i64,
     pub device: i64,
     pub timestamp: NaiveDateTime,
 }
 
-impl Beat {
-    pub fn date(&self) -> DateTime<Utc> {
-        self.timestamp.and_utc()
+/// Number of beats a device sent in a span of time
+pub struct BeatBucket {
+    pub device: i64,
+    /// unix timestamp of the start of the bucket
+    pub start: i64,
+    pub count: i64,
+}
+
+/// Number of beats on a day
+pub struct DayCount {
+    pub day: NaiveDate,
+    pub count: i64,
+}
+
+/// Number of beats in an hour of the week
+pub struct HourOfWeekCount {
+    /// day of the week, with sunday being 0
+    pub weekday: i64,
+    pub hour: i64,
+    pub count: i64,
+}
+
+/// Beats sent by a device compared to all beats, for a week
+pub struct WeekShare {
+    /// first day of the week
+    pub week: NaiveDate,
+    pub device: i64,
+    pub total: i64,
+}
+
+/// Number of beats in an hour of the day
+pub struct HourCount {
+    pub hour: i64,
+    pub count: i64,
+}
+
+/// Which beats to look at, by their device and metadata
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct BeatFilter {
+    pub device: Option<i64>,
+    pub source: Option<Source>,
+    pub client_version: Option<String>,
+    /// only beats sent at most this long after the last input
+    pub max_idle_seconds: Option<i64>,
+}
+
+impl BeatFilter {
+    pub fn is_empty(&self) -> bool {
+        self.device.is_none()
+            && self.source.is_none()
+            && self.client_version.is_none()
+            && self.max_idle_seconds.is_none()
+    }
+
+    /// Adds ` and ...` for every part of the filter
+    fn push_conditions(&self, query_builder: &mut QueryBuilder<Sqlite>) {
+        if let Some(device) = self.device {
+            query_builder.push(" and device = ").push_bind(device);
+        }
+        if let Some(source) = self.source {
+            query_builder
+                .push(" and source = ")
+                .push_bind(source_code(source));
+        }
+        if let Some(version) = &self.client_version {
+            query_builder
+                .push(" and client_version = (select id from client_versions where version = ")
+                .push_bind(version.clone())
+                .push(")");
+        }
+        if let Some(idle) = self.max_idle_seconds {
+            query_builder.push(" and idle_seconds <= ").push_bind(idle);
+        }
     }
+}
+
+/// Amount and span of the beats that match a [`BeatFilter`]
+pub struct BeatSummary {
+    pub count: i64,
+    pub first: Option<NaiveDateTime>,
+    pub last: Option<NaiveDateTime>,
+}
 
+/// How a source is stored, to keep beats small
+fn source_code(source: Source) -> i64 {
+    match source {
+        Source::Keyboard => 1,
+        Source::Mouse => 2,
+        Source::ScreenUnlock => 3,
+        Source::Manual => 4,
+    }
+}
+
+/// Id of `version` in the client_versions table, adding it if it's new
+async fn client_version_id(
+    version: Option<&str>,
+    conn: &mut SqliteConnection,
+) -> Result<Option<i64>> {
+    let Some(version) = version else {
+        return Ok(None);
+    };
+
+    sqlx::query!(
+        "insert into client_versions (version) values (?) on conflict do nothing",
+        version
+    )
+    .execute(&mut *conn)
+    .await?;
+    let id = sqlx::query_scalar!(
+        "select id as \"id!\" from client_versions where version = ?",
+        version
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    Ok(Some(id))
+}
+
+impl Beat {
     pub fn unix_timestamp(&self) -> i64 {
         self.timestamp.and_utc().timestamp()
     }
 
-    pub async fn count<'c, E>(executor: E) -> Result<i32>
+    #[cfg(test)]
+    pub async fn count<'c, E>(user: i64, executor: E) -> Result<i32>
     where
         E: Executor<'c, Database = Sqlite>,
     {
-        let count = sqlx::query_scalar!("select count(*) from beats")
-            .fetch_one(executor)
-            .await?;
+        let count = sqlx::query_scalar!(
+            "select coalesce(sum(weight), 0) as \"count!: i32\" from beats where user = ?",
+            user
+        )
+        .fetch_one(executor)
+        .await?;
         Ok(count)
     }
 
-    /// Gets the last 4000 most recent beats
-    pub async fn get_recent<'c, E>(executor: E) -> Result<Vec<Self>>
+    /// Counts beats of `user` from `timestamp` on, leaving out hidden devices unless `with_hidden`
+    pub async fn count_since<'c, E>(
+        user: i64,
+        timestamp: &NaiveDateTime,
+        with_hidden: bool,
+        executor: E,
+    ) -> Result<i32>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let count = sqlx::query_scalar!(
+            "select coalesce(sum(weight), 0) as \"count!: i32\" from beats where user = ? and timestamp >= ?
+            and (? or device in (select id from devices where visible))",
+            user,
+            timestamp,
+            with_hidden
+        )
+        .fetch_one(executor)
+        .await?;
+        Ok(count)
+    }
+
+    /// Gets all beats of `user` after `timestamp`, most recent first
+    pub async fn get_since<'c, E>(
+        user: i64,
+        timestamp: &NaiveDateTime,
+        executor: E,
+    ) -> Result<Vec<Self>>
     where
         E: Executor<'c, Database = Sqlite>,
     {
         let beats = sqlx::query_as!(
             Self,
-            "select id, device, timestamp from beats order by timestamp desc limit 4000"
+            "select id as \"id!\", device, timestamp from beats where user = ? and timestamp >= ? order by timestamp desc",
+            user,
+            timestamp
         )
         .fetch_all(executor)
         .await?;
         Ok(beats)
     }
 
-    pub async fn get_all_before<'c, E>(timestamp: &NaiveDateTime, executor: E) -> Result<Vec<Self>>
+    /// Counts beats after `timestamp` for each device, grouped in buckets of `bucket` seconds
+    pub async fn get_buckets_since<'c, E>(
+        user: i64,
+        timestamp: &NaiveDateTime,
+        bucket: i64,
+        executor: E,
+    ) -> Result<Vec<BeatBucket>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let buckets = sqlx::query_as!(
+            BeatBucket,
+            "select device as \"device!: i64\",
+            cast(strftime('%s', timestamp) as integer) / ? * ? as \"start!: i64\",
+            sum(weight) as \"count!: i64\"
+            from beats where user = ? and timestamp >= ? group by 1, 2 order by 2",
+            bucket,
+            bucket,
+            user,
+            timestamp
+        )
+        .fetch_all(executor)
+        .await?;
+        Ok(buckets)
+    }
+
+    /// Gets all beats of `user` from `timestamp` on, oldest first
+    pub async fn get_all_since<'c, E>(
+        user: i64,
+        timestamp: &NaiveDateTime,
+        executor: E,
+    ) -> Result<Vec<Self>>
     where
         E: Executor<'c, Database = Sqlite>,
     {
         let beats = sqlx::query_as!(
             Self,
-            "select id, device, timestamp from beats where timestamp >= ? order by timestamp asc",
+            "select id as \"id!\", device, timestamp from beats where user = ? and timestamp >= ? order by timestamp asc, id asc",
+            user,
             timestamp
         )
         .fetch_all(executor)
@@ -56,6 +237,143 @@ impl Beat {
         Ok(beats)
     }
 
+    /// Gets up to `limit` beats of `user` from `since` on, oldest first. beats at `since` are only
+    /// included if their id is greater than `after`, so a page can start where the last one ended
+    pub async fn get_page_since<'c, E>(
+        user: i64,
+        since: &NaiveDateTime,
+        after: i64,
+        limit: i64,
+        executor: E,
+    ) -> Result<Vec<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let beats = sqlx::query_as!(
+            Self,
+            "select id as \"id!\", device, timestamp from beats
+            where user = ? and timestamp >= ? and (timestamp > ? or id > ?)
+            order by timestamp asc, id asc limit ?",
+            user,
+            since,
+            since,
+            after,
+            limit
+        )
+        .fetch_all(executor)
+        .await?;
+        Ok(beats)
+    }
+
+    /// Gets the latest beat of `user` that retention merged others into, if it's from `since` on.
+    /// beats before it are an interval apart, so the gaps between them aren't absences
+    pub async fn compacted_until<'c, E>(
+        user: i64,
+        since: &NaiveDateTime,
+        executor: E,
+    ) -> Result<Option<NaiveDateTime>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        // only looking from `since` on keeps it to a few beats on the index
+        let timestamp = sqlx::query_scalar!(
+            "select max(timestamp) as \"timestamp?: NaiveDateTime\" from beats
+            where user = ? and timestamp >= ? and weight > 1",
+            user,
+            since
+        )
+        .fetch_one(executor)
+        .await?;
+        Ok(timestamp)
+    }
+
+    pub async fn get_by_id<'c, E>(id: i64, executor: E) -> Result<Option<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let beat = sqlx::query_as!(
+            Self,
+            "select id, device, timestamp from beats where id = ?",
+            id
+        )
+        .fetch_optional(executor)
+        .await?;
+        Ok(beat)
+    }
+
+    /// Gets a page of the beats of `user`, most recent first
+    pub async fn get_page<'c, E>(
+        user: i64,
+        offset: i64,
+        limit: i64,
+        executor: E,
+    ) -> Result<Vec<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let beats = sqlx::query_as!(
+            Self,
+            "select id as \"id!\", device, timestamp from beats where user = ? order by timestamp desc limit ? offset ?",
+            user,
+            limit,
+            offset
+        )
+        .fetch_all(executor)
+        .await?;
+        Ok(beats)
+    }
+
+    /// Gets the most recent beat of `user` before `timestamp`
+    pub async fn last_before<'c, E>(
+        user: i64,
+        timestamp: &NaiveDateTime,
+        executor: E,
+    ) -> Result<Option<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let beat = sqlx::query_as!(
+            Self,
+            "select id as \"id!\", device, timestamp from beats where user = ? and timestamp < ? order by timestamp desc limit 1",
+            user,
+            timestamp
+        )
+        .fetch_optional(executor)
+        .await?;
+        Ok(beat)
+    }
+
+    /// Gets the oldest beat of `user` after `timestamp`
+    pub async fn first_after<'c, E>(
+        user: i64,
+        timestamp: &NaiveDateTime,
+        executor: E,
+    ) -> Result<Option<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let beat = sqlx::query_as!(
+            Self,
+            "select id as \"id!\", device, timestamp from beats where user = ? and timestamp > ? order by timestamp asc limit 1",
+            user,
+            timestamp
+        )
+        .fetch_optional(executor)
+        .await?;
+        Ok(beat)
+    }
+
+    /// Deletes the beat. Absences that start or end on it are deleted with it
+    pub async fn delete<'c, E>(&self, executor: E) -> Result<()>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        sqlx::query!("delete from beats where id = ?", self.id)
+            .execute(executor)
+            .await?;
+        Ok(())
+    }
+
     #[allow(dead_code)]
     pub async fn get_by_ids<'c, E>(ids: &[i64], executor: E) -> Result<Vec<Self>>
     where
@@ -86,12 +404,16 @@ impl Beat {
         Ok(beats)
     }
 
+    #[cfg(test)]
     pub async fn create<'c, E>(mut self, pool: E) -> Result<Self>
     where
         E: Executor<'c, Database = Sqlite>,
     {
+        // beats belong to the user that owns their device
         let id = sqlx::query!(
-            "insert into beats (device, timestamp) values (?, ?)",
+            "insert into beats (device, user, timestamp)
+            values (?, (select user from devices where id = ?), ?)",
+            self.device,
             self.device,
             self.timestamp,
         )
@@ -104,24 +426,72 @@ impl Beat {
         Ok(self)
     }
 
-    pub async fn create_many<'c, E>(
+    /// Creates the beat along with what the client said about it
+    pub async fn create_with(
+        mut self,
+        meta: &BeatMeta,
+        conn: &mut SqliteConnection,
+    ) -> Result<Self> {
+        let client_version = client_version_id(meta.client_version.as_deref(), conn).await?;
+        let source = meta.source.map(source_code);
+
+        let id = sqlx::query!(
+            "insert into beats (device, user, timestamp, source, idle_seconds, client_version)
+            values (?, (select user from devices where id = ?), ?, ?, ?, ?)",
+            self.device,
+            self.device,
+            self.timestamp,
+            source,
+            meta.idle_seconds,
+            client_version,
+        )
+        .execute(&mut *conn)
+        .await?
+        .last_insert_rowid();
+
+        self.id = id;
+
+        Ok(self)
+    }
+
+    pub async fn create_many(
         device_id: i64,
-        timestamps: &[NaiveDateTime],
-        executor: E,
-    ) -> Result<Vec<i64>>
-    where
-        E: Executor<'c, Database = Sqlite>,
-    {
-        // https://github.com/launchbadge/sqlx/issues/294
-        let mut query_builder: QueryBuilder<Sqlite> =
-            QueryBuilder::new("insert into beats (device, timestamp) ");
+        beats: &[BatchBeat],
+        conn: &mut SqliteConnection,
+    ) -> Result<Vec<i64>> {
+        let mut versions = HashMap::new();
+        for beat in beats {
+            if let Some(version) = &beat.meta.client_version {
+                if !versions.contains_key(version) {
+                    let id = client_version_id(Some(version), conn).await?;
+                    versions.insert(version.clone(), id);
+                }
+            }
+        }
 
-        query_builder.push_values(timestamps.iter(), |mut b, timestamp| {
-            b.push_bind(device_id).push_bind(timestamp);
+        // https://github.com/launchbadge/sqlx/issues/294
+        let mut query_builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+            "insert into beats (device, user, timestamp, source, idle_seconds, client_version) ",
+        );
+
+        query_builder.push_values(beats.iter(), |mut b, beat| {
+            let version = beat
+                .meta
+                .client_version
+                .as_ref()
+                .and_then(|version| versions[version]);
+            b.push_bind(device_id)
+                .push("(select user from devices where id = ")
+                .push_bind_unseparated(device_id)
+                .push_unseparated(")")
+                .push_bind(beat.timestamp)
+                .push_bind(beat.meta.source.map(source_code))
+                .push_bind(beat.meta.idle_seconds)
+                .push_bind(version);
         });
         query_builder.push("returning id");
 
-        let ids = query_builder.build().fetch_all(executor).await?;
+        let ids = query_builder.build().fetch_all(&mut *conn).await?;
         let ids = ids
             .into_iter()
             .filter_map(|row| row.try_get(0).ok())
@@ -130,28 +500,226 @@ impl Beat {
         Ok(ids)
     }
 
-    pub async fn first_beat<'c, E>(executor: E) -> Result<Option<Self>>
+    /// Counts beats of `user` per day, for days after `since`. hidden devices are left out unless
+    /// `with_hidden`
+    pub async fn count_per_day<'c, E>(
+        user: i64,
+        since: &NaiveDateTime,
+        with_hidden: bool,
+        executor: E,
+    ) -> Result<Vec<DayCount>>
     where
         E: Executor<'c, Database = Sqlite>,
     {
-        let last_beat = sqlx::query_as!(Self, "select * from beats order by timestamp asc limit 1")
-            .fetch_optional(executor)
-            .await?;
+        let counts = sqlx::query_as!(
+            DayCount,
+            "select date(timestamp) as \"day!: NaiveDate\", sum(weight) as \"count!: i64\"
+            from beats where user = ? and timestamp >= ? and (? or device in (select id from devices where visible))
+            group by 1 order by 1",
+            user,
+            since,
+            with_hidden
+        )
+        .fetch_all(executor)
+        .await?;
+        Ok(counts)
+    }
+
+    /// Counts beats per hour of the week, over all beats of `user`. hidden devices are left out
+    /// unless `with_hidden`
+    pub async fn count_per_hour_of_week<'c, E>(
+        user: i64,
+        with_hidden: bool,
+        executor: E,
+    ) -> Result<Vec<HourOfWeekCount>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let counts = sqlx::query_as!(
+            HourOfWeekCount,
+            "select cast(strftime('%w', timestamp) as integer) as \"weekday!: i64\",
+            cast(strftime('%H', timestamp) as integer) as \"hour!: i64\",
+            sum(weight) as \"count!: i64\"
+            from beats where user = ? and (? or device in (select id from devices where visible))
+            group by 1, 2",
+            user,
+            with_hidden
+        )
+        .fetch_all(executor)
+        .await?;
+        Ok(counts)
+    }
+
+    /// Gets the share of beats sent by `device` each week, out of all beats of its user. beats of
+    /// hidden devices are left out of that unless `with_hidden`
+    pub async fn device_share_per_week<'c, E>(
+        device: i64,
+        with_hidden: bool,
+        executor: E,
+    ) -> Result<Vec<WeekShare>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        // weeks start on monday
+        let shares = sqlx::query_as!(
+            WeekShare,
+            "select date(timestamp, 'weekday 0', '-6 days') as \"week!: NaiveDate\",
+            sum((device = ?) * weight) as \"device!: i64\",
+            sum(weight) as \"total!: i64\"
+            from beats where user = (select user from devices where id = ?) and (? or device in (select id from devices where visible))
+            group by 1 order by 1",
+            device,
+            device,
+            with_hidden
+        )
+        .fetch_all(executor)
+        .await?;
+        Ok(shares)
+    }
+
+    /// Counts beats sent by `device` per hour of the day
+    pub async fn device_count_per_hour<'c, E>(device: i64, executor: E) -> Result<Vec<HourCount>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let counts = sqlx::query_as!(
+            HourCount,
+            "select cast(strftime('%H', timestamp) as integer) as \"hour!: i64\",
+            sum(weight) as \"count!: i64\"
+            from beats where device = ? group by 1",
+            device
+        )
+        .fetch_all(executor)
+        .await?;
+        Ok(counts)
+    }
+
+    pub async fn first_beat<'c, E>(user: i64, executor: E) -> Result<Option<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let first_beat = sqlx::query_as!(
+            Self,
+            "select id as \"id!\", device, timestamp from beats where user = ? order by timestamp asc limit 1",
+            user
+        )
+        .fetch_optional(executor)
+        .await?;
+
+        Ok(first_beat)
+    }
+
+    pub async fn last_beat<'c, E>(user: i64, executor: E) -> Result<Option<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let last_beat = sqlx::query_as!(
+            Self,
+            "select id as \"id!\", device, timestamp from beats where user = ? order by timestamp desc limit 1",
+            user
+        )
+        .fetch_optional(executor)
+        .await?;
 
         Ok(last_beat)
     }
 
-    pub async fn last_beat<'c, E>(executor: E) -> Result<Option<Self>>
+    pub async fn first_beat_of<'c, E>(device: i64, executor: E) -> Result<Option<Self>>
     where
         E: Executor<'c, Database = Sqlite>,
     {
-        let last_beat =
-            sqlx::query_as!(Self, "select * from beats order by timestamp desc limit 1")
-                .fetch_optional(executor)
-                .await?;
+        let first_beat = sqlx::query_as!(
+            Self,
+            "select id as \"id!\", device, timestamp from beats where device = ? order by timestamp asc limit 1",
+            device
+        )
+        .fetch_optional(executor)
+        .await?;
+
+        Ok(first_beat)
+    }
+
+    pub async fn last_beat_of<'c, E>(device: i64, executor: E) -> Result<Option<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let last_beat = sqlx::query_as!(
+            Self,
+            "select id as \"id!\", device, timestamp from beats where device = ? order by timestamp desc limit 1",
+            device
+        )
+        .fetch_optional(executor)
+        .await?;
 
         Ok(last_beat)
     }
+
+    /// Counts the beats of `user` that match `filter`, and when the first and last were
+    pub async fn summary<'c, E>(user: i64, filter: &BeatFilter, executor: E) -> Result<BeatSummary>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let mut query_builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+            "select coalesce(sum(weight), 0), min(timestamp), max(timestamp) from beats where user = ",
+        );
+        query_builder.push_bind(user);
+        filter.push_conditions(&mut query_builder);
+
+        let row = query_builder.build().fetch_one(executor).await?;
+        Ok(BeatSummary {
+            count: row.try_get(0)?,
+            first: row.try_get(1)?,
+            last: row.try_get(2)?,
+        })
+    }
+
+    /// Counts the beats of each device of `user` that match `filter`
+    pub async fn count_per_device<'c, E>(
+        user: i64,
+        filter: &BeatFilter,
+        executor: E,
+    ) -> Result<HashMap<i64, i64>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let mut query_builder: QueryBuilder<Sqlite> =
+            QueryBuilder::new("select device, sum(weight) from beats where user = ");
+        query_builder.push_bind(user);
+        filter.push_conditions(&mut query_builder);
+        query_builder.push(" group by device");
+
+        let counts = query_builder
+            .build()
+            .fetch_all(executor)
+            .await?
+            .into_iter()
+            .filter_map(|row| Some((row.try_get(0).ok()?, row.try_get(1).ok()?)))
+            .collect();
+
+        Ok(counts)
+    }
+
+    /// Gets the timestamps of beats of `device` between `from` and `to`, both included
+    pub async fn timestamps_between<'c, E>(
+        device: i64,
+        from: &NaiveDateTime,
+        to: &NaiveDateTime,
+        executor: E,
+    ) -> Result<Vec<NaiveDateTime>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let timestamps = sqlx::query_scalar!(
+            "select timestamp from beats where device = ? and timestamp between ? and ?",
+            device,
+            from,
+            to
+        )
+        .fetch_all(executor)
+        .await?;
+
+        Ok(timestamps)
+    }
 }
 
 #[cfg(test)]
@@ -160,7 +728,8 @@ mod tests {
     use crate::{device::Device, testing::init_state};
 
     use anyhow::Result;
-    use chrono::TimeDelta;
+    use chrono::{DateTime, TimeDelta, Utc};
+    use sqlx::Row;
 
     #[tokio::test]
     async fn can_create_many() -> Result<()> {
@@ -168,9 +737,11 @@ mod tests {
 
         Device {
             id: 1,
+            user: 1,
             name: "test device".to_string(),
             token: "my_token".to_string(),
             beat_count: 0,
+            visible: true,
         }
         .create(&state.pool)
         .await
@@ -179,11 +750,11 @@ mod tests {
         let ids = Beat::create_many(
             1,
             &[
-                (Utc::now() - TimeDelta::days(10)).naive_utc(),
-                (Utc::now() - TimeDelta::days(9)).naive_utc(),
-                (Utc::now() - TimeDelta::days(8)).naive_utc(),
+                (Utc::now() - TimeDelta::days(10)).naive_utc().into(),
+                (Utc::now() - TimeDelta::days(9)).naive_utc().into(),
+                (Utc::now() - TimeDelta::days(8)).naive_utc().into(),
             ],
-            &state.pool,
+            &mut *state.pool.acquire().await?,
         )
         .await?;
 
@@ -198,9 +769,11 @@ mod tests {
 
         Device {
             id: 1,
+            user: 1,
             name: "test device".to_string(),
             token: "my_token".to_string(),
             beat_count: 0,
+            visible: true,
         }
         .create(&state.pool)
         .await
@@ -223,4 +796,81 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn can_get_buckets() -> Result<()> {
+        let state = init_state().await;
+
+        Device {
+            id: 1,
+            user: 1,
+            name: "test device".to_string(),
+            token: "my_token".to_string(),
+            beat_count: 0,
+            visible: true,
+        }
+        .create(&state.pool)
+        .await
+        .unwrap();
+
+        let hour = Utc::now().timestamp() / 3600 * 3600;
+        for minutes in [-70, -50, -40, 5, 10, 15] {
+            Beat {
+                id: 0,
+                device: 1,
+                timestamp: DateTime::from_timestamp(hour + minutes * 60, 0)
+                    .unwrap()
+                    .naive_utc(),
+            }
+            .create(&state.pool)
+            .await?;
+        }
+
+        let since = DateTime::from_timestamp(hour - 3600, 0)
+            .unwrap()
+            .naive_utc();
+        let buckets = Beat::get_buckets_since(1, &since, 3600, &state.pool).await?;
+
+        assert_eq!(2, buckets.len());
+        assert_eq!((hour - 3600, 2), (buckets[0].start, buckets[0].count));
+        assert_eq!((hour, 3), (buckets[1].start, buckets[1].count));
+
+        Ok(())
+    }
+
+    /// the home page and every beat look up the first and latest beats, so they can't scan
+    #[tokio::test]
+    async fn looks_up_beats_by_index() -> Result<()> {
+        let state = init_state().await;
+
+        for (query, index) in [
+            (
+                "select id from beats where user = 1 order by timestamp asc limit 1",
+                "beats_user_timestamp_idx",
+            ),
+            (
+                "select id from beats where user = 1 order by timestamp desc limit 1",
+                "beats_user_timestamp_idx",
+            ),
+            (
+                "select id from beats where device = 1 order by timestamp desc limit 1",
+                "beats_device_timestamp_idx",
+            ),
+            (
+                "select max(timestamp) from beats where user = 1 and timestamp >= '2024-06-01' and weight > 1",
+                "beats_user_timestamp_idx",
+            ),
+        ] {
+            let plan = sqlx::query(&format!("explain query plan {query}"))
+                .fetch_all(&state.pool)
+                .await?
+                .iter()
+                .map(|row| row.get::<String, _>("detail"))
+                .collect::<Vec<_>>()
+                .join("\n");
+            assert!(plan.contains(index), "{query}: {plan}");
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,140 @@
+use anyhow::Result;
+use chrono::{DateTime, TimeDelta, Utc};
+use heartbeat_client::api::{DeviceHealth, HealthStatus};
+use sqlx::SqliteConnection;
+
+use crate::beat::Beat;
+
+/// days of beats before the latest one that the usual pattern of a device is learned from
+const HISTORY_DAYS: i64 = 30;
+/// gaps shorter than this are between beats of the same session
+const SESSION_GAP: i64 = 60 * 60;
+/// fewer session gaps than this aren't enough to tell what's usual
+const MIN_GAPS: usize = 60;
+/// clients beat once a minute, with some leeway for timers firing early
+const MIN_INTERVAL: i64 = 55;
+/// shorter silences are never flagged, so a device that was used all the time lately isn't
+/// flagged on a quiet afternoon
+const MIN_SILENCE: i64 = 12 * 60 * 60;
+
+/// Looks at how `device` beat lately to tell whether its client still works as usual
+pub async fn health(
+    device: i64,
+    now: DateTime<Utc>,
+    conn: &mut SqliteConnection,
+) -> Result<DeviceHealth> {
+    let Some(last_beat) = Beat::last_beat_of(device, &mut *conn).await? else {
+        return Ok(judge(&[], None));
+    };
+
+    let since = last_beat.timestamp - TimeDelta::days(HISTORY_DAYS);
+    let gaps = sqlx::query_scalar!(
+        r#"select gap as "gap!: i64" from (
+            select cast(round((julianday(timestamp) - julianday(lag(timestamp) over (order by timestamp))) * 86400) as integer) as gap
+            from beats where device = ? and timestamp >= ?
+        ) where gap is not null"#,
+        device,
+        since
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let silent_for = (now - last_beat.timestamp.and_utc()).num_seconds();
+    Ok(judge(&gaps, Some(silent_for)))
+}
+
+/// Tells the health of a device from the seconds between its beats, oldest first
+fn judge(gaps: &[i64], silent_for: Option<i64>) -> DeviceHealth {
+    let mut session_gaps = gaps
+        .iter()
+        .copied()
+        .filter(|gap| *gap < SESSION_GAP)
+        .collect::<Vec<_>>();
+    session_gaps.sort_unstable();
+    let interval = session_gaps.get(session_gaps.len() / 2).copied();
+    let longest_silence = gaps.iter().copied().max();
+
+    let status = match interval {
+        _ if session_gaps.len() < MIN_GAPS => HealthStatus::Learning,
+        Some(interval) if interval < MIN_INTERVAL => HealthStatus::TooFast,
+        _ if silent_for > Some(longest_silence.unwrap_or_default().max(MIN_SILENCE)) => {
+            HealthStatus::Silent
+        }
+        _ => HealthStatus::Ok,
+    };
+
+    DeviceHealth {
+        status,
+        interval,
+        longest_silence,
+        silent_for,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{device::Device, testing::init_state};
+
+    #[test]
+    fn judges_gaps() {
+        // a minute apart while used, with a night in between
+        let mut gaps = vec![60; 100];
+        gaps[50] = 8 * 60 * 60;
+
+        let health = judge(&gaps, Some(60));
+        assert_eq!(HealthStatus::Ok, health.status);
+        assert_eq!(Some(60), health.interval);
+        assert_eq!(Some(8 * 60 * 60), health.longest_silence);
+
+        assert_eq!(HealthStatus::Ok, judge(&gaps, Some(10 * 60 * 60)).status);
+        assert_eq!(
+            HealthStatus::Silent,
+            judge(&gaps, Some(13 * 60 * 60)).status
+        );
+
+        gaps[51] = 3 * 24 * 60 * 60;
+        assert_eq!(
+            HealthStatus::Ok,
+            judge(&gaps, Some(2 * 24 * 60 * 60)).status
+        );
+
+        assert_eq!(HealthStatus::TooFast, judge(&[10; 100], Some(10)).status);
+        assert_eq!(HealthStatus::Learning, judge(&[60; 10], Some(60)).status);
+        assert_eq!(HealthStatus::Learning, judge(&[], None).status);
+    }
+
+    #[tokio::test]
+    async fn measures_gaps_of_the_device() -> Result<()> {
+        let state = init_state().await;
+        Device {
+            id: 1,
+            user: 1,
+            name: "laptop".to_string(),
+            token: "laptop_token".to_string(),
+            beat_count: 0,
+            visible: true,
+        }
+        .create(&state.pool)
+        .await?;
+
+        let start = Utc::now() - TimeDelta::days(2);
+        for i in 0..100 {
+            Beat {
+                id: 0,
+                device: 1,
+                timestamp: (start + TimeDelta::seconds(30 * i)).naive_utc(),
+            }
+            .create(&state.pool)
+            .await?;
+        }
+
+        let mut conn = state.pool.acquire().await?;
+        let health = health(1, Utc::now(), &mut conn).await?;
+        assert_eq!(HealthStatus::TooFast, health.status);
+        assert_eq!(Some(30), health.interval);
+        assert_eq!(Some(30), health.longest_silence);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+use anyhow::Result;
+use axum::http::{header::DATE, HeaderMap};
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use sqlx::SqliteConnection;
+
+/// skews smaller than this are ignored, requests take a while and dates only have seconds
+pub const SKEW_TOLERANCE: i64 = 5;
+/// skews from this on are shown on the device page
+pub const LARGE_SKEW: i64 = 60;
+
+/// How far off the clock of a device is
+#[derive(Debug)]
+pub struct DeviceClock {
+    /// seconds the device is ahead of the server, negative when it's behind
+    pub skew: i64,
+    pub measured_at: NaiveDateTime,
+}
+
+impl DeviceClock {
+    pub async fn get(device: i64, conn: &mut SqliteConnection) -> Result<Option<Self>> {
+        let clock = sqlx::query_as!(
+            DeviceClock,
+            "select skew, measured_at from device_clocks where device = ?",
+            device
+        )
+        .fetch_optional(conn)
+        .await?;
+
+        Ok(clock)
+    }
+
+    /// Skew of `device` that its timestamps should be corrected by, 0 if it was never measured
+    pub async fn skew_of(device: i64, conn: &mut SqliteConnection) -> Result<i64> {
+        Ok(Self::get(device, conn).await?.map_or(0, |clock| clock.skew))
+    }
+
+    /// Measures the skew of `device` from the time it says it is. returns the skew its timestamps
+    /// should be corrected by, which is only kept once it's stored
+    pub async fn measure(
+        device: i64,
+        device_now: DateTime<Utc>,
+        conn: &mut SqliteConnection,
+    ) -> Result<i64> {
+        let now = Utc::now();
+        let mut skew = (device_now - now).num_seconds();
+        if skew.abs() < SKEW_TOLERANCE {
+            skew = 0;
+        }
+
+        // small changes are noise, keeping the previous skew corrects the same beats the same
+        // way when they're sent again
+        if let Some(previous) = Self::get(device, conn).await? {
+            if (previous.skew - skew).abs() < SKEW_TOLERANCE {
+                skew = previous.skew;
+            }
+        }
+
+        Ok(skew)
+    }
+
+    /// Keeps `skew` as the one timestamps of `device` are corrected by
+    pub async fn store(device: i64, skew: i64, conn: &mut SqliteConnection) -> Result<()> {
+        let now = Utc::now().naive_utc();
+        sqlx::query!(
+            "insert into device_clocks (device, skew, measured_at) values (?, ?, ?)
+            on conflict (device) do update set skew = excluded.skew, measured_at = excluded.measured_at",
+            device,
+            skew,
+            now
+        )
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Moves a timestamp from the clock of a device to the server's
+pub fn correct(timestamp: NaiveDateTime, skew: i64) -> NaiveDateTime {
+    timestamp - TimeDelta::seconds(skew)
+}
+
+/// The time a request was sent at according to its `Date` header
+pub fn request_date(headers: &HeaderMap) -> Option<DateTime<Utc>> {
+    let date = headers.get(DATE)?.to_str().ok()?;
+    DateTime::parse_from_rfc2822(date)
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn parses_the_date_header() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, request_date(&headers));
+
+        headers.insert(
+            DATE,
+            HeaderValue::from_static("Sun, 06 Nov 1994 08:49:37 GMT"),
+        );
+        assert_eq!(
+            "1994-11-06T08:49:37Z",
+            request_date(&headers)
+                .unwrap()
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        );
+
+        headers.insert(DATE, HeaderValue::from_static("yesterday"));
+        assert_eq!(None, request_date(&headers));
+    }
+}
@@ -0,0 +1,61 @@
+use std::{io::BufRead, path::Path};
+
+use anyhow::{anyhow, bail, Result};
+use sqlx::SqlitePool;
+
+use crate::{backup, retention::Retention, user::User};
+
+const USAGE: &str = "usage:
+  heartbeat                      run the server
+  heartbeat set-password <name>  create an owner, or change their password. reads the password from stdin
+  heartbeat compact [--dry-run]  compact old beats now, as configured by RETENTION_DAYS
+  heartbeat backup <path>        write a copy of the database to <path>, even while the server runs
+  heartbeat restore <path>       replace the database with the backup at <path>. stop the server first";
+
+/// Runs a command given on the command line, instead of the server
+pub async fn run(args: &[String], pool: &SqlitePool) -> Result<()> {
+    match args {
+        [command, name] if command == "set-password" => {
+            eprintln!("password for {name}:");
+            let password = std::io::stdin()
+                .lock()
+                .lines()
+                .next()
+                .ok_or_else(|| anyhow!("no password provided"))??;
+            if password.is_empty() {
+                bail!("the password can't be empty");
+            }
+
+            User::set_password(name, &password, pool).await?;
+            eprintln!("password for {name} set");
+            Ok(())
+        }
+        [command, flags @ ..]
+            if command == "compact" && flags.iter().all(|flag| flag == "--dry-run") =>
+        {
+            let Some(mut retention) = Retention::from_env() else {
+                bail!("RETENTION_DAYS isn't set");
+            };
+            retention.dry_run |= !flags.is_empty();
+
+            let report = retention.run(pool).await?;
+            if retention.dry_run {
+                eprintln!("dry run, nothing was changed");
+            }
+            eprintln!("{report}");
+            Ok(())
+        }
+        [command, path] if command == "backup" => {
+            backup::backup(pool, Path::new(path)).await?;
+            eprintln!("backed up to {path}");
+            Ok(())
+        }
+        [command, path] if command == "restore" => {
+            let database_url = std::env::var("DATABASE_URL")?;
+            backup::restore(pool, &database_url, Path::new(path)).await?;
+            eprintln!("restored {path}");
+            Ok(())
+        }
+        _ => bail!("{USAGE}"),
+    }
+}
@@ -6,15 +6,25 @@ use axum::{
     extract::FromRequestParts,
     http::{request::Parts, StatusCode},
 };
-use sqlx::{Executor, Sqlite};
+use sqlx::{Executor, Sqlite, SqlitePool};
 
-use crate::AppState;
+use crate::{
+    api_token::{ApiToken, Scope},
+    audit::AuditEvent,
+    helpers::random_token,
+    proxy::ClientIp,
+    AppState,
+};
 
 pub struct Device {
     pub id: i64,
+    /// user this device belongs to
+    pub user: i64,
     pub name: String,
     pub token: String,
     pub beat_count: i64,
+    /// whether anonymous visitors can see this device
+    pub visible: bool,
 }
 
 #[async_trait]
@@ -25,36 +35,112 @@ impl FromRequestParts<Arc<AppState>> for Device {
         parts: &mut Parts,
         state: &Arc<AppState>,
     ) -> Result<Self, Self::Rejection> {
-        let Some(auth) = parts.headers.get("Authorization") else {
-            return Err((StatusCode::BAD_REQUEST, "authorization header is missing"));
+        let (status, reason, user) = match authenticate(parts, &state.pool).await {
+            Ok(device) => return Ok(device),
+            Err(failure) => failure,
         };
 
-        let Ok(auth) = auth.to_str() else {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                "failed to read Authorization header as string",
-            ));
+        let Ok(ClientIp(ip)) = ClientIp::from_request_parts(parts, state).await;
+        let event = AuditEvent::DeviceAuthFailed {
+            reason: reason.to_string(),
         };
+        // failing to write the audit log shouldn't change the response
+        if let Err(err) = event.record(user, ip, &state.pool).await {
+            tracing::error!("couldn't record failed device authentication: {err}");
+        }
 
-        let Ok(Some(device)) = Device::get_by_auth(auth, &state.pool).await else {
-            return Err((
-                StatusCode::UNAUTHORIZED,
-                ("no device found with this token"),
-            ));
-        };
+        Err((status, reason))
+    }
+}
 
-        Ok(device)
+/// Finds the device a request is sending beats as. failures come with the user, when the token
+/// belonged to someone
+async fn authenticate(
+    parts: &Parts,
+    pool: &SqlitePool,
+) -> Result<Device, (StatusCode, &'static str, Option<i64>)> {
+    let Some(auth) = parts.headers.get("Authorization") else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "authorization header is missing",
+            None,
+        ));
+    };
+
+    let Ok(auth) = auth.to_str() else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "failed to read Authorization header as string",
+            None,
+        ));
+    };
+
+    authenticate_token(auth, pool).await
+}
+
+/// Finds the device a device token or an api token with the `beat:write` scope sends beats as
+pub async fn authenticate_token(
+    auth: &str,
+    pool: &SqlitePool,
+) -> Result<Device, (StatusCode, &'static str, Option<i64>)> {
+    if let Ok(Some(device)) = Device::get_by_auth(auth, pool).await {
+        return Ok(device);
     }
+
+    // api tokens with the beat:write scope can also send beats, as their device
+    let Ok(Some(token)) = ApiToken::get_valid(auth, pool).await else {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "no device found with this token",
+            None,
+        ));
+    };
+
+    if !token.allows(Scope::BeatWrite) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "this token doesn't have the beat:write scope",
+            Some(token.user),
+        ));
+    }
+
+    let Some(device_id) = token.device else {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "this token isn't linked to a device",
+            Some(token.user),
+        ));
+    };
+
+    let Ok(Some(device)) = Device::get_by_id(device_id, pool).await else {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "no device found with this token",
+            Some(token.user),
+        ));
+    };
+    if device.user != token.user {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "this token's device belongs to someone else",
+            Some(token.user),
+        ));
+    }
+
+    Ok(device)
 }
 
 impl Device {
-    pub async fn get_all<'c, E>(executor: E) -> Result<Vec<Self>>
+    /// Gets all devices of `user`
+    pub async fn get_all<'c, E>(user: i64, executor: E) -> Result<Vec<Self>>
     where
         E: Executor<'c, Database = Sqlite>,
     {
         let device = sqlx::query_as!(
             Device,
-            "select id as \"id!\", name as \"name!\", token, beat_count from devices",
+            "select id as \"id!\", user as \"user!\", name as \"name!\", token, beat_count, visible
+            from devices where user = ?",
+            user
         )
         .fetch_all(executor)
         .await?;
@@ -62,17 +148,34 @@ impl Device {
         Ok(device)
     }
 
+    pub async fn get_by_id<'c, E>(id: i64, executor: E) -> Result<Option<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let device = sqlx::query_as!(
+            Device,
+            "select id as \"id!\", user as \"user!\", name as \"name!\", token, beat_count, visible
+            from devices where id = ?",
+            id
+        )
+        .fetch_optional(executor)
+        .await?;
+
+        Ok(device)
+    }
+
     pub async fn get_by_auth<'c, E>(auth: &str, executor: E) -> Result<Option<Self>>
     where
         E: Executor<'c, Database = Sqlite>,
     {
         let device = sqlx::query_as!(
             Device,
-            "select id as \"id!\", name as \"name!\", token, beat_count from devices where token = ?",
+            "select id as \"id!\", user as \"user!\", name as \"name!\", token, beat_count, visible
+            from devices where token = ?",
             auth
         )
-            .fetch_optional(executor)
-            .await?;
+        .fetch_optional(executor)
+        .await?;
 
         Ok(device)
     }
@@ -83,24 +186,54 @@ impl Device {
         E: Executor<'c, Database = Sqlite>,
     {
         sqlx::query!(
-            "insert into devices (id, name, token, beat_count) values (?, ?, ?, ?)",
+            "insert into devices (id, user, name, token, beat_count, visible) values (?, ?, ?, ?, ?, ?)",
             self.id,
+            self.user,
             self.name,
             self.token,
-            self.beat_count
+            self.beat_count,
+            self.visible
         )
         .execute(executor)
         .await?;
         Ok(())
     }
 
-    pub async fn increase_beat_count<'c, E>(&self, amount: i64, executor: E) -> Result<()>
+    /// Creates a new device for `user` with a random token
+    pub async fn register<'c, E>(user: i64, name: &str, executor: E) -> Result<Self>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let token = random_token();
+        let id = sqlx::query!(
+            "insert into devices (user, name, token) values (?, ?, ?)",
+            user,
+            name,
+            token
+        )
+        .execute(executor)
+        .await?
+        .last_insert_rowid();
+
+        Ok(Device {
+            id,
+            user,
+            name: name.to_string(),
+            token,
+            beat_count: 0,
+            visible: true,
+        })
+    }
+
+    /// Saves the name and visibility of the device
+    pub async fn update<'c, E>(&self, executor: E) -> Result<()>
     where
         E: Executor<'c, Database = Sqlite>,
     {
         sqlx::query!(
-            "update devices set beat_count = beat_count + ? where id = ?",
-            amount,
+            "update devices set name = ?, visible = ? where id = ?",
+            self.name,
+            self.visible,
             self.id,
         )
         .execute(executor)
@@ -108,4 +241,30 @@ impl Device {
 
         Ok(())
     }
+
+    /// Replaces the token of the device with a new random one
+    pub async fn regenerate_token<'c, E>(&mut self, executor: E) -> Result<()>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let token = random_token();
+        sqlx::query!("update devices set token = ? where id = ?", token, self.id)
+            .execute(executor)
+            .await?;
+        self.token = token;
+
+        Ok(())
+    }
+
+    /// Deletes the device, along with its beats
+    pub async fn delete<'c, E>(&self, executor: E) -> Result<()>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        sqlx::query!("delete from devices where id = ?", self.id)
+            .execute(executor)
+            .await?;
+
+        Ok(())
+    }
 }
@@ -6,6 +6,7 @@ use axum::{
 pub enum AppError {
     Anyhow(anyhow::Error),
     Html(Html<String>),
+    Rejection(StatusCode, &'static str),
 }
 
 impl AppError {
@@ -25,6 +26,8 @@ impl IntoResponse for AppError {
                 .into_response(),
 
             AppError::Html(html) => html.into_response(),
+
+            AppError::Rejection(status, message) => (status, message).into_response(),
         }
     }
 }
@@ -1,4 +1,6 @@
+use axum::http::{header::COOKIE, HeaderMap};
 use chrono::{DateTime, Datelike, Days, Timelike, Utc};
+use rand::{distributions::Alphanumeric, Rng};
 
 pub fn date_matches(a: DateTime<Utc>, b: DateTime<Utc>) -> bool {
     a.day() == b.day() && a.month() == b.month() && a.year() == b.year()
@@ -49,6 +51,32 @@ pub fn format_relative(secs: i64) -> String {
     s
 }
 
+/// Gets the value of a cookie from the request headers
+pub fn get_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Generates a random token, to be used for sessions and such
+pub fn random_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+/// Compares two secrets without leaking where they differ through timing
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
 #[derive(Clone)]
 pub struct RangeDays {
     from: DateTime<Utc>,
@@ -121,4 +149,15 @@ mod tests {
         let r = format_relative(1000000000);
         assert_eq!(r, "31 years 8 months 7 days 19h 17m 52s ");
     }
+
+    #[test]
+    fn test_get_cookie() {
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, "a=1; heartbeat_session=abc".parse().unwrap());
+        headers.append(COOKIE, "b=2".parse().unwrap());
+
+        assert_eq!(Some("abc"), get_cookie(&headers, "heartbeat_session"));
+        assert_eq!(Some("2"), get_cookie(&headers, "b"));
+        assert_eq!(None, get_cookie(&headers, "c"));
+    }
 }
@@ -21,6 +21,9 @@ li {
 .inactive {
     color: #d90422;
 }
+.away {
+    color: #9a37ec;
+}
 
 .absences, .recent-beats {
     width: 80vw;
@@ -94,12 +97,55 @@ li {
 .absences .line span.length:hover {
     background-color: #d715d76e;
 }
+.absences .line span.length.planned {
+    background-color: #9a37ec6e;
+}
 
 .recent-beats .beat {
     width: 1px;
     height: 1rem;
     background-color: #800080;
 }
+
+.warning {
+    border-left: 3px solid #d715d7;
+    padding-left: 0.5rem;
+}
+
+.bars {
+    display: block;
+    margin-bottom: 1rem;
+}
+.bars rect {
+    fill: #800080;
+}
+.bars rect:hover {
+    fill: #d715d7;
+}
+
+.heatmap {
+    display: block;
+    margin-bottom: 1rem;
+}
+.heatmap text {
+    font-size: 0.6rem;
+    fill: #e3228f;
+}
+.heatmap .heat-0 {
+    fill: #ffe8ee;
+}
+.heatmap .heat-1 {
+    fill: #f5a3d3;
+}
+.heatmap .heat-2 {
+    fill: #d15fb1;
+}
+.heatmap .heat-3 {
+    fill: #a32a94;
+}
+.heatmap .heat-4 {
+    fill: #800080;
+}
 "#;
 
 pub fn base_template(content: PreEscaped<String>) -> PreEscaped<String> {
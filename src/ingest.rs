@@ -0,0 +1,333 @@
+use std::net::IpAddr;
+
+use anyhow::Result;
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use heartbeat_client::api::BeatMeta;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sqlx::{Executor, Sqlite, SqliteConnection, Transaction};
+
+use crate::{
+    absence::Absence,
+    audit::AuditEvent,
+    beat::Beat,
+    device::Device,
+    helpers::{constant_time_eq, random_token},
+    AppState,
+};
+
+/// Stores a single beat of `device` in `tx`, updating the absences around it, and commits. every
+/// way of sending beats one at a time goes through here
+pub async fn record_beat(
+    state: &AppState,
+    device: &Device,
+    timestamp: DateTime<Utc>,
+    meta: &BeatMeta,
+    ip: Option<IpAddr>,
+    mut tx: Transaction<'_, Sqlite>,
+) -> Result<Beat> {
+    let beat = Beat {
+        id: 0, // id is ignored on create
+        device: device.id,
+        timestamp: timestamp.naive_utc(),
+    }
+    .create_with(meta, &mut tx)
+    .await?;
+
+    let changes = recompute_absences(device.user, &[beat.timestamp], ip, &mut tx).await?;
+
+    // snapshots loaded from here on might already have the beat
+    let write = state.home_snapshots.write(device.user);
+    tx.commit().await?;
+
+    // what's kept in memory only changes once the beat is stored
+    write.record(std::slice::from_ref(&beat));
+    changes.record(device.user, state).await?;
+
+    Ok(beat)
+}
+
+/// How new beats changed the absences of a user, to update the longest absence once they're
+/// committed
+#[derive(Default)]
+#[must_use]
+pub struct AbsenceChanges {
+    /// gaps between beats that were measured
+    gaps: Vec<(DateTime<Utc>, DateTime<Utc>)>,
+    /// whether absences were split by the beats
+    deleted: bool,
+}
+
+impl AbsenceChanges {
+    /// Updates the longest absence of `user`. if an absence was split, the one before it isn't
+    /// known anymore, so it's loaded again
+    pub async fn record(self, user: i64, state: &AppState) -> Result<()> {
+        if self.deleted {
+            return state.longest_absences.refresh(user, &state.pool).await;
+        }
+        for (start, end) in self.gaps {
+            state
+                .longest_absences
+                .record(user, start, end, &state.pool)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Measures the absences of `user` again after new beats at `timestamps`, oldest first. absences
+/// the beats fall into are deleted, and the gaps from the beat before them on are stored as
+/// absences when they're an hour or more
+pub async fn recompute_absences(
+    user: i64,
+    timestamps: &[NaiveDateTime],
+    ip: Option<IpAddr>,
+    tx: &mut SqliteConnection,
+) -> Result<AbsenceChanges> {
+    let mut changes = AbsenceChanges::default();
+    let Some(first_timestamp) = timestamps.first() else {
+        return Ok(changes);
+    };
+
+    // the gap before the first new beat changes too
+    let since = Beat::last_before(user, first_timestamp, &mut *tx)
+        .await?
+        .map_or(*first_timestamp, |beat| beat.timestamp);
+    let beats = Beat::get_all_since(user, &since, &mut *tx).await?;
+    let compacted_until = Beat::compacted_until(user, &since, &mut *tx).await?;
+    let mut absences = Absence::get_all_after(user, &since, &mut *tx).await?;
+
+    let mut idx = 0;
+    'out: while idx < absences.len() {
+        for timestamp in timestamps {
+            let absence = &absences[idx];
+            if absence.contains(&timestamp.and_utc()) {
+                absence.delete(&mut *tx).await?;
+                AuditEvent::AbsenceDeleted {
+                    absence: absence.id,
+                    start: absence.timestamp - TimeDelta::seconds(absence.duration),
+                    end: absence.timestamp,
+                }
+                .record(Some(user), ip, &mut *tx)
+                .await?;
+                absences.remove(idx);
+                changes.deleted = true;
+
+                continue 'out;
+            }
+        }
+
+        idx += 1;
+    }
+
+    for window in beats.windows(2) {
+        let [last_beat, beat] = window else {
+            continue;
+        };
+
+        // old beats were compacted, so the gaps between them were never measured
+        if compacted_until.is_some_and(|until| beat.timestamp <= until) {
+            continue;
+        }
+
+        // if there's already an absence between these two beats, skip
+        if absences
+            .iter()
+            .any(|abs| abs.begin_beat == last_beat.id && abs.end_beat == beat.id)
+        {
+            continue;
+        }
+
+        let diff = beat.timestamp.and_utc() - last_beat.timestamp.and_utc();
+
+        changes
+            .gaps
+            .push((last_beat.timestamp.and_utc(), beat.timestamp.and_utc()));
+
+        // if the absence was longer than 1h, log it
+        if diff.num_hours() >= 1 {
+            Absence {
+                id: 0,
+                timestamp: beat.timestamp,
+                duration: diff.num_seconds(),
+                begin_beat: last_beat.id,
+                end_beat: beat.id,
+            }
+            .create(&mut *tx)
+            .await?;
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Something other than a client that sends beats for a device through `/api/ingest/<name>`,
+/// like a git hook or a chat bot
+#[derive(Debug)]
+pub struct IngestSource {
+    pub id: i64,
+    pub user: i64,
+    pub device: i64,
+    /// part of the url, so it's unique across users
+    pub name: String,
+    /// key payloads are signed with
+    pub secret: String,
+    /// json pointer to the time of the beat in payloads. beats are stored when they arrive
+    /// without one
+    pub timestamp_pointer: Option<String>,
+    /// json pointer to a value payloads need to have to count as a beat, to ignore pings and such
+    pub require_pointer: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+impl IngestSource {
+    pub async fn create<'c, E>(
+        user: i64,
+        device: i64,
+        name: &str,
+        timestamp_pointer: Option<String>,
+        require_pointer: Option<String>,
+        executor: E,
+    ) -> Result<Self>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let secret = random_token();
+        let created_at = Utc::now().naive_utc();
+
+        let id = sqlx::query!(
+            "insert into ingest_sources
+            (user, device, name, secret, timestamp_pointer, require_pointer, created_at)
+            values (?, ?, ?, ?, ?, ?, ?)",
+            user,
+            device,
+            name,
+            secret,
+            timestamp_pointer,
+            require_pointer,
+            created_at,
+        )
+        .execute(executor)
+        .await?
+        .last_insert_rowid();
+
+        Ok(IngestSource {
+            id,
+            user,
+            device,
+            name: name.to_string(),
+            secret,
+            timestamp_pointer,
+            require_pointer,
+            created_at,
+        })
+    }
+
+    /// Gets all sources of `user`
+    pub async fn get_all<'c, E>(user: i64, executor: E) -> Result<Vec<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let sources = sqlx::query_as!(
+            Self,
+            "select id as \"id!\", user, device, name, secret, timestamp_pointer, require_pointer, created_at
+            from ingest_sources where user = ? order by id",
+            user
+        )
+        .fetch_all(executor)
+        .await?;
+
+        Ok(sources)
+    }
+
+    pub async fn get_by_name<'c, E>(name: &str, executor: E) -> Result<Option<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let source = sqlx::query_as!(
+            Self,
+            "select id as \"id!\", user, device, name, secret, timestamp_pointer, require_pointer, created_at
+            from ingest_sources where name = ?",
+            name
+        )
+        .fetch_optional(executor)
+        .await?;
+
+        Ok(source)
+    }
+
+    /// Deletes the source, returning whether there was one to delete
+    pub async fn delete<'c, E>(user: i64, id: i64, executor: E) -> Result<bool>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let result = sqlx::query!(
+            "delete from ingest_sources where id = ? and user = ?",
+            id,
+            user
+        )
+        .execute(executor)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Checks a signature like github's `X-Hub-Signature-256`, `sha256=` and the hex hmac of the
+    /// payload keyed with the secret
+    pub fn verify(&self, payload: &[u8], signature: &str) -> bool {
+        let Some(signature) = signature.strip_prefix("sha256=") else {
+            return false;
+        };
+
+        constant_time_eq(
+            self.sign(payload).as_bytes(),
+            signature.to_ascii_lowercase().as_bytes(),
+        )
+    }
+
+    /// What a webhook signs: the unix time it signed at, a dot and the body. with the time in it,
+    /// a request can't be sent again later as if it were new
+    pub fn signed_payload(timestamp: &str, body: &[u8]) -> Vec<u8> {
+        [timestamp.as_bytes(), b".", body].concat()
+    }
+
+    /// Hex hmac of `payload`
+    pub fn sign(&self, payload: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("hmac takes keys of any length");
+        mac.update(payload);
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_signatures() {
+        let source = IngestSource {
+            id: 1,
+            user: 1,
+            device: 1,
+            name: "github".to_string(),
+            // from github's docs on validating webhook deliveries
+            secret: "It's a Secret to Everybody".to_string(),
+            timestamp_pointer: None,
+            require_pointer: None,
+            created_at: Utc::now().naive_utc(),
+        };
+
+        let signature = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+        assert!(source.verify(b"Hello, World!", signature));
+        assert!(source.verify(b"Hello, World!", &signature.replace("757107ea", "757107EA")));
+        assert!(!source.verify(b"Hello, World?", signature));
+        assert!(!source.verify(b"Hello, World!", &signature[7..]));
+        assert!(!source.verify(b"Hello, World!", "sha256="));
+    }
+}
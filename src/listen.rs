@@ -0,0 +1,263 @@
+use std::{
+    fmt,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    os::unix::fs::FileTypeExt,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use axum::Router;
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use tokio::net::{TcpListener, UnixListener};
+
+/// Where the server listens, read from the environment:
+///
+/// - `UNIX_SOCKET`: path of a unix socket to listen on, instead of a port
+/// - `BIND_ADDRESS`: address to listen on, like `127.0.0.1` or `::`. defaults to `0.0.0.0`
+/// - `PORT`: defaults to 3000
+/// - `TLS_CERT` and `TLS_KEY`: paths to a pem certificate chain and private key, to serve https
+///   directly. they're reloaded when they change
+pub enum Listen {
+    Tcp { addr: SocketAddr, tls: Option<Tls> },
+    Unix(PathBuf),
+}
+
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listen::Tcp { addr, tls: None } => write!(f, "http://{addr}"),
+            Listen::Tcp { addr, tls: Some(_) } => write!(f, "https://{addr}"),
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Listen {
+    pub fn from_env() -> Result<Self> {
+        if let Ok(path) = std::env::var("UNIX_SOCKET") {
+            return Ok(Self::Unix(path.into()));
+        }
+
+        let ip: IpAddr = match std::env::var("BIND_ADDRESS") {
+            Ok(addr) if addr == "localhost" => [127, 0, 0, 1].into(),
+            Ok(addr) => addr
+                .parse()
+                .map_err(|_| anyhow!("BIND_ADDRESS {addr} isn't an ip address"))?,
+            Err(_) => [0, 0, 0, 0].into(),
+        };
+        let port: u16 = std::env::var("PORT")
+            .ok()
+            .and_then(|a| a.parse().ok())
+            .unwrap_or(3000);
+
+        let tls = match (std::env::var("TLS_CERT"), std::env::var("TLS_KEY")) {
+            (Ok(cert), Ok(key)) => Some(Tls {
+                cert: cert.into(),
+                key: key.into(),
+            }),
+            (Err(_), Err(_)) => None,
+            _ => return Err(anyhow!("TLS_CERT and TLS_KEY have to be set together")),
+        };
+
+        Ok(Self::Tcp {
+            addr: SocketAddr::new(ip, port),
+            tls,
+        })
+    }
+
+    pub fn is_https(&self) -> bool {
+        matches!(self, Listen::Tcp { tls: Some(_), .. })
+    }
+
+    /// Serves `app` until `shutdown` resolves, then waits for the requests that are running
+    pub async fn serve(
+        self,
+        app: Router,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<()> {
+        match self {
+            Listen::Tcp { addr, tls: None } => {
+                let listener = TcpListener::bind(addr).await?;
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .with_graceful_shutdown(shutdown)
+                .await?;
+            }
+            Listen::Tcp {
+                addr,
+                tls: Some(tls),
+            } => {
+                let config = RustlsConfig::from_pem_file(&tls.cert, &tls.key).await?;
+                tokio::spawn(reload_tls(tls, config.clone()));
+
+                let handle = Handle::new();
+                let shutdown_handle = handle.clone();
+                tokio::spawn(async move {
+                    shutdown.await;
+                    shutdown_handle.graceful_shutdown(None);
+                });
+
+                axum_server::bind_rustls(addr, config)
+                    .handle(handle)
+                    .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                    .await?;
+            }
+            Listen::Unix(path) => {
+                // a socket left over from the last run would make binding fail. anything else
+                // there isn't ours to remove
+                if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+                    if !metadata.file_type().is_socket() {
+                        return Err(anyhow!("{} exists and isn't a socket", path.display()));
+                    }
+                    std::fs::remove_file(&path)?;
+                }
+                let listener = UnixListener::bind(&path)?;
+                serve_unix(listener, app, shutdown).await;
+                std::fs::remove_file(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// axum can't serve unix sockets by itself, so connections are handed to hyper one by one
+async fn serve_unix(
+    listener: UnixListener,
+    app: Router,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) {
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let graceful = GracefulShutdown::new();
+    let mut shutdown = std::pin::pin!(shutdown);
+
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    tracing::warn!("couldn't accept connection: {err}");
+                    continue;
+                }
+            },
+            _ = shutdown.as_mut() => break,
+        };
+
+        let service = TowerToHyperService::new(app.clone());
+        let connection = builder
+            .serve_connection_with_upgrades(TokioIo::new(stream), service)
+            .into_owned();
+        let connection = graceful.watch(connection);
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                tracing::debug!("connection failed: {err}");
+            }
+        });
+    }
+
+    drop(listener);
+    graceful.shutdown().await;
+}
+
+/// Checks the certificate and key every minute, and loads them again when they change
+async fn reload_tls(tls: Tls, config: RustlsConfig) {
+    let modified = |tls: &Tls| -> Option<(SystemTime, SystemTime)> {
+        let cert = std::fs::metadata(&tls.cert).ok()?.modified().ok()?;
+        let key = std::fs::metadata(&tls.key).ok()?.modified().ok()?;
+        Some((cert, key))
+    };
+
+    let mut last = modified(&tls);
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+
+        let current = modified(&tls);
+        if current.is_none() || current == last {
+            continue;
+        }
+
+        match config.reload_from_pem_file(&tls.cert, &tls.key).await {
+            Ok(()) => {
+                tracing::info!("reloaded tls certificate");
+                last = current;
+            }
+            // renewals can write the files one at a time, so this is tried again next time
+            Err(err) => tracing::warn!("couldn't reload tls certificate: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{helpers::random_token, routes::health::healthz};
+
+    use axum::routing::get;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixStream,
+        sync::oneshot,
+    };
+
+    #[tokio::test]
+    async fn serves_unix_sockets() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("heartbeat-{}.sock", random_token()));
+        let app = Router::new().route("/healthz", get(healthz));
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(Listen::Unix(path.clone()).serve(app, async {
+            stopped.await.ok();
+        }));
+
+        // wait for the socket to be there
+        let mut stream = loop {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        stream
+            .write_all(b"GET /healthz HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("ok"), "{response}");
+
+        stop.send(()).unwrap();
+        server.await??;
+        assert!(!path.exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn doesnt_replace_other_files() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("heartbeat-{}.sock", random_token()));
+        std::fs::write(&path, "not a socket")?;
+
+        let app = Router::new().route("/healthz", get(healthz));
+        let served = Listen::Unix(path.clone()).serve(app, async {}).await;
+        assert!(served.is_err());
+        assert_eq!("not a socket", std::fs::read_to_string(&path)?);
+
+        std::fs::remove_file(path)?;
+
+        Ok(())
+    }
+}
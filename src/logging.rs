@@ -0,0 +1,53 @@
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{MatchedPath, Request},
+    Router,
+};
+use tower_http::trace::{DefaultOnResponse, TraceLayer};
+use tracing::{info_span, Level, Span};
+use tracing_subscriber::EnvFilter;
+
+use crate::proxy::ClientIp;
+
+/// Sets up where logs go, read from the environment:
+///
+/// - `LOG_FORMAT`: `text` (the default) for people, or `json` with one object per line
+/// - `RUST_LOG`: which logs to show, like `debug` or `heartbeat=debug,tower_http=warn`.
+///   defaults to `info`
+pub fn init() -> Result<()> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => builder.json().init(),
+        Ok("text") | Err(_) => builder.init(),
+        Ok(format) => return Err(anyhow!("LOG_FORMAT {format} isn't text or json")),
+    }
+
+    Ok(())
+}
+
+/// Wraps every route in a span with the method, route and client, and logs each response
+pub fn trace_requests(app: Router) -> Router {
+    let layer = TraceLayer::new_for_http()
+        .make_span_with(request_span)
+        .on_response(DefaultOnResponse::new().level(Level::INFO));
+    app.layer(layer)
+}
+
+fn request_span(request: &Request) -> Span {
+    // the route, like /device/:id, groups requests better than the path
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(MatchedPath::as_str);
+    let ClientIp(ip) = ClientIp::from_request(request.extensions(), request.headers());
+
+    info_span!(
+        "request",
+        method = %request.method(),
+        route,
+        path = request.uri().path(),
+        ip = ip.map(tracing::field::display),
+    )
+}
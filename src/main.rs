@@ -1,22 +1,53 @@
-use std::net::SocketAddr;
-use std::sync::{atomic::AtomicI64, Arc};
-use tokio::net::TcpListener;
+use std::str::FromStr;
+use std::sync::Arc;
 
 use axum::{
-    routing::{get, post},
+    response::Redirect,
+    routing::{delete, get, post, put},
     Router,
 };
 use chrono::{DateTime, Utc};
-use sqlx::{sqlite::SqlitePoolOptions, SqlitePool};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous},
+    SqlitePool,
+};
+
+use absence::LongestAbsences;
+use backup::Backups;
+use listen::Listen;
+use mqtt::Mqtt;
+use proxy::ProxyConfig;
+use retention::Retention;
+use session::LoginAttempts;
+use snapshot::HomeSnapshots;
 
 mod absence;
+mod api_token;
+mod audit;
+mod backup;
 mod beat;
+mod cadence;
+mod clock;
+mod commands;
 mod device;
 mod errors;
 mod helpers;
 mod html;
+mod ingest;
+mod listen;
+mod logging;
+mod mqtt;
+mod presence;
+mod proxy;
+mod retention;
 mod routes;
+mod session;
+mod settings;
+mod sleep;
+mod snapshot;
 mod testing;
+mod user;
+mod viewer;
 
 #[tokio::main]
 async fn main() {
@@ -25,10 +56,18 @@ async fn main() {
         panic!("{:?}", err);
     }
 
+    logging::init().expect("invalid logging config");
+
     let db_connection_str = std::env::var("DATABASE_URL").expect("failed to get db url");
+    // wal lets the pages read while beats are being written
+    let options = SqliteConnectOptions::from_str(&db_connection_str)
+        .expect("invalid db url")
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(std::time::Duration::from_secs(5));
     let pool = SqlitePoolOptions::new()
         .max_connections(5)
-        .connect(&db_connection_str)
+        .connect_with(options)
         .await
         .expect("failed to connect to db");
 
@@ -37,44 +76,175 @@ async fn main() {
         .await
         .expect("couldn't run migrations");
 
-    let longest_absence = sqlx::query_scalar!("select MAX(duration) from absences")
-        .fetch_optional(&pool)
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if !args.is_empty() {
+        if let Err(err) = commands::run(&args, &pool).await {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let longest_absences = LongestAbsences::load(&pool)
+        .await
+        .expect("couldn't load longest absences");
+    let home_snapshots = HomeSnapshots::load(&pool)
         .await
-        .unwrap_or_default()
-        .unwrap_or_default()
-        .unwrap_or_default();
+        .expect("couldn't load home snapshots");
+
+    if let Some(backups) = Backups::from_env() {
+        backups.spawn(pool.clone());
+    }
+
+    let state = Arc::new(AppState {
+        pool: pool.clone(),
+        longest_absences,
+        home_snapshots,
+        login_attempts: LoginAttempts::default(),
+        start_time: Utc::now(),
+    });
+    if let Some(retention) = Retention::from_env() {
+        retention.spawn(state.clone());
+    }
+    if let Some(mqtt) = Mqtt::from_env() {
+        mqtt.spawn(state.clone());
+    }
 
     let app = Router::new()
         .route("/", get(routes::home::home))
         .route("/graph", get(routes::graph::graph))
+        .route("/heatmap", get(routes::heatmap::heatmap))
+        .route("/device/:id", get(routes::device::device))
         .route("/report", get(routes::report::report))
+        .route("/sleep", get(routes::sleep::sleep))
+        .route("/u/:name", get(routes::home::home))
+        .route("/u/:name/graph", get(routes::graph::graph))
+        .route("/u/:name/heatmap", get(routes::heatmap::heatmap))
+        .route("/u/:name/report", get(routes::report::report))
+        .route("/u/:name/sleep", get(routes::sleep::sleep))
+        .route(
+            "/login",
+            get(routes::admin::login_page).post(routes::admin::login),
+        )
+        .route("/logout", post(routes::admin::logout))
+        .route("/admin", get(routes::admin::dashboard))
+        .route("/admin/devices", post(routes::admin::create_device))
+        .route("/admin/devices/:id", post(routes::admin::update_device))
+        .route(
+            "/admin/devices/:id/token",
+            post(routes::admin::regenerate_device_token),
+        )
+        .route(
+            "/admin/devices/:id/delete",
+            post(routes::admin::delete_device),
+        )
+        .route("/admin/beats/:id/delete", post(routes::admin::delete_beat))
+        .route("/admin/settings", post(routes::admin::update_settings))
+        .route("/admin/status", post(routes::admin::update_status))
+        .route(
+            "/admin/planned",
+            post(routes::admin::create_planned_absence),
+        )
+        .route(
+            "/admin/planned/:id/delete",
+            post(routes::admin::delete_planned_absence),
+        )
+        .route("/admin/tokens", post(routes::admin::create_api_token))
+        .route(
+            "/admin/tokens/:id/delete",
+            post(routes::admin::delete_api_token),
+        )
+        .route("/admin/webhooks", post(routes::admin::create_ingest_source))
+        .route(
+            "/admin/webhooks/:id/delete",
+            post(routes::admin::delete_ingest_source),
+        )
+        .route("/healthz", get(routes::health::healthz))
+        .route("/readyz", get(routes::health::readyz))
         .route("/api/beat", post(routes::beat::beat))
         .route("/api/batch", post(routes::batch::batch))
-        .with_state(Arc::new(AppState {
-            pool,
-            longest_absence: AtomicI64::new(longest_absence as i64),
-            start_time: Utc::now(),
-        }));
-
-    let port: u16 = std::env::var("PORT")
-        .ok()
-        .and_then(|a| a.parse().ok())
-        .unwrap_or(3000);
-
-    #[cfg(debug_assertions)]
-    println!("listening on http://localhost:{port}");
-
-    // run it with hyper on localhost:3000
-    let listener = TcpListener::bind(SocketAddr::new([0, 0, 0, 0].into(), port))
+        .route("/api/devices/me", get(routes::batch::current_device))
+        .route("/api/ingest/:name", post(routes::ingest::ingest))
+        .route("/api/stats", get(routes::api::stats))
+        .route("/api/export", get(routes::api::export))
+        .route(
+            "/api/tokens",
+            get(routes::api::list_tokens).post(routes::api::create_token),
+        )
+        .route("/api/tokens/:id", delete(routes::api::delete_token))
+        .route("/api/status", put(routes::api::set_status))
+        .route(
+            "/api/planned",
+            get(routes::api::list_planned_absences).post(routes::api::create_planned_absence),
+        )
+        .route(
+            "/api/planned/:id",
+            delete(routes::api::delete_planned_absence),
+        )
+        .with_state(state);
+    let app = logging::trace_requests(app);
+
+    let listen = Listen::from_env().expect("invalid listen config");
+
+    let mut proxy_config = ProxyConfig::from_env().expect("invalid proxy config");
+    proxy_config.https = listen.is_https();
+    let base_path = proxy_config.base_path.clone();
+    proxy_config.install();
+    let app = if base_path.is_empty() {
+        app
+    } else {
+        let home = Redirect::permanent(&base_path);
+        Router::new()
+            .nest(&base_path, app)
+            // nesting only matches the base path without the slash
+            .route(&format!("{base_path}/"), get(|| async { home }))
+    };
+
+    tracing::info!("listening on {listen}");
+
+    listen
+        .serve(app, shutdown_signal())
         .await
-        .unwrap();
-    axum::serve(listener, app).await.unwrap();
+        .expect("couldn't serve");
+
+    // requests that were running have finished by now, so every connection is back in the pool
+    pool.close().await;
+}
+
+/// Resolves on ctrl-c or SIGTERM, so requests that are running can finish before stopping
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("couldn't listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("couldn't listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    tracing::info!("shutting down");
 }
 
 pub struct AppState {
     pool: SqlitePool,
     /// server start time, to keep track of uptime
     start_time: DateTime<Utc>,
-    /// Longest absence in seconds
-    longest_absence: AtomicI64,
+    /// Longest absence of each user
+    longest_absences: LongestAbsences,
+    /// What the home page shows of each user
+    home_snapshots: HomeSnapshots,
+    /// Failed logins of each address
+    login_attempts: LoginAttempts,
 }
@@ -0,0 +1,344 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use chrono::{NaiveDateTime, Utc};
+use heartbeat_client::api::BeatMeta;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde::Deserialize;
+
+use crate::{
+    audit::AuditEvent,
+    beat::Beat,
+    clock::{self, DeviceClock},
+    device::{authenticate_token, Device},
+    errors::AppError,
+    ingest,
+    routes::beat::{check_meta, check_timestamp},
+    AppState,
+};
+
+/// how long to wait before connecting again after losing the broker
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Beats sent over mqtt, read from the environment:
+///
+/// - `MQTT_HOST`: broker to subscribe at. the subscriber doesn't run if this isn't set
+/// - `MQTT_PORT`: defaults to 1883
+/// - `MQTT_CLIENT_ID`: defaults to `heartbeat`
+/// - `MQTT_USERNAME` and `MQTT_PASSWORD`: only sent when the username is set
+/// - `MQTT_TOPICS`: topic filters to subscribe to, separated by spaces. defaults to `heartbeat/#`
+/// - `MQTT_TOKEN_IN_TOPIC`: also take the token from the last level of the topic, for messages
+///   without one
+#[derive(Clone, Debug)]
+pub struct Mqtt {
+    pub options: MqttOptions,
+    pub topics: Vec<String>,
+    pub token_in_topic: bool,
+}
+
+/// A message with a beat. the payload is empty or json, and the token comes from it or, when
+/// enabled, from the last level of the topic
+#[derive(Debug, Default, Deserialize)]
+struct Message {
+    token: Option<String>,
+    timestamp: Option<NaiveDateTime>,
+    #[serde(flatten)]
+    meta: BeatMeta,
+}
+
+impl Mqtt {
+    pub fn from_env() -> Option<Self> {
+        let host = std::env::var("MQTT_HOST").ok()?;
+        let port = std::env::var("MQTT_PORT")
+            .ok()
+            .and_then(|port| port.parse().ok())
+            .unwrap_or(1883);
+        let client_id = std::env::var("MQTT_CLIENT_ID").unwrap_or_else(|_| "heartbeat".into());
+
+        let mut options = MqttOptions::new(client_id, host, port);
+        if let Ok(username) = std::env::var("MQTT_USERNAME") {
+            options.set_credentials(username, std::env::var("MQTT_PASSWORD").unwrap_or_default());
+        }
+
+        let topics = std::env::var("MQTT_TOPICS")
+            .map(|topics| topics.split_whitespace().map(String::from).collect())
+            .unwrap_or_else(|_| vec!["heartbeat/#".to_string()]);
+        // topics tend to end up in broker logs and acls, so tokens in them are opt-in
+        let token_in_topic = std::env::var("MQTT_TOKEN_IN_TOPIC")
+            .map(|a| a == "true" || a == "1")
+            .unwrap_or_default();
+
+        Some(Self {
+            options,
+            topics,
+            token_in_topic,
+        })
+    }
+
+    /// Subscribes to the topics in the background, and stores a beat for every message. the
+    /// connection is made again when it drops
+    pub fn spawn(self, state: Arc<AppState>) {
+        tokio::spawn(async move {
+            let (client, mut eventloop) = AsyncClient::new(self.options, 16);
+            loop {
+                match eventloop.poll().await {
+                    // subscriptions don't outlive the session, so they're made on every connect
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        tracing::info!("connected to the mqtt broker");
+                        // requests only go out while the loop polls, so waiting for them here
+                        // would get stuck once there are more than fit in the queue
+                        let client = client.clone();
+                        let topics = self.topics.clone();
+                        tokio::spawn(async move {
+                            for topic in topics {
+                                if let Err(err) = client.subscribe(&topic, QoS::AtLeastOnce).await {
+                                    tracing::error!("couldn't subscribe to {topic}: {err}");
+                                }
+                            }
+                        });
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        let beat = handle(
+                            &state,
+                            &publish.topic,
+                            &publish.payload,
+                            self.token_in_topic,
+                        );
+                        match beat.await {
+                            Ok(device) => tracing::debug!(device = device.id, "beat over mqtt"),
+                            Err(err) => {
+                                tracing::warn!(topic = publish.topic, "dropped mqtt message: {err}")
+                            }
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        tracing::warn!("lost the mqtt broker: {err}");
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Stores the beat in a message, through the same path as single beats over http
+pub async fn handle(
+    state: &AppState,
+    topic: &str,
+    payload: &[u8],
+    token_in_topic: bool,
+) -> Result<Device> {
+    let message = if payload.iter().all(u8::is_ascii_whitespace) {
+        Message::default()
+    } else {
+        serde_json::from_slice::<Message>(payload)
+            .map_err(|err| anyhow!("the payload isn't a valid beat: {err}"))?
+    };
+    let token = match &message.token {
+        Some(token) => token.as_str(),
+        None if token_in_topic => topic.rsplit('/').next().unwrap_or_default(),
+        None => return Err(anyhow!("the payload doesn't have a token")),
+    };
+
+    let device = match authenticate_token(token, &state.pool).await {
+        Ok(device) => device,
+        Err((_, reason, user)) => {
+            AuditEvent::DeviceAuthFailed {
+                reason: format!("{reason}, over mqtt"),
+            }
+            .record(user, None, &state.pool)
+            .await?;
+            return Err(anyhow!(reason));
+        }
+    };
+
+    check_meta(&message.meta).map_err(rejection)?;
+
+    let mut tx = state.pool.begin().await?;
+    // messages don't say when they were sent, so the skew measured over http is used
+    let timestamp = match message.timestamp {
+        Some(timestamp) => {
+            let skew = DeviceClock::skew_of(device.id, &mut tx).await?;
+            clock::correct(timestamp, skew).and_utc()
+        }
+        None => Utc::now(),
+    };
+    check_timestamp(timestamp).map_err(rejection)?;
+
+    // the broker sends messages again until it knows they arrived, so a beat at the same time is
+    // the same message
+    if message.timestamp.is_some() {
+        let timestamp = timestamp.naive_utc();
+        let existing =
+            Beat::timestamps_between(device.id, &timestamp, &timestamp, &mut *tx).await?;
+        if !existing.is_empty() {
+            tracing::debug!(device = device.id, "mqtt message was sent again");
+            return Ok(device);
+        }
+    }
+
+    ingest::record_beat(state, &device, timestamp, &message.meta, None, tx).await?;
+
+    Ok(device)
+}
+
+/// Turns the answer a route would give into an error to log
+fn rejection(err: AppError) -> anyhow::Error {
+    match err {
+        AppError::Anyhow(err) => err,
+        AppError::Rejection(_, reason) => anyhow!(reason),
+        AppError::Html(_) => anyhow!("rejected"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::init_state;
+    use chrono::TimeDelta;
+
+    async fn base() -> Arc<AppState> {
+        let state = init_state().await;
+
+        Device {
+            id: 1,
+            user: 1,
+            name: "desk sensor".to_string(),
+            token: "my_token".to_string(),
+            beat_count: 0,
+            visible: true,
+        }
+        .create(&state.pool)
+        .await
+        .unwrap();
+
+        state
+    }
+
+    #[tokio::test]
+    async fn stores_beats_from_messages() -> Result<()> {
+        let state = base().await;
+
+        let timestamp = (Utc::now() - TimeDelta::minutes(1)).naive_utc();
+        let payload = serde_json::json!({
+            "token": "my_token",
+            "timestamp": timestamp,
+            "source": "manual",
+            "client_version": "esp32 desk sensor",
+        });
+        let device = handle(
+            &state,
+            "heartbeat/desk",
+            payload.to_string().as_bytes(),
+            false,
+        )
+        .await?;
+        assert_eq!(1, device.id);
+
+        // the token can be the last level of the topic, when that's enabled
+        assert!(handle(&state, "heartbeat/my_token", b"", false)
+            .await
+            .is_err());
+        handle(&state, "heartbeat/my_token", b"", true).await?;
+
+        assert_eq!(2, Beat::count(1, &state.pool).await?);
+        let first = Beat::first_beat_of(1, &state.pool).await?.unwrap();
+        assert_eq!(timestamp, first.timestamp);
+
+        // messages the broker sends again aren't stored twice
+        handle(
+            &state,
+            "heartbeat/desk",
+            payload.to_string().as_bytes(),
+            false,
+        )
+        .await?;
+        assert_eq!(2, Beat::count(1, &state.pool).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrects_clock_skew() -> Result<()> {
+        let state = base().await;
+
+        // the device's clock is two minutes ahead
+        let mut conn = state.pool.acquire().await?;
+        let skew = DeviceClock::measure(1, Utc::now() + TimeDelta::minutes(2), &mut conn).await?;
+        DeviceClock::store(1, skew, &mut conn).await?;
+        drop(conn);
+
+        let timestamp = (Utc::now() + TimeDelta::minutes(1)).naive_utc();
+        let payload = serde_json::json!({ "token": "my_token", "timestamp": timestamp });
+        handle(
+            &state,
+            "heartbeat/desk",
+            payload.to_string().as_bytes(),
+            false,
+        )
+        .await?;
+
+        let beat = Beat::last_beat(1, &state.pool).await?.unwrap();
+        assert_eq!(timestamp - TimeDelta::seconds(skew), beat.timestamp);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_messages() -> Result<()> {
+        let state = base().await;
+
+        for payload in [
+            serde_json::json!({ "token": "wrong_token" }),
+            serde_json::json!({ "token": "my_token", "idle_seconds": -1 }),
+            serde_json::json!({
+                "token": "my_token",
+                "timestamp": (Utc::now() + TimeDelta::hours(1)).naive_utc(),
+            }),
+        ] {
+            let payload = payload.to_string();
+            assert!(handle(&state, "heartbeat/desk", payload.as_bytes(), false)
+                .await
+                .is_err());
+        }
+        assert!(handle(&state, "heartbeat/my_token", b"beat!", true)
+            .await
+            .is_err());
+        assert_eq!(0, Beat::count(1, &state.pool).await?);
+
+        Ok(())
+    }
+
+    /// needs a broker like mosquitto on localhost:1883
+    #[tokio::test]
+    #[ignore]
+    async fn subscribes_to_a_broker() -> Result<()> {
+        let state = base().await;
+        Mqtt {
+            options: MqttOptions::new("heartbeat-test", "localhost", 1883),
+            topics: vec!["heartbeat-test/#".to_string()],
+            token_in_topic: true,
+        }
+        .spawn(state.clone());
+
+        let (client, mut eventloop) = AsyncClient::new(
+            MqttOptions::new("heartbeat-test-sender", "localhost", 1883),
+            16,
+        );
+        tokio::spawn(async move { while eventloop.poll().await.is_ok() {} });
+
+        // the subscription might not be there yet, so keep sending until a beat arrives
+        for _ in 0..50 {
+            client
+                .publish("heartbeat-test/my_token", QoS::AtLeastOnce, false, "")
+                .await?;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            if Beat::count(1, &state.pool).await? > 0 {
+                return Ok(());
+            }
+        }
+
+        Err(anyhow!("no beat arrived"))
+    }
+}
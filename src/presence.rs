@@ -0,0 +1,265 @@
+use anyhow::Result;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use sqlx::{Executor, Sqlite};
+
+/// A message set by a user, shown on their home page
+#[derive(Debug, serde::Serialize)]
+pub struct Status {
+    pub message: String,
+    pub updated_at: NaiveDateTime,
+}
+
+impl Status {
+    pub async fn get<'c, E>(user: i64, executor: E) -> Result<Option<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let status = sqlx::query_as!(
+            Status,
+            "select message, updated_at from statuses where user = ?",
+            user
+        )
+        .fetch_optional(executor)
+        .await?;
+
+        Ok(status)
+    }
+
+    /// Sets the status of `user`. an empty message clears it
+    pub async fn set<'c, E>(user: i64, message: &str, executor: E) -> Result<()>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let message = message.trim();
+        if message.is_empty() {
+            sqlx::query!("delete from statuses where user = ?", user)
+                .execute(executor)
+                .await?;
+            return Ok(());
+        }
+
+        let now = Utc::now().naive_utc();
+        sqlx::query!(
+            "insert into statuses (user, message, updated_at) values (?, ?, ?)
+            on conflict (user) do update set message = excluded.message, updated_at = excluded.updated_at",
+            user,
+            message,
+            now
+        )
+        .execute(executor)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// A time a user knows they'll be away, like a vacation
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct PlannedAbsence {
+    #[serde(default)]
+    pub id: i64,
+    #[serde(skip)]
+    pub user: i64,
+    pub starts_at: NaiveDateTime,
+    pub ends_at: NaiveDateTime,
+    #[serde(default)]
+    pub note: String,
+    /// whether absences during this are left out of the longest absence
+    #[serde(default)]
+    pub exclude_from_record: bool,
+}
+
+impl From<PlannedAbsence> for heartbeat_client::api::PlannedAbsence {
+    fn from(planned: PlannedAbsence) -> Self {
+        Self {
+            id: planned.id,
+            starts_at: planned.starts_at,
+            ends_at: planned.ends_at,
+            note: planned.note,
+            exclude_from_record: planned.exclude_from_record,
+        }
+    }
+}
+
+impl PlannedAbsence {
+    /// whether this overlaps the time between `start` and `end`
+    pub fn overlaps(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> bool {
+        self.starts_at.and_utc() < end && start < self.ends_at.and_utc()
+    }
+
+    /// whether this is happening at `date`
+    pub fn contains(&self, date: DateTime<Utc>) -> bool {
+        self.starts_at.and_utc() <= date && date < self.ends_at.and_utc()
+    }
+
+    /// description shown next to absences that overlap this
+    pub fn desc(&self) -> String {
+        if self.note.is_empty() {
+            "planned".to_string()
+        } else {
+            format!("planned: {}", self.note)
+        }
+    }
+
+    pub async fn create<'c, E>(mut self, executor: E) -> Result<Self>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let id = sqlx::query!(
+            "insert into planned_absences (user, starts_at, ends_at, note, exclude_from_record)
+            values (?, ?, ?, ?, ?)",
+            self.user,
+            self.starts_at,
+            self.ends_at,
+            self.note,
+            self.exclude_from_record,
+        )
+        .execute(executor)
+        .await?
+        .last_insert_rowid();
+
+        self.id = id;
+
+        Ok(self)
+    }
+
+    /// Gets all planned absences of `user`, newest first
+    pub async fn get_all<'c, E>(user: i64, executor: E) -> Result<Vec<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let planned = sqlx::query_as!(
+            Self,
+            "select id as \"id!\", user, starts_at, ends_at, note, exclude_from_record
+            from planned_absences where user = ? order by starts_at desc",
+            user
+        )
+        .fetch_all(executor)
+        .await?;
+
+        Ok(planned)
+    }
+
+    /// Gets planned absences of `user` that haven't ended yet, soonest first
+    pub async fn get_upcoming<'c, E>(user: i64, executor: E) -> Result<Vec<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let now = Utc::now().naive_utc();
+        let planned = sqlx::query_as!(
+            Self,
+            "select id as \"id!\", user, starts_at, ends_at, note, exclude_from_record
+            from planned_absences where user = ? and ends_at > ? order by starts_at asc",
+            user,
+            now
+        )
+        .fetch_all(executor)
+        .await?;
+
+        Ok(planned)
+    }
+
+    /// Whether an absence of `user` between `start` and `end` should be left out of the longest absence
+    pub async fn excludes<'c, E>(
+        user: i64,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        executor: E,
+    ) -> Result<bool>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let start = start.naive_utc();
+        let end = end.naive_utc();
+        let excluded = sqlx::query_scalar!(
+            "select exists (
+                select 1 from planned_absences
+                where user = ? and exclude_from_record and starts_at < ? and ends_at > ?
+            ) as \"excluded!: bool\"",
+            user,
+            end,
+            start
+        )
+        .fetch_one(executor)
+        .await?;
+
+        Ok(excluded)
+    }
+
+    /// Deletes a planned absence of `user`
+    pub async fn delete<'c, E>(user: i64, id: i64, executor: E) -> Result<()>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        sqlx::query!(
+            "delete from planned_absences where id = ? and user = ?",
+            id,
+            user
+        )
+        .execute(executor)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::init_state;
+
+    use chrono::TimeDelta;
+
+    #[tokio::test]
+    async fn can_set_and_clear_status() -> Result<()> {
+        let state = init_state().await;
+
+        assert!(Status::get(1, &state.pool).await?.is_none());
+
+        Status::set(1, "on vacation", &state.pool).await?;
+        Status::set(1, "on vacation!", &state.pool).await?;
+        let status = Status::get(1, &state.pool).await?.unwrap();
+        assert_eq!("on vacation!", status.message);
+
+        Status::set(1, " ", &state.pool).await?;
+        assert!(Status::get(1, &state.pool).await?.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn excludes_overlapping_absences() -> Result<()> {
+        let state = init_state().await;
+
+        let now = Utc::now();
+        for exclude_from_record in [true, false] {
+            PlannedAbsence {
+                id: 0,
+                user: 1,
+                starts_at: (now - TimeDelta::days(10)).naive_utc(),
+                ends_at: (now - TimeDelta::days(5)).naive_utc(),
+                note: String::new(),
+                exclude_from_record,
+            }
+            .create(&state.pool)
+            .await?;
+            // not excluded, and long over
+            assert_eq!(0, PlannedAbsence::get_upcoming(1, &state.pool).await?.len());
+        }
+
+        let excludes = |start: i64, end: i64| {
+            PlannedAbsence::excludes(
+                1,
+                now - TimeDelta::days(start),
+                now - TimeDelta::days(end),
+                &state.pool,
+            )
+        };
+        assert!(excludes(12, 9).await?);
+        assert!(excludes(6, 1).await?);
+        assert!(!excludes(4, 1).await?);
+        assert!(!excludes(20, 11).await?);
+
+        Ok(())
+    }
+}
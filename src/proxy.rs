@@ -0,0 +1,374 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::OnceLock,
+};
+
+use anyhow::{anyhow, Result};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, Extensions, HeaderMap},
+};
+
+/// Settings for running behind a reverse proxy, read from the environment:
+///
+/// - `BASE_PATH`: path the service is mounted at, like `/heartbeat`. defaults to the root
+/// - `TRUSTED_PROXIES`: comma separated addresses or ranges, like `127.0.0.1,10.0.0.0/8`, whose
+///   `Forwarded`, `X-Forwarded-For` and `X-Forwarded-Proto` headers are believed. unix socket
+///   connections are always trusted
+pub struct ProxyConfig {
+    pub base_path: String,
+    pub trusted_proxies: Vec<IpRange>,
+    /// whether the server serves https itself. set from the listen config
+    pub https: bool,
+}
+
+static CONFIG: OnceLock<ProxyConfig> = OnceLock::new();
+
+impl ProxyConfig {
+    pub fn from_env() -> Result<Self> {
+        let base_path = normalize_base_path(&std::env::var("BASE_PATH").unwrap_or_default());
+        let trusted_proxies = std::env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|range| !range.is_empty())
+            .map(IpRange::parse)
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            base_path,
+            trusted_proxies,
+            https: false,
+        })
+    }
+
+    /// Makes this the config used by [`url`] and [`ClientIp`]. can only be done once
+    pub fn install(self) {
+        if CONFIG.set(self).is_err() {
+            panic!("the proxy config was already installed");
+        }
+    }
+
+    fn get() -> &'static ProxyConfig {
+        CONFIG.get_or_init(|| ProxyConfig {
+            base_path: String::new(),
+            trusted_proxies: vec![],
+            https: false,
+        })
+    }
+}
+
+/// Path the service is mounted at, without a trailing slash. empty at the root
+pub fn base_path() -> &'static str {
+    &ProxyConfig::get().base_path
+}
+
+/// Absolute link to `path`, under the base path
+pub fn url(path: &str) -> String {
+    format!("{}{path}", base_path())
+}
+
+fn normalize_base_path(path: &str) -> String {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        String::new()
+    } else {
+        format!("/{path}")
+    }
+}
+
+/// An address, or a range of them in cidr notation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl IpRange {
+    pub fn parse(range: &str) -> Result<Self> {
+        let invalid = || anyhow!("{range} isn't an ip address or range");
+
+        let (addr, prefix) = match range.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (range, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => bits,
+        };
+        if prefix > bits {
+            return Err(invalid());
+        }
+
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // ipv4 clients of ipv6 sockets show up as mapped addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+
+        match (self.addr, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(range) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(range) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Address of the client that sent the request, looking past trusted proxies
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl ClientIp {
+    /// For places that see the request before extractors run, like middleware
+    pub fn from_request(extensions: &Extensions, headers: &HeaderMap) -> Self {
+        // connections over unix sockets don't have an address
+        let peer = extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+
+        ClientIp(client_ip(
+            peer,
+            headers,
+            &ProxyConfig::get().trusted_proxies,
+        ))
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(ClientIp::from_request(&parts.extensions, &parts.headers))
+    }
+}
+
+/// Whether the client reached the server over https, directly or through a trusted proxy
+pub struct Https(pub bool);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Https {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let config = ProxyConfig::get();
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+
+        Ok(Https(
+            config.https || forwarded_https(peer, &parts.headers, &config.trusted_proxies),
+        ))
+    }
+}
+
+/// Whether the proxy in front says the request reached it over https, from the `Forwarded`
+/// header or `X-Forwarded-Proto` if there isn't one. only proxies are believed
+fn forwarded_https(peer: Option<IpAddr>, headers: &HeaderMap, trusted: &[IpRange]) -> bool {
+    if peer.is_some_and(|peer| !trusted.iter().any(|range| range.contains(peer))) {
+        return false;
+    }
+
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|value| value.trim().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    // the proxy closest to the server adds the last one
+    let proto = values("forwarded")
+        .iter()
+        .rev()
+        .find_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("proto")
+                    .then(|| value.trim_matches('"').to_string())
+            })
+        })
+        .or_else(|| values("x-forwarded-proto").pop());
+
+    proto.is_some_and(|proto| proto.eq_ignore_ascii_case("https"))
+}
+
+/// Walks the addresses the request was forwarded through from the closest one, and returns the
+/// first that isn't a trusted proxy
+fn client_ip(peer: Option<IpAddr>, headers: &HeaderMap, trusted: &[IpRange]) -> Option<IpAddr> {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|range| range.contains(*ip));
+    if peer.is_some_and(|peer| !is_trusted(&peer)) {
+        return peer;
+    }
+
+    let forwarded = forwarded_for(headers);
+    forwarded
+        .iter()
+        .rev()
+        .find(|ip| !is_trusted(ip))
+        // everything was a trusted proxy, so the first one is the client
+        .or(forwarded.first())
+        .copied()
+        .or(peer)
+}
+
+/// Addresses in the `Forwarded` header, or `X-Forwarded-For` if there isn't one, client first
+fn forwarded_for(headers: &HeaderMap) -> Vec<IpAddr> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>()
+    };
+
+    let forwarded = values("forwarded");
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    key.eq_ignore_ascii_case("for").then(|| parse_node(value))?
+                })
+            })
+            .collect();
+    }
+
+    values("x-forwarded-for")
+        .into_iter()
+        .filter_map(parse_node)
+        .collect()
+}
+
+/// Parses an address like `192.0.2.60`, `"192.0.2.60:4711"` or `"[2001:db8::17]:4711"`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim_matches('"');
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Some(v6) = node.strip_prefix('[') {
+        return v6.split_once(']')?.0.parse().ok();
+    }
+    node.split_once(':')?.0.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::http::HeaderValue;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn normalizes_base_paths() {
+        assert_eq!("", normalize_base_path(""));
+        assert_eq!("", normalize_base_path("/"));
+        assert_eq!("/heartbeat", normalize_base_path("heartbeat/"));
+        assert_eq!("/heart/beat", normalize_base_path("/heart/beat"));
+    }
+
+    #[test]
+    fn matches_ranges() -> Result<()> {
+        let range = IpRange::parse("10.0.0.0/8")?;
+        assert!(range.contains(ip("10.1.2.3")));
+        assert!(range.contains(ip("::ffff:10.1.2.3")));
+        assert!(!range.contains(ip("11.1.2.3")));
+
+        let range = IpRange::parse("fd00::/8")?;
+        assert!(range.contains(ip("fd12::1")));
+        assert!(!range.contains(ip("fe80::1")));
+
+        assert!(IpRange::parse("0.0.0.0/0")?.contains(ip("1.2.3.4")));
+        assert!(IpRange::parse("::1")?.contains(ip("::1")));
+        assert!(IpRange::parse("10.0.0.0/33").is_err());
+        assert!(IpRange::parse("localhost").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_believes_trusted_proxies() -> Result<()> {
+        let trusted = [IpRange::parse("127.0.0.1")?, IpRange::parse("10.0.0.0/8")?];
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("6.6.6.6, 1.2.3.4, 10.0.0.2"),
+        );
+
+        // the client can put anything in the header, so only the part added by proxies counts
+        assert_eq!(
+            Some(ip("1.2.3.4")),
+            client_ip(Some(ip("127.0.0.1")), &headers, &trusted)
+        );
+        // unix sockets
+        assert_eq!(Some(ip("1.2.3.4")), client_ip(None, &headers, &trusted));
+        // not from a proxy
+        assert_eq!(
+            Some(ip("5.5.5.5")),
+            client_ip(Some(ip("5.5.5.5")), &headers, &trusted)
+        );
+        assert_eq!(
+            Some(ip("127.0.0.1")),
+            client_ip(Some(ip("127.0.0.1")), &HeaderMap::new(), &trusted)
+        );
+
+        headers.insert(
+            "forwarded",
+            HeaderValue::from_static(
+                "for=6.6.6.6, for=\"[2001:db8:cafe::17]:4711\";proto=https, for=10.0.0.2",
+            ),
+        );
+        assert_eq!(
+            Some(ip("2001:db8:cafe::17")),
+            client_ip(Some(ip("127.0.0.1")), &headers, &trusted)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_believes_trusted_proxies_about_https() -> Result<()> {
+        let trusted = [IpRange::parse("127.0.0.1")?];
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("https"));
+        assert!(forwarded_https(Some(ip("127.0.0.1")), &headers, &trusted));
+        assert!(forwarded_https(None, &headers, &trusted));
+        assert!(!forwarded_https(Some(ip("5.5.5.5")), &headers, &trusted));
+        assert!(!forwarded_https(
+            Some(ip("127.0.0.1")),
+            &HeaderMap::new(),
+            &trusted
+        ));
+
+        headers.insert(
+            "forwarded",
+            HeaderValue::from_static("for=1.2.3.4;proto=https, for=10.0.0.2;proto=http"),
+        );
+        assert!(!forwarded_https(Some(ip("127.0.0.1")), &headers, &trusted));
+
+        Ok(())
+    }
+}
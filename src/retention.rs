@@ -0,0 +1,285 @@
+use std::{fmt, sync::Arc};
+
+use anyhow::{bail, Result};
+use chrono::{Duration, NaiveDateTime, Utc};
+use sqlx::SqlitePool;
+
+use crate::AppState;
+
+/// How long beats are kept at full resolution, read from the environment:
+///
+/// - `RETENTION_DAYS`: days to keep every beat for. the job doesn't run if this isn't set
+/// - `RETENTION_INTERVAL_MINUTES`: older beats get merged into one per interval, defaults to 60
+/// - `RETENTION_DRY_RUN`: only report what would be compacted
+#[derive(Clone, Debug)]
+pub struct Retention {
+    pub keep_days: i64,
+    /// length of the intervals old beats are merged into, in seconds
+    pub interval: i64,
+    pub dry_run: bool,
+}
+
+/// What a retention run compacted, or would compact in a dry run
+#[derive(Debug)]
+pub struct RetentionReport {
+    /// beats older than this were compacted
+    pub cutoff: NaiveDateTime,
+    /// beats that were merged into others and removed
+    pub removed: i64,
+    /// beats that stand for an interval of a device after compacting
+    pub intervals: i64,
+    /// old beats that were left alone, because absences start or end on them
+    pub preserved: i64,
+    /// users that have beats older than the cutoff
+    pub users: Vec<i64>,
+}
+
+impl fmt::Display for RetentionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "beats before {}: {} merged into {} intervals, {} kept for absences",
+            self.cutoff.format("%Y/%m/%d %H:%M UTC"),
+            self.removed,
+            self.intervals,
+            self.preserved
+        )
+    }
+}
+
+impl Retention {
+    pub fn from_env() -> Option<Self> {
+        let keep_days = std::env::var("RETENTION_DAYS").ok()?.parse().ok()?;
+        let interval_minutes: i64 = std::env::var("RETENTION_INTERVAL_MINUTES")
+            .ok()
+            .and_then(|a| a.parse().ok())
+            .unwrap_or(60);
+        let dry_run = std::env::var("RETENTION_DRY_RUN")
+            .map(|a| a == "true" || a == "1")
+            .unwrap_or_default();
+
+        Some(Self {
+            keep_days,
+            interval: interval_minutes * 60,
+            dry_run,
+        })
+    }
+
+    /// Compacts beats older than `keep_days` into one beat per device and interval, keeping the
+    /// last beat of each interval so new absences are still measured from it
+    pub async fn run(&self, pool: &SqlitePool) -> Result<RetentionReport> {
+        // the last beat of every device has to stay, or the next absence would start too early
+        if self.keep_days < 1 {
+            bail!("beats have to be kept for at least a day");
+        }
+        if self.interval < 1 {
+            bail!("the retention interval has to be at least a minute");
+        }
+
+        let cutoff = (Utc::now() - Duration::days(self.keep_days)).naive_utc();
+
+        let mut tx = pool.begin().await?;
+
+        let report = sqlx::query!(
+            "with old as (
+                select device, cast(strftime('%s', timestamp) as integer) / ? as interval
+                from beats where timestamp < ?
+                and id not in (select begin_beat from absences union select end_beat from absences)
+            )
+            select
+            (select count(*) from old) as \"old!: i64\",
+            (select count(*) from (select 1 from old group by device, interval)) as \"intervals!: i64\",
+            (select count(*) from beats where timestamp < ?
+                and id in (select begin_beat from absences union select end_beat from absences)
+            ) as \"preserved!: i64\"",
+            self.interval,
+            cutoff,
+            cutoff,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        let users = sqlx::query_scalar!(
+            "select distinct user as \"user!\" from beats where timestamp < ? order by user",
+            cutoff
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let report = RetentionReport {
+            cutoff,
+            removed: report.old - report.intervals,
+            intervals: report.intervals,
+            preserved: report.preserved,
+            users,
+        };
+
+        if self.dry_run || report.removed == 0 {
+            return Ok(report);
+        }
+
+        // sqlite picks the id from the row with the latest timestamp
+        sqlx::query!(
+            "with old as (
+                select id, device, timestamp, weight,
+                cast(strftime('%s', timestamp) as integer) / ? as interval
+                from beats where timestamp < ?
+                and id not in (select begin_beat from absences union select end_beat from absences)
+            ), kept as (
+                select id, max(timestamp), sum(weight) as weight from old
+                group by device, interval having count(*) > 1
+            )
+            update beats set weight = (select weight from kept where kept.id = beats.id)
+            where id in (select id from kept)",
+            self.interval,
+            cutoff,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "with old as (
+                select id, device, timestamp,
+                cast(strftime('%s', timestamp) as integer) / ? as interval
+                from beats where timestamp < ?
+                and id not in (select begin_beat from absences union select end_beat from absences)
+            ), kept as (
+                select id, max(timestamp) from old group by device, interval
+            )
+            delete from beats where id in (select id from old) and id not in (select id from kept)",
+            self.interval,
+            cutoff,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(report)
+    }
+
+    /// Runs the retention job every hour in the background. home pages are loaded again after
+    /// beats were compacted
+    pub fn spawn(self, state: Arc<AppState>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+            loop {
+                interval.tick().await;
+
+                match self.run(&state.pool).await {
+                    Ok(report) if self.dry_run => tracing::info!("retention dry run: {report}"),
+                    Ok(report) if report.removed > 0 => {
+                        tracing::info!("retention: {report}");
+                        for user in &report.users {
+                            state.home_snapshots.invalidate(*user);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(err) => tracing::error!("retention failed: {err}"),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{absence::Absence, beat::Beat, device::Device, testing::init_state};
+
+    use chrono::{DurationRound, TimeDelta};
+
+    #[tokio::test]
+    async fn compacts_old_beats() -> Result<()> {
+        let state = init_state().await;
+
+        Device {
+            id: 1,
+            user: 1,
+            name: "test device".to_string(),
+            token: "my_token".to_string(),
+            beat_count: 0,
+            visible: true,
+        }
+        .create(&state.pool)
+        .await?;
+
+        // a beat every minute for two hours, ten days ago
+        let start = (Utc::now() - TimeDelta::days(10)).duration_trunc(TimeDelta::hours(1))?;
+        let mut beats = vec![];
+        for minute in 0..120 {
+            let beat = Beat {
+                id: 0,
+                device: 1,
+                timestamp: (start + TimeDelta::minutes(minute)).naive_utc(),
+            }
+            .create(&state.pool)
+            .await?;
+            beats.push(beat);
+        }
+        // and some recent ones
+        for minute in 0..5 {
+            Beat {
+                id: 0,
+                device: 1,
+                timestamp: (Utc::now() - TimeDelta::minutes(minute)).naive_utc(),
+            }
+            .create(&state.pool)
+            .await?;
+        }
+        Absence {
+            id: 0,
+            timestamp: beats[11].timestamp,
+            duration: 60,
+            begin_beat: beats[10].id,
+            end_beat: beats[11].id,
+        }
+        .create(&state.pool)
+        .await?;
+
+        let mut retention = Retention {
+            keep_days: 7,
+            interval: 60 * 60,
+            dry_run: true,
+        };
+
+        let report = retention.run(&state.pool).await?;
+        assert_eq!(116, report.removed);
+        assert_eq!(2, report.intervals);
+        assert_eq!(2, report.preserved);
+        assert_eq!(vec![1], report.users);
+        // nothing changes in a dry run
+        assert_eq!(
+            125,
+            sqlx::query_scalar!("select count(*) from beats")
+                .fetch_one(&state.pool)
+                .await?
+        );
+
+        retention.dry_run = false;
+        let compacted = retention.run(&state.pool).await?;
+        assert_eq!(report.removed, compacted.removed);
+
+        assert_eq!(
+            9,
+            sqlx::query_scalar!("select count(*) from beats")
+                .fetch_one(&state.pool)
+                .await?
+        );
+        assert_eq!(125, Beat::count(1, &state.pool).await?);
+        assert_eq!(1, Absence::count(1, &state.pool).await?);
+        let device = Device::get_by_id(1, &state.pool).await?.unwrap();
+        assert_eq!(125, device.beat_count);
+        // the last beat of each interval is the one that's kept
+        let last = Beat::last_before(1, &(start + TimeDelta::hours(3)).naive_utc(), &state.pool)
+            .await?
+            .unwrap();
+        assert_eq!(beats[119].id, last.id);
+
+        // running it again doesn't change anything
+        let report = retention.run(&state.pool).await?;
+        assert_eq!(0, report.removed);
+        assert_eq!(125, Beat::count(1, &state.pool).await?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1175 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Result;
+use axum::{
+    extract::{Path, Query, State},
+    http::{header::SET_COOKIE, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+    Form,
+};
+use chrono::{NaiveDate, NaiveDateTime, Utc};
+use heartbeat_client::api::{DeviceHealth, HealthStatus};
+use maud::{html, PreEscaped};
+use serde::Deserialize;
+
+use crate::{
+    absence::Absence,
+    api_token::{ApiToken, Scope},
+    audit::{AuditEntry, AuditEvent},
+    beat::Beat,
+    cadence,
+    device::Device,
+    errors::AppError,
+    helpers::format_relative,
+    html::base_template,
+    ingest::IngestSource,
+    presence::{PlannedAbsence, Status},
+    proxy::{base_path, url, ClientIp, Https},
+    session::Session,
+    settings::Settings,
+    user::User,
+    AppState,
+};
+
+/// amount of beats shown per page in the dashboard
+const BEATS_PER_PAGE: i64 = 100;
+/// amount of audit log entries shown in the dashboard
+const AUDIT_ENTRIES: i64 = 20;
+/// longest name of a webhook, so its client version still fits
+const MAX_WEBHOOK_NAME_LEN: usize = 32;
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    name: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct CsrfForm {
+    csrf: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeviceForm {
+    csrf: String,
+    name: String,
+    /// checkboxes are only sent when they're checked
+    visible: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SettingsForm {
+    csrf: String,
+    sleep_min_hours: u32,
+    sleep_max_hours: u32,
+    active_minutes: u32,
+}
+
+#[derive(Deserialize)]
+pub struct ApiTokenForm {
+    csrf: String,
+    name: String,
+    /// checkboxes are only sent when they're checked
+    beat_write: Option<String>,
+    stats_read: Option<String>,
+    export_read: Option<String>,
+    status_write: Option<String>,
+    admin: Option<String>,
+    /// empty when the token isn't linked to a device
+    device: String,
+    /// empty when the token doesn't expire
+    expires_at: String,
+}
+
+#[derive(Deserialize)]
+pub struct IngestSourceForm {
+    csrf: String,
+    name: String,
+    device: i64,
+    /// json pointers, empty when not used
+    timestamp_pointer: String,
+    require_pointer: String,
+}
+
+#[derive(Deserialize)]
+pub struct StatusForm {
+    csrf: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+pub struct PlannedAbsenceForm {
+    csrf: String,
+    /// from a datetime-local input, in utc
+    starts_at: String,
+    ends_at: String,
+    note: String,
+    /// checkboxes are only sent when they're checked
+    exclude_from_record: Option<String>,
+}
+
+fn login_template(error: Option<&str>) -> Html<String> {
+    let content = html! {
+        h1 { "log in" }
+        @if let Some(error) = error {
+            p.inactive { (error) }
+        }
+        form method="post" action=(url("/login")) {
+            label { "name " input type="text" name="name" required; }
+            br;
+            label { "password " input type="password" name="password" required; }
+            br;
+            input type="submit" value="log in";
+        }
+    };
+    Html(base_template(content).0)
+}
+
+pub async fn login_page() -> Html<String> {
+    login_template(None)
+}
+
+pub async fn login(
+    State(state): State<Arc<AppState>>,
+    ClientIp(ip): ClientIp,
+    Https(https): Https,
+    Form(form): Form<LoginForm>,
+) -> Result<Response, AppError> {
+    if state.login_attempts.is_locked(ip) {
+        let error = login_template(Some("too many failed logins, try again later"));
+        return Ok((StatusCode::TOO_MANY_REQUESTS, error).into_response());
+    }
+
+    let user = User::get_by_name(&form.name, &state.pool).await?;
+    let Some(user) = user.filter(|user| user.verify_password(&form.password)) else {
+        tracing::warn!(
+            name = form.name,
+            ip = ip.map(tracing::field::display),
+            "failed login"
+        );
+        state.login_attempts.failed(ip);
+        return Ok(login_template(Some("wrong name or password")).into_response());
+    };
+    state.login_attempts.succeeded(ip);
+
+    let session = Session::create(user.id, &state.pool).await?;
+
+    Ok((
+        [(SET_COOKIE, session.cookie(https))],
+        Redirect::to(&url("/admin")),
+    )
+        .into_response())
+}
+
+pub async fn logout(
+    State(state): State<Arc<AppState>>,
+    Https(https): Https,
+    session: Session,
+    Form(form): Form<CsrfForm>,
+) -> Result<Response, AppError> {
+    session.check_csrf(&form.csrf)?;
+    session.delete(&state.pool).await?;
+
+    Ok((
+        [(SET_COOKIE, Session::removal_cookie(https))],
+        Redirect::to(&url("/")),
+    )
+        .into_response())
+}
+
+fn csrf(session: &Session) -> PreEscaped<String> {
+    html! {
+        input type="hidden" name="csrf" value=(session.csrf_token);
+    }
+}
+
+/// Describes whether a device beats as usual, like "silent for 3 days, usually at most 14h"
+fn describe_health(health: &DeviceHealth) -> String {
+    let relative = |secs: Option<i64>| {
+        format_relative(secs.unwrap_or_default())
+            .trim_end()
+            .to_string()
+    };
+    match health.status {
+        HealthStatus::Ok => format!("ok, beats every {}", relative(health.interval)),
+        HealthStatus::Learning => "learning".to_string(),
+        HealthStatus::Silent => format!(
+            "silent for {}, usually at most {}",
+            relative(health.silent_for),
+            relative(health.longest_silence)
+        ),
+        HealthStatus::TooFast => format!("too fast, beats every {}", relative(health.interval)),
+    }
+}
+
+pub async fn dashboard(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Query(q): Query<HashMap<String, String>>,
+) -> Result<Html<String>, AppError> {
+    let user = User::get_by_id(session.user, &state.pool)
+        .await?
+        .ok_or_else(|| AppError::html_from_str("this user doesn't exist anymore :3"))?;
+
+    let devices = Device::get_all(user.id, &state.pool).await?;
+    let mut healths = HashMap::new();
+    let mut conn = state.pool.acquire().await?;
+    for device in &devices {
+        let health = cadence::health(device.id, Utc::now(), &mut conn).await?;
+        healths.insert(device.id, health);
+    }
+    drop(conn);
+    let settings = Settings::get(user.id, &state.pool).await?;
+    let tokens = ApiToken::get_all(user.id, &state.pool).await?;
+    let sources = IngestSource::get_all(user.id, &state.pool).await?;
+    let status = Status::get(user.id, &state.pool).await?;
+    let planned = PlannedAbsence::get_all(user.id, &state.pool).await?;
+    let audit = AuditEntry::get_recent(user.id, AUDIT_ENTRIES, &state.pool).await?;
+
+    let page = q
+        .get("page")
+        .and_then(|s| s.parse::<i64>().ok())
+        .unwrap_or_default()
+        // past that the offset doesn't fit anymore, and there are no beats there anyway
+        .clamp(0, i64::MAX / BEATS_PER_PAGE);
+    let beats = Beat::get_page(user.id, page * BEATS_PER_PAGE, BEATS_PER_PAGE, &state.pool).await?;
+    let device_name = |id: i64| {
+        devices
+            .iter()
+            .find(|device| device.id == id)
+            .map(|device| device.name.as_str())
+            .unwrap_or_default()
+    };
+
+    let content = html! {
+        h1 { "admin" }
+        form method="post" action=(url("/logout")) {
+            "logged in as " strong { (user.name) } " "
+            (csrf(&session))
+            input type="submit" value="log out";
+        }
+
+        h4 { "devices" }
+        table {
+            tr {
+                th { "id" } th { "name" } th { "visible" } th { "beats" } th { "health" } th { "token" } th { }
+            }
+            @for device in &devices {
+                tr {
+                    td { (device.id) }
+                    td colspan="2" {
+                        form method="post" action={(base_path())"/admin/devices/"(device.id)} {
+                            (csrf(&session))
+                            input type="text" name="name" value=(device.name) required;
+                            input type="checkbox" name="visible" value="on" checked[device.visible];
+                            input type="submit" value="save";
+                        }
+                    }
+                    td { (device.beat_count) }
+                    td {
+                        @if let Some(health) = healths.get(&device.id) {
+                            @if matches!(health.status, HealthStatus::Silent | HealthStatus::TooFast) {
+                                strong { (describe_health(health)) }
+                            } @else {
+                                (describe_health(health))
+                            }
+                        }
+                    }
+                    td {
+                        form method="post" action={(base_path())"/admin/devices/"(device.id)"/token"} {
+                            (csrf(&session))
+                            code { (device.token) } " "
+                            input type="submit" value="regenerate";
+                        }
+                    }
+                    td {
+                        form method="post" action={(base_path())"/admin/devices/"(device.id)"/delete"} {
+                            (csrf(&session))
+                            input type="submit" value="delete";
+                        }
+                    }
+                }
+            }
+        }
+        form method="post" action=(url("/admin/devices")) {
+            (csrf(&session))
+            input type="text" name="name" placeholder="new device" required;
+            input type="submit" value="add device";
+        }
+
+        h4 { "status" }
+        form method="post" action=(url("/admin/status")) {
+            (csrf(&session))
+            input type="text" name="message" placeholder="no status" value=[status.as_ref().map(|status| &status.message)];
+            " "
+            input type="submit" value="save";
+        }
+
+        h4 { "planned absences" }
+        ul {
+            @for planned in &planned {
+                li {
+                    form method="post" action={(base_path())"/admin/planned/"(planned.id)"/delete"} {
+                        (csrf(&session))
+                        (planned.starts_at.and_utc().format("%Y/%m/%d %H:%M UTC").to_string())
+                        " to "
+                        (planned.ends_at.and_utc().format("%Y/%m/%d %H:%M UTC").to_string())
+                        @if !planned.note.is_empty() {
+                            ": " (planned.note)
+                        }
+                        @if planned.exclude_from_record {
+                            " (not counted for the longest absence)"
+                        }
+                        " "
+                        input type="submit" value="delete";
+                    }
+                }
+            }
+        }
+        form method="post" action=(url("/admin/planned")) {
+            (csrf(&session))
+            label { "from " input type="datetime-local" name="starts_at" required; }
+            label { " to " input type="datetime-local" name="ends_at" required; }
+            " UTC "
+            input type="text" name="note" placeholder="note";
+            label { " " input type="checkbox" name="exclude_from_record" value="on"; " don't count for the longest absence" }
+            " "
+            input type="submit" value="add";
+        }
+
+        h4 { "api tokens" }
+        p { "api tokens are separate from device tokens, and are only shown once after creating them" }
+        table {
+            tr {
+                th { "name" } th { "scopes" } th { "device" } th { "expires" } th { }
+            }
+            @for token in &tokens {
+                tr {
+                    td { (token.name) }
+                    td {
+                        @for scope in &token.scopes {
+                            code { (scope.as_str()) } " "
+                        }
+                    }
+                    td { @if let Some(device) = token.device { (device_name(device)) } }
+                    td {
+                        @if let Some(expires_at) = token.expires_at {
+                            (expires_at.and_utc().format("%Y/%m/%d %H:%M UTC").to_string())
+                        } @else {
+                            "never"
+                        }
+                    }
+                    td {
+                        form method="post" action={(base_path())"/admin/tokens/"(token.id)"/delete"} {
+                            (csrf(&session))
+                            input type="submit" value="delete";
+                        }
+                    }
+                }
+            }
+        }
+        form method="post" action=(url("/admin/tokens")) {
+            (csrf(&session))
+            input type="text" name="name" placeholder="new token" required;
+            @for (scope, field) in [(Scope::BeatWrite, "beat_write"), (Scope::StatsRead, "stats_read"), (Scope::ExportRead, "export_read"), (Scope::StatusWrite, "status_write"), (Scope::Admin, "admin")] {
+                label { " " input type="checkbox" name=(field) value="on"; " " (scope.as_str()) }
+            }
+            " "
+            select name="device" {
+                option value="" { "no device" }
+                @for device in &devices {
+                    option value=(device.id) { (device.name) }
+                }
+            }
+            label { " expires " input type="date" name="expires_at"; }
+            " "
+            input type="submit" value="create token";
+        }
+
+        h4 { "webhooks" }
+        p {
+            "other services can send beats to " code { (url("/api/ingest/")) "<name>" }
+            ", signed like github's webhooks along with the time in " code { "X-Signature-Timestamp" }
+            ". the secret is only shown once after creating them"
+        }
+        table {
+            tr {
+                th { "name" } th { "device" } th { "timestamp at" } th { "requires" } th { "created" } th { }
+            }
+            @for source in &sources {
+                tr {
+                    td { (source.name) }
+                    td { (device_name(source.device)) }
+                    td { @if let Some(pointer) = &source.timestamp_pointer { code { (pointer) } } }
+                    td { @if let Some(pointer) = &source.require_pointer { code { (pointer) } } }
+                    td { (source.created_at.and_utc().format("%Y/%m/%d %H:%M UTC").to_string()) }
+                    td {
+                        form method="post" action={(base_path())"/admin/webhooks/"(source.id)"/delete"} {
+                            (csrf(&session))
+                            input type="submit" value="delete";
+                        }
+                    }
+                }
+            }
+        }
+        form method="post" action=(url("/admin/webhooks")) {
+            (csrf(&session))
+            input type="text" name="name" placeholder="new webhook" pattern="[a-z0-9_-]+" required;
+            " "
+            select name="device" required {
+                @for device in &devices {
+                    option value=(device.id) { (device.name) }
+                }
+            }
+            " "
+            input type="text" name="timestamp_pointer" placeholder="/head_commit/timestamp";
+            " "
+            input type="text" name="require_pointer" placeholder="/head_commit";
+            " "
+            input type="submit" value="create webhook";
+        }
+
+        h4 { "settings" }
+        form method="post" action=(url("/admin/settings")) {
+            (csrf(&session))
+            label { "sleep is at least " input type="number" name="sleep_min_hours" min="1" value=(settings.sleep_min_hours); " hours" }
+            br;
+            label { "sleep is at most " input type="number" name="sleep_max_hours" min="1" value=(settings.sleep_max_hours); " hours" }
+            br;
+            label { "active for " input type="number" name="active_minutes" min="1" value=(settings.active_minutes); " minutes after a beat" }
+            br;
+            input type="submit" value="save";
+        }
+
+        h4 { "audit log" }
+        ul {
+            @for entry in &audit {
+                li {
+                    (entry.timestamp.and_utc().format("%Y/%m/%d %H:%M:%S UTC").to_string())
+                    ": " (entry.event)
+                    @if let Some(ip) = &entry.ip {
+                        " from " (ip)
+                    }
+                }
+            }
+        }
+
+        h4 { "beats" }
+        ul {
+            @for beat in &beats {
+                li {
+                    form method="post" action={(base_path())"/admin/beats/"(beat.id)"/delete"} {
+                        (csrf(&session))
+                        (beat.timestamp.and_utc().format("%Y/%m/%d %H:%M:%S UTC").to_string())
+                        " from " (device_name(beat.device)) " "
+                        input type="submit" value="delete";
+                    }
+                }
+            }
+        }
+        p {
+            @if page > 0 {
+                a href={(base_path())"/admin?page="(page - 1)} { "newer" } " "
+            }
+            @if beats.len() as i64 == BEATS_PER_PAGE {
+                a href={(base_path())"/admin?page="(page + 1)} { "older" }
+            }
+        }
+    };
+    let content = base_template(content);
+
+    Ok(Html(content.0))
+}
+
+pub async fn create_device(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Form(form): Form<DeviceForm>,
+) -> Result<Redirect, AppError> {
+    session.check_csrf(&form.csrf)?;
+
+    Device::register(session.user, &form.name, &state.pool).await?;
+
+    Ok(Redirect::to(&url("/admin")))
+}
+
+/// gets a device of the logged in user
+async fn get_device(id: i64, session: &Session, state: &AppState) -> Result<Device, AppError> {
+    Device::get_by_id(id, &state.pool)
+        .await?
+        .filter(|device| device.user == session.user)
+        .ok_or_else(|| AppError::html_from_str("there is no such device :3"))
+}
+
+pub async fn update_device(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Path(id): Path<i64>,
+    Form(form): Form<DeviceForm>,
+) -> Result<Redirect, AppError> {
+    session.check_csrf(&form.csrf)?;
+
+    let mut device = get_device(id, &session, &state).await?;
+    device.name = form.name;
+    device.visible = form.visible.is_some();
+    device.update(&state.pool).await?;
+
+    Ok(Redirect::to(&url("/admin")))
+}
+
+pub async fn regenerate_device_token(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<i64>,
+    Form(form): Form<CsrfForm>,
+) -> Result<Redirect, AppError> {
+    session.check_csrf(&form.csrf)?;
+
+    let mut device = get_device(id, &session, &state).await?;
+    device.regenerate_token(&state.pool).await?;
+    AuditEvent::DeviceTokenRegenerated { device: device.id }
+        .record(Some(session.user), ip, &state.pool)
+        .await?;
+
+    Ok(Redirect::to(&url("/admin")))
+}
+
+pub async fn delete_device(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Path(id): Path<i64>,
+    Form(form): Form<CsrfForm>,
+) -> Result<Redirect, AppError> {
+    session.check_csrf(&form.csrf)?;
+
+    let device = get_device(id, &session, &state).await?;
+    device.delete(&state.pool).await?;
+    state.home_snapshots.invalidate(device.user);
+
+    Ok(Redirect::to(&url("/admin")))
+}
+
+/// Deletes a beat, and replaces the absences around it with one spanning the gap it leaves
+pub async fn delete_beat(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Path(id): Path<i64>,
+    Form(form): Form<CsrfForm>,
+) -> Result<Redirect, AppError> {
+    session.check_csrf(&form.csrf)?;
+
+    let mut tx = state.pool.begin().await?;
+
+    let no_beat = || AppError::html_from_str("there is no such beat :3");
+    let beat = Beat::get_by_id(id, &mut *tx).await?.ok_or_else(no_beat)?;
+    let device = Device::get_by_id(beat.device, &mut *tx)
+        .await?
+        .filter(|device| device.user == session.user)
+        .ok_or_else(no_beat)?;
+
+    // absences that start or end on this beat get deleted along with it
+    beat.delete(&mut *tx).await?;
+
+    let last_beat = Beat::last_before(device.user, &beat.timestamp, &mut *tx).await?;
+    let next_beat = Beat::first_after(device.user, &beat.timestamp, &mut *tx).await?;
+    // old beats were compacted, so the gaps between them were never measured
+    let compacted_until = Beat::compacted_until(device.user, &beat.timestamp, &mut *tx).await?;
+    let next_beat =
+        next_beat.filter(|beat| compacted_until.is_none_or(|until| beat.timestamp > until));
+
+    let gap = last_beat.zip(next_beat);
+    if let Some((last_beat, next_beat)) = &gap {
+        let diff = next_beat.timestamp.and_utc() - last_beat.timestamp.and_utc();
+
+        // if the absence was longer than 1h, log it
+        if diff.num_hours() >= 1 {
+            Absence {
+                id: 0,
+                timestamp: next_beat.timestamp,
+                duration: diff.num_seconds(),
+                begin_beat: last_beat.id,
+                end_beat: next_beat.id,
+            }
+            .create(&mut *tx)
+            .await?;
+        }
+    }
+
+    tx.commit().await?;
+    state.home_snapshots.invalidate(device.user);
+
+    // the longest absence only changes once the beat is gone
+    if let Some((last_beat, next_beat)) = gap {
+        state
+            .longest_absences
+            .record(
+                device.user,
+                last_beat.timestamp.and_utc(),
+                next_beat.timestamp.and_utc(),
+                &state.pool,
+            )
+            .await?;
+    }
+
+    Ok(Redirect::to(&url("/admin")))
+}
+
+pub async fn update_settings(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Form(form): Form<SettingsForm>,
+) -> Result<Redirect, AppError> {
+    session.check_csrf(&form.csrf)?;
+
+    Settings {
+        sleep_min_hours: form.sleep_min_hours,
+        sleep_max_hours: form.sleep_max_hours,
+        active_minutes: form.active_minutes,
+    }
+    .save(session.user, &state.pool)
+    .await?;
+
+    Ok(Redirect::to(&url("/admin")))
+}
+
+pub async fn update_status(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Form(form): Form<StatusForm>,
+) -> Result<Redirect, AppError> {
+    session.check_csrf(&form.csrf)?;
+
+    Status::set(session.user, &form.message, &state.pool).await?;
+
+    Ok(Redirect::to(&url("/admin")))
+}
+
+pub async fn create_planned_absence(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Form(form): Form<PlannedAbsenceForm>,
+) -> Result<Redirect, AppError> {
+    session.check_csrf(&form.csrf)?;
+
+    let date = |s: &str| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M").ok();
+    let (Some(starts_at), Some(ends_at)) = (date(&form.starts_at), date(&form.ends_at)) else {
+        return Err(AppError::html_from_str("those dates don't look right :3"));
+    };
+    if ends_at <= starts_at {
+        return Err(AppError::html_from_str(
+            "planned absences have to end after they start :3",
+        ));
+    }
+
+    PlannedAbsence {
+        id: 0,
+        user: session.user,
+        starts_at,
+        ends_at,
+        note: form.note.trim().to_string(),
+        exclude_from_record: form.exclude_from_record.is_some(),
+    }
+    .create(&state.pool)
+    .await?;
+    state
+        .longest_absences
+        .refresh(session.user, &state.pool)
+        .await?;
+
+    Ok(Redirect::to(&url("/admin")))
+}
+
+pub async fn delete_planned_absence(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    Path(id): Path<i64>,
+    Form(form): Form<CsrfForm>,
+) -> Result<Redirect, AppError> {
+    session.check_csrf(&form.csrf)?;
+
+    PlannedAbsence::delete(session.user, id, &state.pool).await?;
+    state
+        .longest_absences
+        .refresh(session.user, &state.pool)
+        .await?;
+
+    Ok(Redirect::to(&url("/admin")))
+}
+
+pub async fn create_api_token(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    ClientIp(ip): ClientIp,
+    Form(form): Form<ApiTokenForm>,
+) -> Result<Html<String>, AppError> {
+    session.check_csrf(&form.csrf)?;
+
+    let scopes = [
+        (Scope::BeatWrite, &form.beat_write),
+        (Scope::StatsRead, &form.stats_read),
+        (Scope::ExportRead, &form.export_read),
+        (Scope::StatusWrite, &form.status_write),
+        (Scope::Admin, &form.admin),
+    ]
+    .into_iter()
+    .filter(|(_, checked)| checked.is_some())
+    .map(|(scope, _)| scope)
+    .collect::<Vec<_>>();
+
+    let device = match form.device.parse::<i64>() {
+        Ok(id) => Some(get_device(id, &session, &state).await?.id),
+        Err(_) => None,
+    };
+    if scopes.contains(&Scope::BeatWrite) && device.is_none() {
+        return Err(AppError::html_from_str(
+            "tokens with the beat:write scope need a device :3",
+        ));
+    }
+
+    let expires_at = NaiveDate::parse_from_str(&form.expires_at, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0));
+
+    let token = ApiToken::create(
+        session.user,
+        &form.name,
+        &scopes,
+        device,
+        expires_at,
+        &state.pool,
+    )
+    .await?;
+    AuditEvent::TokenCreated {
+        token: token.id,
+        name: token.name.clone(),
+        scopes: token.scopes.clone(),
+    }
+    .record(Some(session.user), ip, &state.pool)
+    .await?;
+
+    let content = html! {
+        h1 { "token created" }
+        p { "this is the only time the token for " strong { (token.name) } " is shown:" }
+        p { code { (token.token) } }
+        a href=(url("/admin")) { "back" }
+    };
+    let content = base_template(content);
+
+    Ok(Html(content.0))
+}
+
+pub async fn delete_api_token(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<i64>,
+    Form(form): Form<CsrfForm>,
+) -> Result<Redirect, AppError> {
+    session.check_csrf(&form.csrf)?;
+
+    if ApiToken::delete(session.user, id, &state.pool).await? {
+        AuditEvent::TokenDeleted { token: id }
+            .record(Some(session.user), ip, &state.pool)
+            .await?;
+    }
+
+    Ok(Redirect::to(&url("/admin")))
+}
+
+pub async fn create_ingest_source(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    ClientIp(ip): ClientIp,
+    Form(form): Form<IngestSourceForm>,
+) -> Result<Html<String>, AppError> {
+    session.check_csrf(&form.csrf)?;
+
+    let valid_name = !form.name.is_empty()
+        && form.name.len() <= MAX_WEBHOOK_NAME_LEN
+        && form
+            .name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if !valid_name {
+        return Err(AppError::html_from_str(
+            "webhook names can only have up to 32 lowercase letters, digits, - and _ :3",
+        ));
+    }
+    if IngestSource::get_by_name(&form.name, &state.pool)
+        .await?
+        .is_some()
+    {
+        return Err(AppError::html_from_str(
+            "there's already a webhook with that name :3",
+        ));
+    }
+    let pointer = |pointer: String| match pointer.trim() {
+        "" => Ok(None),
+        pointer if pointer.starts_with('/') => Ok(Some(pointer.to_string())),
+        _ => Err(AppError::html_from_str(
+            "json pointers start with a /, like /head_commit/timestamp :3",
+        )),
+    };
+    let timestamp_pointer = pointer(form.timestamp_pointer)?;
+    let require_pointer = pointer(form.require_pointer)?;
+    let device = get_device(form.device, &session, &state).await?;
+
+    let source = IngestSource::create(
+        session.user,
+        device.id,
+        &form.name,
+        timestamp_pointer,
+        require_pointer,
+        &state.pool,
+    )
+    .await?;
+    AuditEvent::IngestSourceCreated {
+        source: source.id,
+        name: source.name.clone(),
+    }
+    .record(Some(session.user), ip, &state.pool)
+    .await?;
+
+    let content = html! {
+        h1 { "webhook created" }
+        p {
+            "send payloads to " code { (url("/api/ingest/"))(source.name) }
+            ", signed with this secret. this is the only time it's shown:"
+        }
+        p { code { (source.secret) } }
+        a href=(url("/admin")) { "back" }
+    };
+    let content = base_template(content);
+
+    Ok(Html(content.0))
+}
+
+pub async fn delete_ingest_source(
+    State(state): State<Arc<AppState>>,
+    session: Session,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<i64>,
+    Form(form): Form<CsrfForm>,
+) -> Result<Redirect, AppError> {
+    session.check_csrf(&form.csrf)?;
+
+    if IngestSource::delete(session.user, id, &state.pool).await? {
+        AuditEvent::IngestSourceDeleted { source: id }
+            .record(Some(session.user), ip, &state.pool)
+            .await?;
+    }
+
+    Ok(Redirect::to(&url("/admin")))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        session::{MAX_FAILED_LOGINS, SESSION_COOKIE},
+        testing::init_state,
+    };
+
+    use super::*;
+    use ::axum_test::TestServer;
+    use assertables::*;
+    use axum::{
+        http::{header::COOKIE, HeaderName, HeaderValue, StatusCode},
+        routing::{get, post},
+        Router,
+    };
+    use chrono::{TimeDelta, Utc};
+
+    async fn base() -> (TestServer, Arc<AppState>, Session) {
+        let state = init_state().await;
+
+        Device {
+            id: 1,
+            user: 1,
+            name: "test device".to_string(),
+            token: "my_token".to_string(),
+            beat_count: 0,
+            visible: true,
+        }
+        .create(&state.pool)
+        .await
+        .unwrap();
+
+        User::set_password("annie", "hunter2", &state.pool)
+            .await
+            .unwrap();
+        let user = User::get_by_name("annie", &state.pool)
+            .await
+            .unwrap()
+            .unwrap();
+        let session = Session::create(user.id, &state.pool).await.unwrap();
+
+        let app = Router::new()
+            .route("/login", get(login_page).post(login))
+            .route("/admin", get(dashboard))
+            .route("/admin/devices", post(create_device))
+            .route("/admin/beats/:id/delete", post(delete_beat))
+            .route("/admin/tokens", post(create_api_token))
+            .route("/admin/webhooks", post(create_ingest_source))
+            .with_state(state.clone());
+        let server = TestServer::new(app).unwrap();
+
+        (server, state, session)
+    }
+
+    fn cookie(session: &Session) -> HeaderValue {
+        HeaderValue::from_str(&format!("{SESSION_COOKIE}={}", session.token)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn login_checks_password() -> Result<()> {
+        let (server, _state, _session) = base().await;
+
+        let response = server
+            .post("/login")
+            .form(&[("name", "annie"), ("password", "hunter3")])
+            .await;
+        response.assert_status_ok();
+        assert_contains!(response.text(), "wrong name or password");
+
+        let response = server
+            .post("/login")
+            .form(&[("name", "annie"), ("password", "hunter2")])
+            .await;
+        response.assert_status(StatusCode::SEE_OTHER);
+        assert_starts_with!(
+            response.header(SET_COOKIE).to_str()?,
+            &format!("{SESSION_COOKIE}=")
+        );
+        assert!(!response.header(SET_COOKIE).to_str()?.contains("Secure"));
+
+        // behind a proxy that terminates tls
+        let response = server
+            .post("/login")
+            .add_header(
+                HeaderName::from_static("x-forwarded-proto"),
+                HeaderValue::from_static("https"),
+            )
+            .form(&[("name", "annie"), ("password", "hunter2")])
+            .await;
+        assert_contains!(response.header(SET_COOKIE).to_str()?, "; Secure");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limits_failed_logins() -> Result<()> {
+        let (server, _state, _session) = base().await;
+
+        for _ in 0..MAX_FAILED_LOGINS {
+            server
+                .post("/login")
+                .form(&[("name", "annie"), ("password", "hunter3")])
+                .await
+                .assert_status_ok();
+        }
+
+        // even the right password has to wait
+        let response = server
+            .post("/login")
+            .form(&[("name", "annie"), ("password", "hunter2")])
+            .await;
+        response.assert_status(StatusCode::TOO_MANY_REQUESTS);
+        assert_contains!(response.text(), "too many failed logins");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admin_requires_session() -> Result<()> {
+        let (server, _state, session) = base().await;
+
+        let response = server.get("/admin").await;
+        response.assert_status(StatusCode::SEE_OTHER);
+
+        let response = server
+            .get("/admin")
+            .add_header(COOKIE, cookie(&session))
+            .await;
+        response.assert_status_ok();
+        assert_contains!(response.text(), "logged in as <strong>annie</strong>");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn takes_any_page() -> Result<()> {
+        let (server, _state, session) = base().await;
+
+        for page in [i64::MIN, -1, i64::MAX] {
+            server
+                .get("/admin")
+                .add_query_param("page", page)
+                .add_header(COOKIE, cookie(&session))
+                .await
+                .assert_status_ok();
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn forms_require_csrf_token() -> Result<()> {
+        let (server, state, session) = base().await;
+
+        let response = server
+            .post("/admin/devices")
+            .add_header(COOKIE, cookie(&session))
+            .form(&[("csrf", "wrong"), ("name", "phone")])
+            .await;
+        response.assert_status(StatusCode::FORBIDDEN);
+        assert_eq!(1, Device::get_all(1, &state.pool).await?.len());
+
+        let response = server
+            .post("/admin/devices")
+            .add_header(COOKIE, cookie(&session))
+            .form(&[("csrf", session.csrf_token.as_str()), ("name", "phone")])
+            .await;
+        response.assert_status(StatusCode::SEE_OTHER);
+        assert_eq!(2, Device::get_all(1, &state.pool).await?.len());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deleting_beats_recomputes_absences() -> Result<()> {
+        let (server, state, session) = base().await;
+
+        // three beats, 40 minutes apart. there's no absence yet
+        let mut ids = vec![];
+        for minutes in [80, 40, 0] {
+            let beat = Beat {
+                id: 0,
+                device: 1,
+                timestamp: (Utc::now() - TimeDelta::minutes(minutes)).naive_utc(),
+            }
+            .create(&state.pool)
+            .await?;
+            ids.push(beat.id);
+        }
+        assert_eq!(0, Absence::count(1, &state.pool).await?);
+
+        let response = server
+            .post(&format!("/admin/beats/{}/delete", ids[1]))
+            .add_header(COOKIE, cookie(&session))
+            .form(&[("csrf", session.csrf_token.as_str())])
+            .await;
+        response.assert_status(StatusCode::SEE_OTHER);
+
+        assert_eq!(2, Beat::count(1, &state.pool).await?);
+        assert_eq!(1, Absence::count(1, &state.pool).await?);
+        assert_eq!(80 * 60, state.longest_absences.get(1));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cant_touch_other_users_beats() -> Result<()> {
+        let (server, state, _session) = base().await;
+
+        User::set_password("bob", "hunter2", &state.pool).await?;
+        let bob = User::get_by_name("bob", &state.pool).await?.unwrap();
+        let session = Session::create(bob.id, &state.pool).await?;
+
+        let beat = Beat {
+            id: 0,
+            device: 1,
+            timestamp: Utc::now().naive_utc(),
+        }
+        .create(&state.pool)
+        .await?;
+
+        let response = server
+            .get("/admin")
+            .add_header(COOKIE, cookie(&session))
+            .await;
+        assert_not_contains!(response.text(), "test device");
+
+        let response = server
+            .post(&format!("/admin/beats/{}/delete", beat.id))
+            .add_header(COOKIE, cookie(&session))
+            .form(&[("csrf", session.csrf_token.as_str())])
+            .await;
+        assert_contains!(response.text(), "there is no such beat");
+        assert_eq!(1, Beat::count(1, &state.pool).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn creates_api_tokens() -> Result<()> {
+        let (server, state, session) = base().await;
+
+        let response = server
+            .post("/admin/tokens")
+            .add_header(COOKIE, cookie(&session))
+            .form(&[
+                ("csrf", session.csrf_token.as_str()),
+                ("name", "dashboard"),
+                ("stats_read", "on"),
+                ("device", ""),
+                ("expires_at", "2099-01-01"),
+            ])
+            .await;
+        response.assert_status_ok();
+
+        let tokens = ApiToken::get_all(1, &state.pool).await?;
+        assert_eq!(1, tokens.len());
+        assert_eq!(vec![Scope::StatsRead], tokens[0].scopes);
+        assert_contains!(response.text(), &tokens[0].token);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn creates_webhooks() -> Result<()> {
+        let (server, state, session) = base().await;
+        let form = |name: &'static str, pointer: &'static str| {
+            [
+                ("csrf", session.csrf_token.clone()),
+                ("name", name.to_string()),
+                ("device", "1".to_string()),
+                ("timestamp_pointer", pointer.to_string()),
+                ("require_pointer", "".to_string()),
+            ]
+        };
+
+        let response = server
+            .post("/admin/webhooks")
+            .add_header(COOKIE, cookie(&session))
+            .form(&form("github", "/head_commit/timestamp"))
+            .await;
+        response.assert_status_ok();
+        let sources = IngestSource::get_all(1, &state.pool).await?;
+        assert_eq!(1, sources.len());
+        assert_eq!(
+            Some("/head_commit/timestamp"),
+            sources[0].timestamp_pointer.as_deref()
+        );
+        assert_eq!(None, sources[0].require_pointer);
+        assert_contains!(response.text(), &sources[0].secret);
+
+        for (name, pointer) in [("github", ""), ("Git Hub", ""), ("gitlab", "head_commit")] {
+            let response = server
+                .post("/admin/webhooks")
+                .add_header(COOKIE, cookie(&session))
+                .form(&form(name, pointer))
+                .await;
+            assert_not_contains!(response.text(), "webhook created");
+        }
+        assert_eq!(1, IngestSource::get_all(1, &state.pool).await?.len());
+
+        let response = server
+            .get("/admin")
+            .add_header(COOKIE, cookie(&session))
+            .await;
+        assert_contains!(response.text(), "<td>github</td>");
+
+        Ok(())
+    }
+}
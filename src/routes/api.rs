@@ -0,0 +1,640 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{NaiveDateTime, Utc};
+use heartbeat_client::api::{DeviceStats, Stats};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    absence::Absence,
+    api_token::{Admin, ApiToken, ExportRead, Scope, Scoped, StatsRead, StatusWrite},
+    audit::AuditEvent,
+    beat::{Beat, BeatFilter},
+    cadence,
+    device::Device,
+    errors::AppError,
+    presence::{PlannedAbsence, Status},
+    proxy::ClientIp,
+    settings::Settings,
+    AppState,
+};
+
+/// Stats of the user of the token. the beats can be filtered by device and metadata, like
+/// `?device=2&source=screen-unlock`, which leaves the longest absence and status as they are
+pub async fn stats(
+    State(state): State<Arc<AppState>>,
+    Scoped(token, _): Scoped<StatsRead>,
+    Query(filter): Query<BeatFilter>,
+) -> Result<Json<Stats>, AppError> {
+    let user = token.user;
+    let summary = Beat::summary(user, &filter, &state.pool).await?;
+    let settings = Settings::get(user, &state.pool).await?;
+
+    let time_since_last_beat = summary
+        .last
+        .map(|last_beat| (Utc::now() - last_beat.and_utc()).num_seconds());
+    // the absence since the last beat only counts when every beat is looked at
+    if let Some(last_beat) = summary.last.filter(|_| filter.is_empty()) {
+        state
+            .longest_absences
+            .record(user, last_beat.and_utc(), Utc::now(), &state.pool)
+            .await?;
+    }
+
+    let counts = Beat::count_per_device(user, &filter, &state.pool).await?;
+    let mut conn = state.pool.acquire().await?;
+    let mut devices = Vec::new();
+    for device in Device::get_all(user, &mut *conn).await? {
+        devices.push(DeviceStats {
+            beat_count: counts.get(&device.id).copied().unwrap_or_default(),
+            health: Some(cadence::health(device.id, Utc::now(), &mut conn).await?),
+            id: device.id,
+            name: device.name,
+        });
+    }
+
+    let status = Status::get(user, &state.pool).await?;
+    let planned_absences = PlannedAbsence::get_upcoming(user, &state.pool)
+        .await?
+        .into_iter()
+        .map(Into::into)
+        .collect();
+
+    Ok(Json(Stats {
+        active: time_since_last_beat.is_some_and(|dur| dur < 60 * settings.active_minutes as i64),
+        first_beat: summary.first,
+        last_beat: summary.last,
+        time_since_last_beat,
+        total_beats: summary.count as i32,
+        longest_absence: state.longest_absences.get(user),
+        devices,
+        status: status.map(|status| status.message),
+        planned_absences,
+    }))
+}
+
+/// most beats sent in one page of the export
+const EXPORT_LIMIT: i64 = 10_000;
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    /// only export beats and absences from this on
+    since: Option<NaiveDateTime>,
+    /// leave out beats at `since` up to this id, which were on the last page
+    #[serde(default)]
+    after: i64,
+    /// how many beats to export at most, up to [`EXPORT_LIMIT`]
+    limit: Option<i64>,
+}
+
+/// Where the next page of the export starts
+#[derive(Serialize)]
+pub struct ExportNext {
+    since: NaiveDateTime,
+    after: i64,
+}
+
+#[derive(Serialize)]
+pub struct Export {
+    beats: Vec<Beat>,
+    absences: Vec<Absence>,
+    /// missing on the last page
+    next: Option<ExportNext>,
+}
+
+/// Exports the beats of the user of the token oldest first, in pages. the absences on a page are
+/// the ones that end in the time it covers
+pub async fn export(
+    State(state): State<Arc<AppState>>,
+    Scoped(token, _): Scoped<ExportRead>,
+    Query(q): Query<ExportQuery>,
+) -> Result<Json<Export>, AppError> {
+    let since = q.since.unwrap_or_default();
+    let limit = q.limit.unwrap_or(EXPORT_LIMIT).clamp(1, EXPORT_LIMIT);
+
+    // one more, to know if there's another page
+    let mut beats =
+        Beat::get_page_since(token.user, &since, q.after, limit + 1, &state.pool).await?;
+    let next = if beats.len() as i64 > limit {
+        beats.truncate(limit as usize);
+        beats.last().map(|beat| ExportNext {
+            since: beat.timestamp,
+            after: beat.id,
+        })
+    } else {
+        None
+    };
+
+    let until = next.as_ref().map(|next| &next.since);
+    let absences = Absence::get_all_between(token.user, &since, until, &state.pool).await?;
+
+    Ok(Json(Export {
+        beats,
+        absences,
+        next,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct NewStatus {
+    /// empty or missing to clear the status
+    #[serde(default)]
+    message: String,
+}
+
+pub async fn set_status(
+    State(state): State<Arc<AppState>>,
+    Scoped(token, _): Scoped<StatusWrite>,
+    Json(status): Json<NewStatus>,
+) -> Result<StatusCode, AppError> {
+    Status::set(token.user, &status.message, &state.pool).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_planned_absences(
+    State(state): State<Arc<AppState>>,
+    Scoped(token, _): Scoped<StatusWrite>,
+) -> Result<Json<Vec<PlannedAbsence>>, AppError> {
+    Ok(Json(
+        PlannedAbsence::get_all(token.user, &state.pool).await?,
+    ))
+}
+
+pub async fn create_planned_absence(
+    State(state): State<Arc<AppState>>,
+    Scoped(token, _): Scoped<StatusWrite>,
+    Json(mut planned): Json<PlannedAbsence>,
+) -> Result<Json<PlannedAbsence>, AppError> {
+    if planned.ends_at <= planned.starts_at {
+        return Err(AppError::Rejection(
+            StatusCode::BAD_REQUEST,
+            "planned absences have to end after they start",
+        ));
+    }
+
+    planned.user = token.user;
+    let planned = planned.create(&state.pool).await?;
+    state
+        .longest_absences
+        .refresh(token.user, &state.pool)
+        .await?;
+
+    Ok(Json(planned))
+}
+
+pub async fn delete_planned_absence(
+    State(state): State<Arc<AppState>>,
+    Scoped(token, _): Scoped<StatusWrite>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, AppError> {
+    PlannedAbsence::delete(token.user, id, &state.pool).await?;
+    state
+        .longest_absences
+        .refresh(token.user, &state.pool)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_tokens(
+    State(state): State<Arc<AppState>>,
+    Scoped(token, _): Scoped<Admin>,
+) -> Result<Json<Vec<ApiToken>>, AppError> {
+    Ok(Json(ApiToken::get_all(token.user, &state.pool).await?))
+}
+
+#[derive(Deserialize)]
+pub struct NewToken {
+    name: String,
+    scopes: Vec<Scope>,
+    device: Option<i64>,
+    expires_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedToken {
+    /// the secret token. this is the only time it's returned by the api
+    token: String,
+    #[serde(flatten)]
+    info: ApiToken,
+}
+
+pub async fn create_token(
+    State(state): State<Arc<AppState>>,
+    Scoped(admin, _): Scoped<Admin>,
+    ClientIp(ip): ClientIp,
+    Json(new): Json<NewToken>,
+) -> Result<Json<CreatedToken>, AppError> {
+    if new.scopes.contains(&Scope::BeatWrite) && new.device.is_none() {
+        return Err(AppError::Rejection(
+            StatusCode::BAD_REQUEST,
+            "tokens with the beat:write scope need a device",
+        ));
+    }
+
+    if let Some(device) = new.device {
+        let device = Device::get_by_id(device, &state.pool).await?;
+        if device.map(|device| device.user) != Some(admin.user) {
+            return Err(AppError::Rejection(
+                StatusCode::BAD_REQUEST,
+                "there is no such device",
+            ));
+        }
+    }
+
+    let token = ApiToken::create(
+        admin.user,
+        &new.name,
+        &new.scopes,
+        new.device,
+        new.expires_at,
+        &state.pool,
+    )
+    .await?;
+    AuditEvent::TokenCreated {
+        token: token.id,
+        name: token.name.clone(),
+        scopes: token.scopes.clone(),
+    }
+    .record(Some(admin.user), ip, &state.pool)
+    .await?;
+
+    Ok(Json(CreatedToken {
+        token: token.token.clone(),
+        info: token,
+    }))
+}
+
+pub async fn delete_token(
+    State(state): State<Arc<AppState>>,
+    Scoped(token, _): Scoped<Admin>,
+    ClientIp(ip): ClientIp,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, AppError> {
+    if token.id == id {
+        return Err(AppError::Rejection(
+            StatusCode::BAD_REQUEST,
+            "can't delete the token used for this request",
+        ));
+    }
+
+    if ApiToken::delete(token.user, id, &state.pool).await? {
+        AuditEvent::TokenDeleted { token: id }
+            .record(Some(token.user), ip, &state.pool)
+            .await?;
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{routes::beat::beat, testing::init_state};
+
+    use super::*;
+    use ::axum_test::TestServer;
+    use axum::{
+        http::{HeaderName, HeaderValue},
+        routing::{get, post, put},
+        Router,
+    };
+    use chrono::TimeDelta;
+    use heartbeat_client::api::HealthStatus;
+
+    async fn base() -> (TestServer, Arc<AppState>) {
+        let state = init_state().await;
+
+        Device {
+            id: 1,
+            user: 1,
+            name: "test device".to_string(),
+            token: "my_token".to_string(),
+            beat_count: 0,
+            visible: true,
+        }
+        .create(&state.pool)
+        .await
+        .unwrap();
+
+        let app = Router::new()
+            .route("/api/beat", post(beat))
+            .route("/api/stats", get(stats))
+            .route("/api/export", get(export))
+            .route("/api/tokens", get(list_tokens).post(create_token))
+            .route("/api/status", put(set_status))
+            .route(
+                "/api/planned",
+                get(list_planned_absences).post(create_planned_absence),
+            )
+            .with_state(state.clone());
+        let server = TestServer::new(app).unwrap();
+
+        (server, state)
+    }
+
+    fn auth(token: &str) -> Result<(HeaderName, HeaderValue)> {
+        Ok((
+            HeaderName::from_bytes(b"Authorization")?,
+            HeaderValue::from_str(token)?,
+        ))
+    }
+
+    #[tokio::test]
+    async fn stats_requires_scope() -> Result<()> {
+        let (server, state) = base().await;
+
+        let export =
+            ApiToken::create(1, "export", &[Scope::ExportRead], None, None, &state.pool).await?;
+        let stats =
+            ApiToken::create(1, "stats", &[Scope::StatsRead], None, None, &state.pool).await?;
+
+        let response = server.get("/api/stats").await;
+        response.assert_status(StatusCode::BAD_REQUEST);
+
+        // device tokens aren't api tokens
+        let (name, value) = auth("my_token")?;
+        let response = server.get("/api/stats").add_header(name, value).await;
+        response.assert_status(StatusCode::UNAUTHORIZED);
+
+        let (name, value) = auth(&export.token)?;
+        let response = server.get("/api/stats").add_header(name, value).await;
+        response.assert_status(StatusCode::FORBIDDEN);
+
+        Beat {
+            id: 0,
+            device: 1,
+            timestamp: (Utc::now() - TimeDelta::minutes(1)).naive_utc(),
+        }
+        .create(&state.pool)
+        .await?;
+
+        let (name, value) = auth(&stats.token)?;
+        let response = server.get("/api/stats").add_header(name, value).await;
+        response.assert_status_ok();
+        let stats = response.json::<Stats>();
+        assert!(stats.active);
+        assert_eq!(1, stats.total_beats);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exports_in_pages() -> Result<()> {
+        let (server, state) = base().await;
+
+        let export =
+            ApiToken::create(1, "export", &[Scope::ExportRead], None, None, &state.pool).await?;
+        let now = Utc::now();
+        let mut ids = vec![];
+        for hours in [3, 1, 0] {
+            let beat = Beat {
+                id: 0,
+                device: 1,
+                timestamp: (now - TimeDelta::hours(hours)).naive_utc(),
+            }
+            .create(&state.pool)
+            .await?;
+            ids.push(beat.id);
+        }
+        Absence {
+            id: 0,
+            timestamp: (now - TimeDelta::hours(1)).naive_utc(),
+            duration: TimeDelta::hours(2).num_seconds(),
+            begin_beat: ids[0],
+            end_beat: ids[1],
+        }
+        .create(&state.pool)
+        .await?;
+
+        let (name, value) = auth(&export.token)?;
+        let response = server
+            .get("/api/export")
+            .add_query_param("limit", 2)
+            .add_header(name.clone(), value.clone())
+            .await;
+        response.assert_status_ok();
+        let page = response.json::<serde_json::Value>();
+        assert_eq!(2, page["beats"].as_array().unwrap().len());
+        // the absence ends on the last beat of the page, which the next page starts with
+        assert_eq!(0, page["absences"].as_array().unwrap().len());
+        assert_eq!(ids[1], page["next"]["after"]);
+
+        let response = server
+            .get("/api/export")
+            .add_query_param("limit", 2)
+            .add_query_param("since", page["next"]["since"].as_str().unwrap())
+            .add_query_param("after", &page["next"]["after"])
+            .add_header(name, value)
+            .await;
+        response.assert_status_ok();
+        let page = response.json::<serde_json::Value>();
+        assert_eq!(ids[2], page["beats"][0]["id"]);
+        assert_eq!(1, page["beats"].as_array().unwrap().len());
+        assert_eq!(1, page["absences"].as_array().unwrap().len());
+        assert!(page["next"].is_null());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_tokens_cant_send_beats() -> Result<()> {
+        let (server, state) = base().await;
+
+        let stats =
+            ApiToken::create(1, "stats", &[Scope::StatsRead], Some(1), None, &state.pool).await?;
+        let write =
+            ApiToken::create(1, "write", &[Scope::BeatWrite], Some(1), None, &state.pool).await?;
+
+        let (name, value) = auth(&stats.token)?;
+        let response = server.post("/api/beat").add_header(name, value).await;
+        response.assert_status(StatusCode::FORBIDDEN);
+        assert_eq!(0, Beat::count(1, &state.pool).await?);
+
+        let (name, value) = auth(&write.token)?;
+        let response = server.post("/api/beat").add_header(name, value).await;
+        response.assert_status_ok();
+        assert_eq!(1, Beat::count(1, &state.pool).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admins_can_create_tokens() -> Result<()> {
+        let (server, state) = base().await;
+
+        let admin = ApiToken::create(1, "admin", &[Scope::Admin], None, None, &state.pool).await?;
+
+        let (name, value) = auth(&admin.token)?;
+        let response = server
+            .post("/api/tokens")
+            .add_header(name.clone(), value.clone())
+            .json(&serde_json::json!({
+                "name": "dashboard",
+                "scopes": ["stats:read"],
+            }))
+            .await;
+        response.assert_status_ok();
+        let token = response.json::<serde_json::Value>()["token"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let (name, value) = auth(&token)?;
+        let response = server.get("/api/stats").add_header(name, value).await;
+        response.assert_status_ok();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_set_status_and_planned_absences() -> Result<()> {
+        let (server, state) = base().await;
+
+        let stats =
+            ApiToken::create(1, "stats", &[Scope::StatsRead], None, None, &state.pool).await?;
+        let status =
+            ApiToken::create(1, "status", &[Scope::StatusWrite], None, None, &state.pool).await?;
+
+        let (name, value) = auth(&stats.token)?;
+        let response = server
+            .put("/api/status")
+            .add_header(name, value)
+            .json(&serde_json::json!({ "message": "on vacation" }))
+            .await;
+        response.assert_status(StatusCode::FORBIDDEN);
+
+        let (name, value) = auth(&status.token)?;
+        let response = server
+            .put("/api/status")
+            .add_header(name.clone(), value.clone())
+            .json(&serde_json::json!({ "message": "on vacation" }))
+            .await;
+        response.assert_status(StatusCode::NO_CONTENT);
+
+        let now = Utc::now().naive_utc();
+        let response = server
+            .post("/api/planned")
+            .add_header(name, value)
+            .json(&serde_json::json!({
+                "starts_at": now - TimeDelta::days(1),
+                "ends_at": now + TimeDelta::days(6),
+                "note": "beach",
+                "exclude_from_record": true,
+            }))
+            .await;
+        response.assert_status_ok();
+
+        let (name, value) = auth(&stats.token)?;
+        let response = server.get("/api/stats").add_header(name, value).await;
+        response.assert_status_ok();
+        let stats = response.json::<serde_json::Value>();
+        assert_eq!("on vacation", stats["status"]);
+        assert_eq!("beach", stats["planned_absences"][0]["note"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn works_with_the_client() -> Result<()> {
+        let state = init_state().await;
+        let device = Device::register(1, "laptop", &state.pool).await?;
+        let token =
+            ApiToken::create(1, "stats", &[Scope::StatsRead], None, None, &state.pool).await?;
+
+        let app = Router::new()
+            .route("/api/beat", post(beat))
+            .route("/api/batch", post(crate::routes::batch::batch))
+            .route("/api/devices/me", get(crate::routes::batch::current_device))
+            .route("/api/stats", get(stats))
+            .with_state(state.clone());
+        let config = axum_test::TestServerConfig::builder()
+            .http_transport()
+            .build();
+        let server = TestServer::new_with_config(app, config)?;
+        let url = server.server_address().unwrap();
+
+        let client = heartbeat_client::Client::new(url.as_str(), &device.token);
+        let now = Utc::now().naive_utc();
+        let receipt = client
+            .batch(&[
+                (now - TimeDelta::hours(3)).into(),
+                (now - TimeDelta::hours(2)).into(),
+            ])
+            .await?;
+        assert_eq!(2, receipt.stored);
+        assert_eq!(Some(now - TimeDelta::hours(2)), receipt.last_beat);
+        let beat = client.beat(&Default::default()).await?;
+        assert_eq!(
+            Some(beat.timestamp()),
+            client
+                .device()
+                .await?
+                .last_beat
+                .map(|last_beat| last_beat.and_utc().timestamp())
+        );
+
+        // device tokens can't read stats
+        assert!(client.stats().await.is_err());
+        let stats = heartbeat_client::Client::new(url.as_str(), &token.token)
+            .stats()
+            .await?;
+        assert!(stats.active);
+        assert_eq!(3, stats.total_beats);
+        assert_eq!("laptop", stats.devices[0].name);
+        // three beats aren't enough to know what's usual for the laptop
+        assert_eq!(
+            Some(HealthStatus::Learning),
+            stats.devices[0].health.as_ref().map(|health| health.status)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn filters_stats_by_metadata() -> Result<()> {
+        let (server, state) = base().await;
+        let stats =
+            ApiToken::create(1, "stats", &[Scope::StatsRead], None, None, &state.pool).await?;
+
+        for source in ["screen-unlock", "screen-unlock", "keyboard"] {
+            let (name, value) = auth("my_token")?;
+            server
+                .post("/api/beat")
+                .add_header(name, value)
+                .add_query_param("source", source)
+                .await
+                .assert_status_ok();
+        }
+
+        let (name, value) = auth(&stats.token)?;
+        let response = server
+            .get("/api/stats")
+            .add_header(name.clone(), value.clone())
+            .add_query_param("source", "screen-unlock")
+            .await;
+        let filtered = response.json::<Stats>();
+        assert_eq!(2, filtered.total_beats);
+        assert_eq!(2, filtered.devices[0].beat_count);
+
+        let response = server
+            .get("/api/stats")
+            .add_header(name.clone(), value.clone())
+            .add_query_param("source", "manual")
+            .await;
+        let filtered = response.json::<Stats>();
+        assert_eq!(0, filtered.total_beats);
+        assert!(!filtered.active);
+        assert_eq!(None, filtered.last_beat);
+
+        let response = server.get("/api/stats").add_header(name, value).await;
+        assert_eq!(3, response.json::<Stats>().total_beats);
+
+        Ok(())
+    }
+}
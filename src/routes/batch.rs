@@ -1,117 +1,197 @@
-use std::sync::{atomic::Ordering, Arc};
+use std::{
+    collections::HashSet,
+    sync::{Arc, LazyLock},
+};
 
 use anyhow::{anyhow, Result};
-use axum::{extract::State, Json};
-use chrono::NaiveDateTime;
-
-use crate::{absence::Absence, beat::Beat, device::Device, errors::AppError, AppState};
-
-#[derive(serde::Deserialize, serde::Serialize)]
-pub struct BeatBatch {
-    timestamps: Vec<NaiveDateTime>,
-}
+use axum::{
+    extract::State,
+    http::{header::ACCEPT, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use heartbeat_client::api::{BatchReceipt, BeatBatch, DeviceInfo, MAX_BATCH_SIZE};
+
+use crate::{
+    audit::AuditEvent,
+    beat::Beat,
+    clock::{self, DeviceClock},
+    device::Device,
+    errors::AppError,
+    ingest,
+    proxy::ClientIp,
+    routes::beat::check_meta,
+    AppState,
+};
+
+static TOO_LARGE: LazyLock<String> = LazyLock::new(|| {
+    format!("batches can have at most {MAX_BATCH_SIZE} timestamps, send the rest in another one")
+});
 
 pub async fn batch(
     State(state): State<Arc<AppState>>,
     device: Device,
-    Json(BeatBatch { timestamps }): Json<BeatBatch>,
-) -> Result<String, AppError> {
-    if timestamps.is_empty() {
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    Json(BeatBatch {
+        timestamps: mut beats,
+    }): Json<BeatBatch>,
+) -> Result<Response, AppError> {
+    if beats.is_empty() {
         return Err(anyhow!("no timestamps provided").into());
     }
+    if beats.len() > MAX_BATCH_SIZE {
+        return Err(AppError::Rejection(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &TOO_LARGE,
+        ));
+    }
 
-    let mut tx = state.pool.begin().await?;
-
-    let ids = Beat::create_many(device.id, &timestamps, &mut *tx).await?;
-    device
-        .increase_beat_count(ids.len() as i64, &mut *tx)
-        .await?;
-
-    let first_timestamp = timestamps.iter().min().unwrap();
-
-    let beats = Beat::get_all_before(first_timestamp, &mut *tx).await?;
-    let mut absences = Absence::get_all_before(first_timestamp, &mut *tx).await?;
-
-    let mut idx = 0;
-    'out: while idx < absences.len() {
-        for timestamp in &timestamps {
-            let absence = &absences[idx];
-            if absence.contains(&timestamp.and_utc()) {
-                absence.delete(&mut *tx).await?;
-                absences.remove(idx);
+    for beat in &beats {
+        check_meta(&beat.meta)?;
+    }
 
-                // TODO what do we do with longest_absence here if this absence was the longest?
-                // we dont have the previous longest
+    let mut tx = state.pool.begin().await?;
 
-                continue 'out;
-            }
+    // timestamps come from the device's clock, so they're moved to the server's
+    let skew = match clock::request_date(&headers) {
+        Some(device_now) => {
+            let skew = DeviceClock::measure(device.id, device_now, &mut tx).await?;
+            DeviceClock::store(device.id, skew, &mut tx).await?;
+            skew
         }
-
-        idx += 1;
+        None => DeviceClock::skew_of(device.id, &mut tx).await?,
+    };
+    for beat in &mut beats {
+        beat.timestamp = clock::correct(beat.timestamp, skew);
     }
 
-    for window in beats.windows(2) {
-        let [last_beat, beat] = window else {
-            continue;
+    let received = beats.len();
+    beats.sort_by_key(|beat| beat.timestamp);
+    beats.dedup_by_key(|beat| beat.timestamp);
+
+    // a client that didn't get the answer to a batch sends it again, so beats it already sent
+    // are left out instead of being stored twice
+    let first_timestamp = beats.first().unwrap().timestamp;
+    let last_timestamp = beats.last().unwrap().timestamp;
+    let existing = Beat::timestamps_between(device.id, &first_timestamp, &last_timestamp, &mut *tx)
+        .await?
+        .into_iter()
+        .collect::<HashSet<_>>();
+    beats.retain(|beat| !existing.contains(&beat.timestamp));
+    let duplicates = received - beats.len();
+    let timestamps = beats.iter().map(|beat| beat.timestamp).collect::<Vec<_>>();
+
+    let Some(first_timestamp) = timestamps.first() else {
+        let last_beat = Beat::last_beat_of(device.id, &mut *tx).await?;
+        // the skew is still kept
+        tx.commit().await?;
+        let receipt = BatchReceipt {
+            stored: 0,
+            duplicates,
+            last_beat: last_beat.map(|beat| beat.timestamp),
         };
+        return Ok(answer(&headers, receipt));
+    };
 
-        // if there's already an absence between these two beats, skip
-        if absences
-            .iter()
-            .any(|abs| abs.begin_beat == last_beat.id && abs.end_beat == beat.id)
-        {
-            continue;
-        }
-
-        let diff = beat.timestamp.and_utc() - last_beat.timestamp.and_utc();
-
-        // update longest absence in state
-        state
-            .longest_absence
-            .fetch_max(diff.num_seconds(), Ordering::Relaxed);
-
-        // if the absence was longer than 1h, log it
-        if diff.num_hours() >= 1 {
-            Absence {
-                id: 0,
-                timestamp: beat.timestamp,
-                duration: diff.num_seconds(),
-                begin_beat: last_beat.id,
-                end_beat: beat.id,
-            }
-            .create(&mut *tx)
-            .await?;
-        }
+    let ids = Beat::create_many(device.id, &beats, &mut tx).await?;
+    AuditEvent::BatchUploaded {
+        device: device.id,
+        count: timestamps.len(),
+        from: *first_timestamp,
+        to: *timestamps.last().unwrap(),
     }
+    .record(Some(device.user), ip, &mut *tx)
+    .await?;
+
+    let changes = ingest::recompute_absences(device.user, &timestamps, ip, &mut tx).await?;
 
+    let last_beat = Beat::last_beat_of(device.id, &mut *tx).await?;
+    let write = state.home_snapshots.write(device.user);
     tx.commit().await?;
 
-    Ok(ids.len().to_string())
+    let new_beats = ids
+        .iter()
+        .zip(&timestamps)
+        .map(|(&id, &timestamp)| Beat {
+            id,
+            device: device.id,
+            timestamp,
+        })
+        .collect::<Vec<_>>();
+    write.record(&new_beats);
+    // the longest absence only changes once the beats are stored
+    changes.record(device.user, &state).await?;
+
+    let receipt = BatchReceipt {
+        stored: ids.len(),
+        duplicates,
+        last_beat: last_beat.map(|beat| beat.timestamp),
+    };
+    Ok(answer(&headers, receipt))
+}
+
+/// Answers with the receipt to clients that ask for json, and with the number of stored beats
+/// like before to the others
+fn answer(headers: &HeaderMap, receipt: BatchReceipt) -> Response {
+    let wants_json = headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|accept| accept.to_str().ok())
+        .any(|accept| accept.contains("application/json"));
+
+    if wants_json {
+        Json(receipt).into_response()
+    } else {
+        receipt.stored.to_string().into_response()
+    }
+}
+
+/// The device of the token, with its latest beat so clients know where to resume from
+pub async fn current_device(
+    State(state): State<Arc<AppState>>,
+    device: Device,
+) -> Result<Json<DeviceInfo>, AppError> {
+    let mut conn = state.pool.acquire().await?;
+    let last_beat = Beat::last_beat_of(device.id, &mut *conn).await?;
+    let clock_skew = DeviceClock::skew_of(device.id, &mut conn).await?;
+
+    Ok(Json(DeviceInfo {
+        id: device.id,
+        name: device.name,
+        last_beat: last_beat.map(|beat| beat.timestamp),
+        clock_skew,
+    }))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{beat::Beat, device::Device, testing::init_state};
+    use crate::{
+        absence::Absence, audit::AuditEntry, beat::Beat, device::Device, retention::Retention,
+        testing::init_state,
+    };
 
     use super::*;
     use ::axum_test::TestServer;
     use anyhow::Result;
     use axum::{
         http::{HeaderName, HeaderValue},
-        routing::post,
+        routing::{get, post},
         Router,
     };
     use axum_test::TestResponse;
-    use chrono::TimeDelta;
+    use chrono::{DurationRound, NaiveDateTime, TimeDelta, Utc};
 
     async fn base() -> (TestServer, Arc<AppState>) {
         let state = init_state().await;
 
         Device {
             id: 1,
+            user: 1,
             name: "test device".to_string(),
             token: "my_token".to_string(),
             beat_count: 0,
+            visible: true,
         }
         .create(&state.pool)
         .await
@@ -119,6 +199,7 @@ mod tests {
 
         let app = Router::new()
             .route("/api/batch", post(batch))
+            .route("/api/devices/me", get(current_device))
             .with_state(state.clone());
         let server = TestServer::new(app).unwrap();
 
@@ -132,7 +213,10 @@ mod tests {
                 HeaderName::from_bytes(b"Authorization")?,
                 HeaderValue::from_str("my_token")?,
             )
-            .json(&BeatBatch { timestamps })
+            .add_header(ACCEPT, HeaderValue::from_static("application/json"))
+            .json(&BeatBatch {
+                timestamps: timestamps.into_iter().map(Into::into).collect(),
+            })
             .await;
         Ok(response)
     }
@@ -152,7 +236,29 @@ mod tests {
         .await?;
 
         response.assert_status_ok();
-        assert_eq!(3, Beat::count(&state.pool).await?);
+        assert_eq!(3, Beat::count(1, &state.pool).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn answers_with_the_count_without_accept() -> Result<()> {
+        let (server, _) = base().await;
+
+        let now = Utc::now().naive_utc();
+        let response = server
+            .post("/api/batch")
+            .add_header(
+                HeaderName::from_bytes(b"Authorization")?,
+                HeaderValue::from_str("my_token")?,
+            )
+            .json(&BeatBatch {
+                timestamps: vec![(now - TimeDelta::minutes(1)).into(), now.into()],
+            })
+            .await;
+
+        response.assert_status_ok();
+        assert_eq!("2", response.text());
 
         Ok(())
     }
@@ -172,7 +278,7 @@ mod tests {
         .await?;
 
         response.assert_status_ok();
-        assert_eq!(2, Absence::count(&state.pool).await?);
+        assert_eq!(2, Absence::count(1, &state.pool).await?);
 
         Ok(())
     }
@@ -216,7 +322,7 @@ mod tests {
 
         response.assert_status_ok();
         // there should be an absence between 10 and 9, 9 and 5, 5 and 3
-        assert_eq!(3, Absence::count(&state.pool).await?);
+        assert_eq!(3, Absence::count(1, &state.pool).await?);
 
         Ok(())
     }
@@ -248,6 +354,7 @@ mod tests {
         }
         .create(&state.pool)
         .await?;
+        state.longest_absences.update(1, 5000);
 
         let response = request(
             &server,
@@ -256,7 +363,9 @@ mod tests {
         .await?;
 
         response.assert_status_ok();
-        assert_eq!(0, Absence::count(&state.pool).await?);
+        assert_eq!(0, Absence::count(1, &state.pool).await?);
+        // the absence is gone, so it isn't the longest anymore
+        assert_eq!(0, state.longest_absences.get(1));
 
         Ok(())
     }
@@ -296,7 +405,222 @@ mod tests {
         .await?;
 
         response.assert_status_ok();
-        assert_eq!(1, Absence::count(&state.pool).await?);
+        assert_eq!(1, Absence::count(1, &state.pool).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn doesnt_create_absences_between_compacted_beats() -> Result<()> {
+        let (server, state) = base().await;
+
+        // a beat every minute for two hours, ten days ago, compacted into one per hour
+        let start = (Utc::now() - TimeDelta::days(10)).duration_trunc(TimeDelta::hours(1))?;
+        let timestamps = (0..120)
+            .map(|minute| (start + TimeDelta::minutes(minute)).naive_utc())
+            .collect();
+        request(&server, timestamps).await?.assert_status_ok();
+        Retention {
+            keep_days: 7,
+            interval: 60 * 60,
+            dry_run: false,
+        }
+        .run(&state.pool)
+        .await?;
+
+        let response = request(
+            &server,
+            vec![(start + TimeDelta::seconds(30 * 60 + 30)).naive_utc()],
+        )
+        .await?;
+
+        response.assert_status_ok();
+        assert_eq!(121, Beat::count(1, &state.pool).await?);
+        assert_eq!(0, Absence::count(1, &state.pool).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn records_uploads_in_the_audit_log() -> Result<()> {
+        let (server, state) = base().await;
+
+        let end = Utc::now().naive_utc();
+        let start = end - TimeDelta::seconds(5000);
+        Beat {
+            id: 0,
+            device: 1,
+            timestamp: start,
+        }
+        .create(&state.pool)
+        .await?;
+        Beat {
+            id: 0,
+            device: 1,
+            timestamp: end,
+        }
+        .create(&state.pool)
+        .await?;
+        Absence {
+            id: 0,
+            timestamp: end,
+            duration: 5000,
+            begin_beat: 1,
+            end_beat: 2,
+        }
+        .create(&state.pool)
+        .await?;
+
+        let from = end - TimeDelta::seconds(3000);
+        let to = end - TimeDelta::seconds(2000);
+        request(&server, vec![to, from]).await?.assert_status_ok();
+
+        let entries = AuditEntry::get_recent(1, 10, &state.pool).await?;
+        let events = entries
+            .into_iter()
+            .map(|entry| entry.event)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                AuditEvent::AbsenceDeleted {
+                    absence: 1,
+                    start,
+                    end
+                },
+                AuditEvent::BatchUploaded {
+                    device: 1,
+                    count: 2,
+                    from,
+                    to
+                },
+            ],
+            events
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn records_failed_authentication() -> Result<()> {
+        let (server, state) = base().await;
+
+        server
+            .post("/api/batch")
+            .add_header(
+                HeaderName::from_bytes(b"Authorization")?,
+                HeaderValue::from_str("not_my_token")?,
+            )
+            .json(&BeatBatch { timestamps: vec![] })
+            .await
+            .assert_status_unauthorized();
+
+        let reason = sqlx::query_scalar!("select details from audit_log where user is null")
+            .fetch_one(&state.pool)
+            .await?;
+        assert_eq!(
+            AuditEvent::DeviceAuthFailed {
+                reason: "no device found with this token".to_string()
+            },
+            serde_json::from_str(&reason)?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limits_batch_size() -> Result<()> {
+        let (server, state) = base().await;
+
+        let now = Utc::now().naive_utc();
+        let timestamps = (0..=MAX_BATCH_SIZE as i64)
+            .map(|minutes| now - TimeDelta::minutes(minutes))
+            .collect::<Vec<_>>();
+        request(&server, timestamps)
+            .await?
+            .assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(0, Beat::count(1, &state.pool).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skips_beats_that_were_already_sent() -> Result<()> {
+        let (server, state) = base().await;
+
+        let now = Utc::now().naive_utc();
+        let first = now - TimeDelta::minutes(2);
+        let second = now - TimeDelta::minutes(1);
+
+        let response = request(&server, vec![second, first, first]).await?;
+        let receipt = response.json::<BatchReceipt>();
+        assert_eq!(2, receipt.stored);
+        assert_eq!(1, receipt.duplicates);
+        assert_eq!(Some(second), receipt.last_beat);
+
+        // the answer got lost, so the client sends the chunk again with a new beat
+        let response = request(&server, vec![first, second, now]).await?;
+        let receipt = response.json::<BatchReceipt>();
+        assert_eq!(1, receipt.stored);
+        assert_eq!(2, receipt.duplicates);
+        assert_eq!(Some(now), receipt.last_beat);
+
+        let response = request(&server, vec![first]).await?;
+        assert_eq!(0, response.json::<BatchReceipt>().stored);
+        assert_eq!(3, Beat::count(1, &state.pool).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrects_clock_skew() -> Result<()> {
+        let (server, state) = base().await;
+
+        // the device's clock is an hour ahead
+        let device_now = Utc::now() + TimeDelta::hours(1);
+        let beat = (device_now - TimeDelta::minutes(5)).naive_utc();
+        let response = server
+            .post("/api/batch")
+            .add_header(
+                HeaderName::from_bytes(b"Authorization")?,
+                HeaderValue::from_str("my_token")?,
+            )
+            .add_header(
+                HeaderName::from_bytes(b"Date")?,
+                HeaderValue::from_str(&device_now.format("%a, %d %b %Y %H:%M:%S GMT").to_string())?,
+            )
+            .json(&BeatBatch {
+                timestamps: vec![beat.into()],
+            })
+            .await;
+        response.assert_status_ok();
+
+        let info = server
+            .get("/api/devices/me")
+            .add_header(
+                HeaderName::from_bytes(b"Authorization")?,
+                HeaderValue::from_str("my_token")?,
+            )
+            .await
+            .json::<DeviceInfo>();
+        // the date header only has seconds
+        assert!((3599..=3600).contains(&info.clock_skew));
+        assert_eq!(
+            Some(beat - TimeDelta::seconds(info.clock_skew)),
+            info.last_beat
+        );
+
+        // batches without a date header are corrected by the skew that was measured last
+        let later = beat + TimeDelta::minutes(1);
+        let receipt = request(&server, vec![beat, later])
+            .await?
+            .json::<BatchReceipt>();
+        assert_eq!(1, receipt.stored);
+        assert_eq!(1, receipt.duplicates);
+        assert_eq!(
+            Some(later - TimeDelta::seconds(info.clock_skew)),
+            receipt.last_beat
+        );
+        assert_eq!(2, Beat::count(1, &state.pool).await?);
 
         Ok(())
     }
@@ -1,57 +1,129 @@
-use std::sync::{atomic::Ordering, Arc};
+use std::sync::Arc;
 
 use anyhow::Result;
-use axum::extract::State;
-use chrono::Utc;
-
-use crate::{absence::Absence, beat::Beat, device::Device, errors::AppError, AppState};
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use heartbeat_client::api::BeatMeta;
+use serde::Deserialize;
+
+use crate::{
+    clock::{self, DeviceClock, SKEW_TOLERANCE},
+    device::Device,
+    errors::AppError,
+    ingest,
+    proxy::ClientIp,
+    AppState,
+};
+
+/// longest client version that's stored
+const MAX_CLIENT_VERSION_LEN: usize = 64;
+/// oldest a single beat can be, older ones have to be sent in a batch
+const MAX_BEAT_AGE: i64 = 5 * 60;
+
+/// When a beat happened according to the device's clock
+#[derive(Debug, Default, Deserialize)]
+pub struct BeatTime {
+    pub timestamp: Option<NaiveDateTime>,
+}
 
-pub async fn beat(State(state): State<Arc<AppState>>, device: Device) -> Result<String, AppError> {
-    let last_beat = Beat::last_beat(&state.pool).await?;
+/// Sends a beat. metadata can come as a json body, or as query parameters
+pub async fn beat(
+    State(state): State<Arc<AppState>>,
+    device: Device,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    Query(query): Query<BeatMeta>,
+    Query(time): Query<BeatTime>,
+    body: Bytes,
+) -> Result<String, AppError> {
+    let (meta, time) = if body.is_empty() {
+        (query, time)
+    } else {
+        let invalid = |_| {
+            AppError::Rejection(
+                StatusCode::BAD_REQUEST,
+                "the body isn't valid beat metadata",
+            )
+        };
+        (
+            serde_json::from_slice(&body).map_err(invalid)?,
+            serde_json::from_slice(&body).map_err(invalid)?,
+        )
+    };
+    check_meta(&meta)?;
 
     let mut tx = state.pool.begin().await?;
 
-    let now = Utc::now();
-    let beat = Beat {
-        id: 0, // id is ignored on create
-        device: device.id,
-        timestamp: now.naive_utc(),
-    }
-    .create(&mut *tx)
-    .await?;
+    // without a date header, the skew measured last is used
+    let measured = match clock::request_date(&headers) {
+        Some(device_now) => Some(DeviceClock::measure(device.id, device_now, &mut tx).await?),
+        None => None,
+    };
+    let skew = match measured {
+        Some(skew) => skew,
+        None => DeviceClock::skew_of(device.id, &mut tx).await?,
+    };
+    let now = match time.timestamp {
+        Some(timestamp) => clock::correct(timestamp, skew).and_utc(),
+        None => Utc::now(),
+    };
+    check_timestamp(now)?;
 
-    device.increase_beat_count(1, &mut *tx).await?;
+    if let Some(skew) = measured {
+        DeviceClock::store(device.id, skew, &mut tx).await?;
+    }
+    ingest::record_beat(&state, &device, now, &meta, ip, tx).await?;
 
-    tx.commit().await?;
+    Ok(now.timestamp().to_string())
+}
 
-    // update longest absence
-    if let Some(last_beat) = last_beat {
-        let diff = now - last_beat.timestamp.and_utc();
-        let duration = diff.num_seconds();
+/// Rejects timestamps of single beats that are in the future, or too old to not be in a batch
+pub fn check_timestamp(timestamp: DateTime<Utc>) -> Result<(), AppError> {
+    if (timestamp - Utc::now()).num_seconds() > SKEW_TOLERANCE {
+        return Err(AppError::Rejection(
+            StatusCode::BAD_REQUEST,
+            "the timestamp is in the future",
+        ));
+    }
+    if (Utc::now() - timestamp).num_seconds() > MAX_BEAT_AGE {
+        return Err(AppError::Rejection(
+            StatusCode::BAD_REQUEST,
+            "the timestamp is too old, send older beats in a batch",
+        ));
+    }
 
-        // update longest absence in state
-        state.longest_absence.fetch_max(duration, Ordering::Relaxed);
+    Ok(())
+}
 
-        // if the absence was longer than 1h, log it
-        if diff.num_hours() >= 1 {
-            Absence {
-                id: 0,
-                timestamp: now.naive_utc(),
-                duration,
-                begin_beat: last_beat.id,
-                end_beat: beat.id,
-            }
-            .create(&state.pool)
-            .await?;
-        }
+/// Rejects metadata that can't be right
+pub fn check_meta(meta: &BeatMeta) -> Result<(), AppError> {
+    if meta
+        .client_version
+        .as_ref()
+        .is_some_and(|version| version.len() > MAX_CLIENT_VERSION_LEN)
+    {
+        return Err(AppError::Rejection(
+            StatusCode::BAD_REQUEST,
+            "the client version can be at most 64 characters long",
+        ));
+    }
+    if meta.idle_seconds.is_some_and(|idle| idle < 0) {
+        return Err(AppError::Rejection(
+            StatusCode::BAD_REQUEST,
+            "idle seconds can't be negative",
+        ));
     }
 
-    Ok(now.timestamp().to_string())
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{beat::Beat, device::Device, testing::init_state};
+    use crate::{absence::Absence, beat::Beat, device::Device, testing::init_state};
 
     use super::*;
     use ::axum_test::TestServer;
@@ -68,9 +140,11 @@ mod tests {
 
         Device {
             id: 1,
+            user: 1,
             name: "test device".to_string(),
             token: "my_token".to_string(),
             beat_count: 0,
+            visible: true,
         }
         .create(&state.pool)
         .await
@@ -99,7 +173,7 @@ mod tests {
     async fn can_create_beats() -> Result<()> {
         let (server, state) = base().await;
 
-        assert_eq!(0, Beat::count(&state.pool).await?);
+        assert_eq!(0, Beat::count(1, &state.pool).await?);
         assert_eq!(
             0,
             Device::get_by_auth("my_token", &state.pool)
@@ -112,7 +186,7 @@ mod tests {
 
         response.assert_status_ok();
 
-        assert_eq!(1, Beat::count(&state.pool).await?);
+        assert_eq!(1, Beat::count(1, &state.pool).await?);
         assert_eq!(
             1,
             Device::get_by_auth("my_token", &state.pool)
@@ -120,7 +194,7 @@ mod tests {
                 .unwrap()
                 .beat_count
         );
-        assert_eq!(0, Absence::count(&state.pool).await?);
+        assert_eq!(0, Absence::count(1, &state.pool).await?);
 
         Ok(())
     }
@@ -149,13 +223,13 @@ mod tests {
         .create(&state.pool)
         .await?;
 
-        assert_eq!(0, Absence::count(&state.pool).await?);
+        assert_eq!(0, Absence::count(1, &state.pool).await?);
 
         let response = request(&server).await?;
 
         response.assert_status_ok();
 
-        assert_eq!(0, Absence::count(&state.pool).await?);
+        assert_eq!(0, Absence::count(1, &state.pool).await?);
 
         Ok(())
     }
@@ -174,14 +248,247 @@ mod tests {
         .create(&state.pool)
         .await?;
 
-        assert_eq!(0, Absence::count(&state.pool).await?);
+        assert_eq!(0, Absence::count(1, &state.pool).await?);
 
         let response = request(&server).await?;
 
         response.assert_status_ok();
 
-        assert_eq!(1, Absence::count(&state.pool).await?);
-        assert_eq!(86400, state.longest_absence.load(Ordering::Relaxed));
+        assert_eq!(1, Absence::count(1, &state.pool).await?);
+        assert_eq!(86400, state.longest_absences.get(1));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn absences_are_per_user() -> Result<()> {
+        let (server, state) = base().await;
+
+        sqlx::query!("insert into users (id, name, password_hash) values (2, 'bob', '')")
+            .execute(&state.pool)
+            .await?;
+        Device {
+            id: 2,
+            user: 2,
+            name: "bob's device".to_string(),
+            token: "bob_token".to_string(),
+            beat_count: 0,
+            visible: true,
+        }
+        .create(&state.pool)
+        .await?;
+
+        // bob was active recently, but that doesn't count for the first user
+        Beat {
+            id: 0,
+            device: 1,
+            timestamp: (Utc::now() + TimeDelta::days(-1)).naive_utc(),
+        }
+        .create(&state.pool)
+        .await?;
+        Beat {
+            id: 0,
+            device: 2,
+            timestamp: (Utc::now() + TimeDelta::minutes(-1)).naive_utc(),
+        }
+        .create(&state.pool)
+        .await?;
+
+        let response = request(&server).await?;
+        response.assert_status_ok();
+
+        assert_eq!(1, Absence::count(1, &state.pool).await?);
+        assert_eq!(0, Absence::count(2, &state.pool).await?);
+        assert_eq!(86400, state.longest_absences.get(1));
+        assert_eq!(0, state.longest_absences.get(2));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn older_beats_shorten_absences() -> Result<()> {
+        let (server, state) = base().await;
+
+        let now = Utc::now();
+        let mut ids = vec![];
+        for minutes in [120, 1] {
+            let beat = Beat {
+                id: 0,
+                device: 1,
+                timestamp: (now - TimeDelta::minutes(minutes)).naive_utc(),
+            }
+            .create(&state.pool)
+            .await?;
+            ids.push(beat.id);
+        }
+        Absence {
+            id: 0,
+            timestamp: (now - TimeDelta::minutes(1)).naive_utc(),
+            duration: TimeDelta::minutes(119).num_seconds(),
+            begin_beat: ids[0],
+            end_beat: ids[1],
+        }
+        .create(&state.pool)
+        .await?;
+        state.longest_absences.update(1, 119 * 60);
+
+        // a beat sent a bit late ends the absence earlier
+        server
+            .post("/api/beat")
+            .add_header(
+                HeaderName::from_bytes(b"Authorization")?,
+                HeaderValue::from_str("my_token")?,
+            )
+            .add_query_param("timestamp", (now - TimeDelta::minutes(3)).naive_utc())
+            .await
+            .assert_status_ok();
+
+        assert_eq!(1, Absence::count(1, &state.pool).await?);
+        assert_eq!(117 * 60, state.longest_absences.get(1));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stores_metadata() -> Result<()> {
+        let (server, state) = base().await;
+        let (name, value) = (
+            HeaderName::from_bytes(b"Authorization")?,
+            HeaderValue::from_str("my_token")?,
+        );
+
+        server
+            .post("/api/beat")
+            .add_header(name.clone(), value.clone())
+            .json(&serde_json::json!({
+                "source": "screen-unlock",
+                "idle_seconds": 0,
+                "client_version": "tasker 1.0",
+            }))
+            .await
+            .assert_status_ok();
+        server
+            .post("/api/beat")
+            .add_header(name.clone(), value.clone())
+            .add_query_param("source", "keyboard")
+            .add_query_param("client_version", "tasker 1.0")
+            .await
+            .assert_status_ok();
+
+        let beats = sqlx::query!(
+            "select source, idle_seconds, version from beats
+            left join client_versions on client_versions.id = beats.client_version
+            order by beats.id"
+        )
+        .fetch_all(&state.pool)
+        .await?;
+        assert_eq!(2, beats.len());
+        assert_eq!(Some(3), beats[0].source);
+        assert_eq!(Some(0), beats[0].idle_seconds);
+        assert_eq!(Some(1), beats[1].source);
+        assert_eq!(None, beats[1].idle_seconds);
+        assert!(beats
+            .iter()
+            .all(|beat| beat.version.as_deref() == Some("tasker 1.0")));
+
+        server
+            .post("/api/beat")
+            .add_header(name.clone(), value.clone())
+            .add_query_param("source", "telepathy")
+            .await
+            .assert_status_bad_request();
+        server
+            .post("/api/beat")
+            .add_header(name, value)
+            .json(&serde_json::json!({ "idle_seconds": -1 }))
+            .await
+            .assert_status_bad_request();
+        assert_eq!(2, Beat::count(1, &state.pool).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn takes_timestamps_from_the_device() -> Result<()> {
+        let (server, state) = base().await;
+        let (name, value) = (
+            HeaderName::from_bytes(b"Authorization")?,
+            HeaderValue::from_str("my_token")?,
+        );
+
+        // without a date header, the timestamp is stored as is, and the clock isn't measured
+        let timestamp = (Utc::now() - TimeDelta::minutes(2)).naive_utc();
+        server
+            .post("/api/beat")
+            .add_header(name.clone(), value.clone())
+            .add_query_param("timestamp", timestamp)
+            .await
+            .assert_status_ok();
+        let beat = Beat::last_beat(1, &state.pool).await?.unwrap();
+        assert_eq!(timestamp, beat.timestamp);
+        assert!(DeviceClock::get(1, &mut *state.pool.acquire().await?)
+            .await?
+            .is_none());
+
+        // the device's clock is 10 minutes behind, and the beat happened a minute before sending
+        let device_now = Utc::now() - TimeDelta::minutes(10);
+        let timestamp = (device_now - TimeDelta::minutes(1)).naive_utc();
+        server
+            .post("/api/beat")
+            .add_header(name.clone(), value.clone())
+            .add_header(
+                HeaderName::from_bytes(b"Date")?,
+                HeaderValue::from_str(&device_now.format("%a, %d %b %Y %H:%M:%S GMT").to_string())?,
+            )
+            .json(&serde_json::json!({ "timestamp": timestamp }))
+            .await
+            .assert_status_ok();
+
+        let skew = DeviceClock::skew_of(1, &mut *state.pool.acquire().await?).await?;
+        // the date header only has seconds
+        assert!((-601..=-600).contains(&skew));
+        let beat = Beat::last_beat(1, &state.pool).await?.unwrap();
+        assert_eq!(timestamp - TimeDelta::seconds(skew), beat.timestamp);
+
+        // without a date header, the skew measured last is used and kept
+        let timestamp = (Utc::now() - TimeDelta::seconds(10 * 60 + 30)).naive_utc();
+        server
+            .post("/api/beat")
+            .add_header(name.clone(), value.clone())
+            .add_query_param("timestamp", timestamp)
+            .await
+            .assert_status_ok();
+        let beat = Beat::last_beat(1, &state.pool).await?.unwrap();
+        assert_eq!(timestamp - TimeDelta::seconds(skew), beat.timestamp);
+        assert_eq!(
+            skew,
+            DeviceClock::skew_of(1, &mut *state.pool.acquire().await?).await?
+        );
+
+        server
+            .post("/api/beat")
+            .add_header(name.clone(), value.clone())
+            .add_header(
+                HeaderName::from_bytes(b"Date")?,
+                HeaderValue::from_str(&Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string())?,
+            )
+            .json(
+                &serde_json::json!({ "timestamp": (Utc::now() + TimeDelta::hours(1)).naive_utc() }),
+            )
+            .await
+            .assert_status_bad_request();
+        // a rejected beat doesn't change the skew
+        assert_eq!(
+            skew,
+            DeviceClock::skew_of(1, &mut *state.pool.acquire().await?).await?
+        );
+        server
+            .post("/api/beat")
+            .add_header(name, value)
+            .json(&serde_json::json!({ "timestamp": "yesterday" }))
+            .await
+            .assert_status_bad_request();
+        assert_eq!(3, Beat::count(1, &state.pool).await?);
 
         Ok(())
     }
@@ -0,0 +1,303 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Result;
+use axum::{
+    extract::{Path, Query, State},
+    response::Html,
+};
+use chrono::Utc;
+use maud::{html, PreEscaped};
+
+use crate::{
+    beat::Beat,
+    clock::{DeviceClock, LARGE_SKEW},
+    device::Device,
+    errors::AppError,
+    helpers::format_relative,
+    html::base_template,
+    routes::graph::{recent_beats, Window},
+    viewer::Visitor,
+    AppState,
+};
+
+/// width of a bar in the bar charts, in px
+const BAR: usize = 12;
+/// height of the bar charts, in px
+const HEIGHT: usize = 80;
+
+pub async fn device(
+    State(state): State<Arc<AppState>>,
+    visitor: Visitor,
+    Path(id): Path<i64>,
+    Query(q): Query<HashMap<String, String>>,
+) -> Result<Html<String>, AppError> {
+    let device = Device::get_by_id(id, &state.pool)
+        .await?
+        .ok_or_else(|| AppError::html_from_str("there is no such device :3"))?;
+    let viewer = visitor.viewer_of(device.user);
+    let device = viewer
+        .device(device)
+        .ok_or_else(|| AppError::html_from_str("there is no such device :3"))?;
+
+    let first_beat = Beat::first_beat_of(device.id, &state.pool).await?;
+    let last_beat = Beat::last_beat_of(device.id, &state.pool).await?;
+    // only the owner can do something about a wrong clock
+    let clock = match viewer.is_private() {
+        true => DeviceClock::get(device.id, &mut *state.pool.acquire().await?)
+            .await?
+            .filter(|clock| clock.skew.abs() >= LARGE_SKEW),
+        false => None,
+    };
+
+    let shares = Beat::device_share_per_week(device.id, viewer.is_private(), &state.pool)
+        .await?
+        .into_iter()
+        .map(|week| {
+            let share = week.device as f64 / week.total as f64;
+            let title = format!(
+                "week of {}: {:.0}% ({} of {} beats)",
+                week.week.format("%Y/%m/%d"),
+                100.0 * share,
+                week.device,
+                week.total
+            );
+            (share, title)
+        })
+        .collect::<Vec<_>>();
+
+    let mut hours = [0; 24];
+    for c in Beat::device_count_per_hour(device.id, &state.pool).await? {
+        hours[c.hour as usize] = c.count;
+    }
+    let max_hour = hours.iter().copied().max().unwrap_or_default().max(1);
+    let hours = hours
+        .iter()
+        .enumerate()
+        .map(|(hour, count)| {
+            (
+                *count as f64 / max_hour as f64,
+                format!("{hour}:00 UTC: {count} beats"),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let now = Utc::now();
+    let window = Window::from_query(&q);
+
+    let content = html! {
+        h1 { (device.name) }
+        @if let Some(clock) = &clock {
+            p.warning {
+                "the clock of this device is "
+                strong { (format_relative(clock.skew.abs()).trim_end()) " " (if clock.skew > 0 { "ahead" } else { "behind" }) }
+                ", its beats are corrected by that. measured "
+                (clock.measured_at.format("%Y/%m/%d %H:%M UTC").to_string())
+            }
+        }
+        ul {
+            li {
+                "total beats: "
+                    strong { (device.beat_count) }
+            }
+            @if let Some(first_beat) = &first_beat {
+                li {
+                    "first beat: "
+                        strong {
+                            (viewer.coarsen(first_beat.timestamp.and_utc()).format("%Y/%m/%d %H:%M UTC").to_string())
+                        }
+                }
+            }
+            @if let Some(last_beat) = &last_beat {
+                li {
+                    "last beat: "
+                        strong {
+                            (viewer.coarsen(last_beat.timestamp.and_utc()).format("%Y/%m/%d %H:%M UTC").to_string())
+                        }
+                }
+                li {
+                    "time since last beat: "
+                        strong {
+                            (format_relative(viewer.coarsen_secs((now - last_beat.timestamp.and_utc()).num_seconds())))
+                        }
+                }
+            }
+        }
+
+        h4 { "recent beats" }
+        (recent_beats(&state, device.user, window, std::slice::from_ref(&device), viewer, "").await?)
+
+        h4 { "share of all beats per week" }
+        (bars(&shares))
+
+        h4 { "typical hours of use" }
+        (bars(&hours))
+    };
+    let content = base_template(content);
+
+    Ok(Html(content.0))
+}
+
+/// Draws a bar chart. values go from 0 to 1
+fn bars(values: &[(f64, String)]) -> PreEscaped<String> {
+    html! {
+        svg.bars width=(values.len() * BAR) height=(HEIGHT) {
+            @for (i, (value, title)) in values.iter().enumerate() {
+                @let height = (value * HEIGHT as f64).round() as usize;
+                rect x=(i * BAR) y=(HEIGHT - height) width=(BAR - 2) height=(height) {
+                    title { (title) }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::init_state;
+
+    use super::*;
+    use ::axum_test::TestServer;
+    use assertables::*;
+    use axum::{
+        http::{HeaderName, HeaderValue},
+        routing::get,
+        Router,
+    };
+    use chrono::TimeDelta;
+
+    async fn base() -> (TestServer, Arc<AppState>) {
+        let state = init_state().await;
+
+        for (id, name, visible) in [(1, "laptop", true), (2, "phone", false)] {
+            Device {
+                id,
+                user: 1,
+                name: name.to_string(),
+                token: format!("{name}_token"),
+                beat_count: 0,
+                visible,
+            }
+            .create(&state.pool)
+            .await
+            .unwrap();
+        }
+
+        let app = Router::new()
+            .route("/device/:id", get(device))
+            .with_state(state.clone());
+        let server = TestServer::new(app).unwrap();
+
+        (server, state)
+    }
+
+    #[tokio::test]
+    async fn doesnt_panic_with_unknown_device() -> Result<()> {
+        let (server, _state) = base().await;
+
+        let response = server.get("/device/10").await;
+
+        response.assert_status_ok();
+        assert_contains!(response.text(), "there is no such device");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn doesnt_panic_with_no_beats() -> Result<()> {
+        let (server, _state) = base().await;
+
+        let response = server.get("/device/1").await;
+
+        response.assert_status_ok();
+        assert_contains!(response.text(), "<h1>device 1</h1>");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hides_hidden_devices_from_public() -> Result<()> {
+        let (server, _state) = base().await;
+
+        let response = server.get("/device/2").await;
+        response.assert_status_ok();
+        assert_contains!(response.text(), "there is no such device");
+
+        let response = server
+            .get("/device/2")
+            .add_header(
+                HeaderName::from_bytes(b"Authorization")?,
+                HeaderValue::from_str("laptop_token")?,
+            )
+            .await;
+        response.assert_status_ok();
+        assert_contains!(response.text(), "<h1>phone</h1>");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shows_share_and_hours() -> Result<()> {
+        let (server, state) = base().await;
+
+        let time = Utc::now() - TimeDelta::minutes(5);
+        for device in [1, 2, 2, 2] {
+            Beat {
+                id: 0,
+                device,
+                timestamp: time.naive_utc(),
+            }
+            .create(&state.pool)
+            .await?;
+        }
+
+        let response = server.get("/device/1").await;
+
+        response.assert_status_ok();
+        // visitors don't see the beats of the hidden phone
+        assert_contains!(response.text(), "100% (1 of 1 beats)");
+        assert_contains!(
+            response.text(),
+            &format!("{}:00 UTC: 1 beats", time.format("%-H"))
+        );
+
+        let response = server
+            .get("/device/1")
+            .add_header(
+                HeaderName::from_bytes(b"Authorization")?,
+                HeaderValue::from_str("laptop_token")?,
+            )
+            .await;
+        assert_contains!(response.text(), "25% (1 of 4 beats)");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn warns_about_a_wrong_clock() -> Result<()> {
+        let (server, state) = base().await;
+        let auth = (
+            HeaderName::from_bytes(b"Authorization")?,
+            HeaderValue::from_str("laptop_token")?,
+        );
+
+        let mut conn = state.pool.acquire().await?;
+        let skew = DeviceClock::measure(1, Utc::now() + TimeDelta::seconds(30), &mut conn).await?;
+        DeviceClock::store(1, skew, &mut conn).await?;
+        let response = server
+            .get("/device/1")
+            .add_header(auth.0.clone(), auth.1.clone())
+            .await;
+        assert_not_contains!(response.text(), "the clock of this device");
+
+        let skew = DeviceClock::measure(1, Utc::now() - TimeDelta::minutes(3), &mut conn).await?;
+        DeviceClock::store(1, skew, &mut conn).await?;
+        let response = server.get("/device/1").add_header(auth.0, auth.1).await;
+        assert_contains!(response.text(), "<strong>3m behind</strong>");
+
+        // visitors don't need to know
+        let response = server.get("/device/1").await;
+        assert_not_contains!(response.text(), "the clock of this device");
+
+        Ok(())
+    }
+}
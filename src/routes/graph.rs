@@ -1,46 +1,101 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use anyhow::Result;
-use axum::{extract::State, response::Html};
-use chrono::{DateTime, Days, Timelike, Utc};
+use axum::{
+    extract::{Query, State},
+    response::Html,
+};
+use chrono::{DateTime, Days, NaiveDate, TimeDelta, Timelike, Utc};
 use maud::{html, PreEscaped};
 
 use crate::{
-    absence::LongAbsences,
-    beat::Beat,
+    absence::{AbsenceFilter, LongAbsences},
+    beat::{Beat, BeatBucket},
     device::Device,
     errors::AppError,
-    helpers::{date_matches, RangeDays},
+    helpers::date_matches,
     html::base_template,
+    presence::PlannedAbsence,
+    viewer::{Page, Viewer},
     AppState,
 };
 
-pub async fn graph(State(state): State<Arc<AppState>>) -> Result<Html<String>, AppError> {
+pub async fn graph(
+    State(state): State<Arc<AppState>>,
+    Page { owner, viewer }: Page,
+    Query(q): Query<HashMap<String, String>>,
+) -> Result<Html<String>, AppError> {
+    let date = |key: &str| {
+        q.get(key)
+            .and_then(|s| NaiveDate::parse_from_str(s, "%Y-%m-%d").ok())
+            .and_then(|d| d.and_hms_opt(0, 0, 0))
+            .map(|d| d.and_utc())
+    };
+
+    let mut filter = AbsenceFilter::default();
+    // links to other windows keep the filter
+    let mut filter_query = String::new();
+    if let Some(from) = date("from") {
+        filter.from = from;
+        filter_query += &format!("&from={}", from.format("%Y-%m-%d"));
+    }
+    if let Some(to) = date("to") {
+        filter_query += &format!("&to={}", to.format("%Y-%m-%d"));
+        // include the whole day, unless it's the last one there is
+        filter.to = to
+            .checked_add_days(Days::new(1))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+    }
+    if let Some(min) = q.get("min").and_then(|s| s.parse::<u32>().ok()) {
+        filter.min_duration = min as i64;
+        filter_query += &format!("&min={min}");
+    }
+
+    let window = Window::from_query(&q);
+    let devices = viewer.devices(Device::get_all(owner.id, &state.pool).await?);
+
     let content = html! {
         h1 { "recent beats" }
-        (recent_beats(&state).await?)
+        (recent_beats(&state, owner.id, window, &devices, viewer, &filter_query).await?)
 
         h1 { "absences" }
-        (absences_graph(&state).await?)
+        form method="get" {
+            input type="hidden" name="window" value=(window.name());
+            label { "from " input type="date" name="from" value=[q.get("from")]; }
+            " "
+            label { "to " input type="date" name="to" value=[q.get("to")]; }
+            " "
+            label title="only absences of an hour or more are stored, so less shows all of them" {
+                "min seconds " input type="number" name="min" min="0" value=[q.get("min")];
+            }
+            " "
+            input type="submit" value="filter";
+        }
+        (absences_graph(&state, owner.id, &filter, viewer).await?)
     };
     let content = base_template(content);
 
     Ok(Html(content.0))
 }
 
-async fn absences_graph(state: &AppState) -> Result<PreEscaped<String>, AppError> {
-    let absences = LongAbsences::get(&state.pool).await?;
+async fn absences_graph(
+    state: &AppState,
+    user: i64,
+    filter: &AbsenceFilter,
+    viewer: Viewer,
+) -> Result<PreEscaped<String>, AppError> {
+    let mut absences = LongAbsences::get_filtered(user, filter, &state.pool).await?;
+    absences.coarsen(viewer);
+    let planned = PlannedAbsence::get_all(user, &state.pool).await?;
 
-    let range = absences
-        .range()
-        .ok_or_else(|| AppError::html_from_str("not enough absences :3"))?;
+    let Some(range) = absences.range() else {
+        return Ok(html! { p { "not enough absences :3" } });
+    };
 
     fn pos(date: DateTime<Utc>) -> f32 {
         100.0 * (date.hour() as f32 * 60.0 + date.minute() as f32) / (24.0 * 60.0)
     }
 
-    // TODO this graph breaks if there's an absence that spans 3 days
-    // well, i think its gonna work fine, but we're not gonna have the middle day filled
     Ok(html! {
         .absences {
             .left {
@@ -66,11 +121,17 @@ async fn absences_graph(state: &AppState) -> Result<PreEscaped<String>, AppError
                         }
 
                         @for abs in absences.absences_on(date) {
+                            @let planned = planned.iter().find(|planned| planned.overlaps(abs.start(), abs.end()));
+                            @let title = match planned {
+                                Some(planned) => format!("{} ({})", abs.desc(), planned.desc()),
+                                None => abs.desc(),
+                            };
+
                             // line between
                             @let start = if date_matches(abs.start(), date) { abs.start() } else { date };
                             @let length = if date_matches(abs.end(), date) { abs.end() - start } else { date.checked_add_days(Days::new(1)).unwrap() - start }.num_seconds() as f32;
                             @let length = 100.0 * (length / (60.0 * 60.0 * 24.0));
-                            span.length style={"left: "(pos(start))"%; width: "(length)"%;"} title=(abs.desc()) { }
+                            span.length.planned[planned.is_some()] style={"left: "(pos(start))"%; width: "(length)"%;"} title=(title) { }
 
                             // start
                             @if date_matches(abs.start(), date) {
@@ -89,28 +150,121 @@ async fn absences_graph(state: &AppState) -> Result<PreEscaped<String>, AppError
     })
 }
 
-async fn recent_beats(state: &AppState) -> Result<PreEscaped<String>, AppError> {
-    let beats = Beat::get_recent(&state.pool).await?;
+/// Time span shown in the recent beats graph
+#[derive(Clone, Copy, PartialEq)]
+pub enum Window {
+    Day,
+    Week,
+    Month,
+}
+
+impl Window {
+    const ALL: [Window; 3] = [Window::Day, Window::Week, Window::Month];
+
+    fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|w| w.name() == s)
+    }
 
-    let devices = Device::get_all(&state.pool).await?;
+    /// reads the `window` query parameter, defaulting to a week
+    pub fn from_query(q: &HashMap<String, String>) -> Self {
+        q.get("window")
+            .and_then(|s| Self::parse(s))
+            .unwrap_or(Window::Week)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Window::Day => "24h",
+            Window::Week => "7d",
+            Window::Month => "30d",
+        }
+    }
 
-    let oldest = beats
-        .last()
-        .ok_or_else(|| AppError::html_from_str("not enough beats :3"))?;
+    fn duration(self) -> TimeDelta {
+        match self {
+            Window::Day => TimeDelta::hours(24),
+            Window::Week => TimeDelta::days(7),
+            Window::Month => TimeDelta::days(30),
+        }
+    }
+
+    /// seconds between dots, seconds between labels, and the format of the labels
+    fn ticks(self) -> (i64, i64, &'static str) {
+        match self {
+            Window::Day => (60 * 60, 3 * 60 * 60, "%H:%M"),
+            Window::Week => (6 * 60 * 60, 24 * 60 * 60, "%m/%d"),
+            Window::Month => (24 * 60 * 60, 3 * 24 * 60 * 60, "%m/%d"),
+        }
+    }
+}
 
+/// most beats we draw one by one. past this, beats are grouped in buckets
+const MAX_BEATS: i32 = 4000;
+/// amount of buckets the window is split into when there's too many beats
+const BUCKETS: i64 = 500;
+
+/// Draws a line with the beats of `user` in `window` for each of `devices`. `query` is added to
+/// the links to other windows, like `&from=2024-06-01`
+///
+/// Anonymous viewers always get beats grouped in buckets, so exact times aren't visible
+pub async fn recent_beats(
+    state: &AppState,
+    user: i64,
+    window: Window,
+    devices: &[Device],
+    viewer: Viewer,
+    query: &str,
+) -> Result<PreEscaped<String>, AppError> {
     let now = Utc::now();
-    let range = RangeDays::new(oldest.date(), now);
+    let since = now - window.duration();
+    let span = window.duration().num_seconds();
+
+    let pos = |timestamp: i64| 100.0 * (timestamp - since.timestamp()) as f64 / span as f64;
 
-    let max_diff = now.timestamp() - oldest.unix_timestamp();
+    let count =
+        Beat::count_since(user, &since.naive_utc(), viewer.is_private(), &state.pool).await?;
+    let (buckets, bucket_size) = if count > MAX_BEATS || !viewer.is_private() {
+        let size = (span / BUCKETS).max(viewer.granularity());
+        let buckets = Beat::get_buckets_since(user, &since.naive_utc(), size, &state.pool).await?;
+        (buckets, Some(size))
+    } else {
+        let buckets = Beat::get_since(user, &since.naive_utc(), &state.pool)
+            .await?
+            .into_iter()
+            .map(|beat| BeatBucket {
+                device: beat.device,
+                start: beat.unix_timestamp(),
+                count: 1,
+            })
+            .collect();
+        (buckets, None)
+    };
+    // only the devices that are shown count, hidden ones stay hidden
+    let max_count = buckets
+        .iter()
+        .filter(|b| devices.iter().any(|device| device.id == b.device))
+        .map(|b| b.count)
+        .max()
+        .unwrap_or(1);
 
-    let pos =
-        |timestamp: i64| 100.0 * (timestamp - oldest.unix_timestamp()) as f64 / max_diff as f64;
+    let (tick, label, format) = window.ticks();
+    let ticks = (since.timestamp() / tick + 1..=now.timestamp() / tick).map(|i| i * tick);
 
     Ok(html! {
+        p {
+            @for w in Window::ALL {
+                @if w == window {
+                    strong { (w.name()) }
+                } @else {
+                    a href={"?window="(w.name())(query)} { (w.name()) }
+                }
+                " "
+            }
+        }
         .recent-beats {
             .left {
                 .line style="color: transparent;" {""}
-                @for device in &devices {
+                @for device in devices {
                     .line {
                         (device.name)
                     }
@@ -118,36 +272,26 @@ async fn recent_beats(state: &AppState) -> Result<PreEscaped<String>, AppError>
             }
             .right {
                 .line {
-                    @for day in range.clone() {
-                        span.hours style={"left: "(pos(day.timestamp()))"%;"} { (day.format("%m/%d").to_string()) }
-                    }
-
-                    @for day in range {
-                        @if oldest.date() < day && day < now {
-                            span.dots style={"left: "(pos(day.timestamp()))"%;"} { }
-                        }
-
-                        @let six = day.with_hour(6).unwrap();
-                        @if oldest.date() < six && six < now {
-                            span.dots style={"left: "(pos(six.timestamp()))"%;"} { }
-                        }
-
-                        @let twelve = day.with_hour(12).unwrap();
-                        @if oldest.date() < twelve && twelve < now {
-                            span.dots style={"left: "(pos(twelve.timestamp()))"%;"} { }
-                        }
-
-                        @let eight = day.with_hour(18).unwrap();
-                        @if oldest.date() < eight && eight < now {
-                            span.dots style={"left: "(pos(eight.timestamp()))"%;"} { }
+                    @for t in ticks {
+                        @if t % label == 0 {
+                            @let date = DateTime::from_timestamp(t, 0).unwrap();
+                            span.hours style={"left: "(pos(t))"%;"} { (date.format(format).to_string()) }
                         }
+                        span.dots style={"left: "(pos(t))"%;"} { }
                     }
                 }
-                @for device in &devices {
+                @for device in devices {
                     .line {
-                        @for beat in beats.iter().filter(|b| b.device == device.id) {
-                            // TODO
-                            span.beat style={"left: "(pos(beat.unix_timestamp()))"%;"} title=(beat.date().format("%Y/%m/%d %H:%M UTC").to_string())  { }
+                        @for bucket in buckets.iter().filter(|b| b.device == device.id) {
+                            @let date = DateTime::from_timestamp(bucket.start, 0).unwrap().format("%Y/%m/%d %H:%M UTC").to_string();
+                            @if let Some(size) = bucket_size {
+                                @let opacity = 0.2 + 0.8 * bucket.count as f64 / max_count as f64;
+                                span.beat
+                                    style={"left: "(pos(bucket.start))"%; width: "(100.0 * size as f64 / span as f64)"%; opacity: "(opacity)";"}
+                                    title={(bucket.count)" beats from "(date)} { }
+                            } @else {
+                                span.beat style={"left: "(pos(bucket.start))"%;"} title=(date) { }
+                            }
                         }
                     }
                 }
@@ -155,3 +299,219 @@ async fn recent_beats(state: &AppState) -> Result<PreEscaped<String>, AppError>
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{absence::Absence, device::Device, helpers::RangeDays, testing::init_state};
+
+    use super::*;
+    use ::axum_test::TestServer;
+    use assertables::*;
+    use axum::{routing::get, Router};
+
+    async fn base() -> (TestServer, Arc<AppState>) {
+        let state = init_state().await;
+
+        Device {
+            id: 1,
+            user: 1,
+            name: "test device".to_string(),
+            token: "my_token".to_string(),
+            beat_count: 0,
+            visible: true,
+        }
+        .create(&state.pool)
+        .await
+        .unwrap();
+
+        let app = Router::new()
+            .route("/graph", get(graph))
+            .with_state(state.clone());
+        let server = TestServer::new(app).unwrap();
+
+        (server, state)
+    }
+
+    /// creates an absence between two new beats
+    async fn absence(state: &AppState, end: DateTime<Utc>, duration: TimeDelta) -> Result<()> {
+        let begin = Beat {
+            id: 0,
+            device: 1,
+            timestamp: (end - duration).naive_utc(),
+        }
+        .create(&state.pool)
+        .await?;
+        let end_beat = Beat {
+            id: 0,
+            device: 1,
+            timestamp: end.naive_utc(),
+        }
+        .create(&state.pool)
+        .await?;
+        Absence {
+            id: 0,
+            timestamp: end.naive_utc(),
+            duration: duration.num_seconds(),
+            begin_beat: begin.id,
+            end_beat: end_beat.id,
+        }
+        .create(&state.pool)
+        .await?;
+        Ok(())
+    }
+
+    fn today() -> DateTime<Utc> {
+        RangeDays::new(Utc::now(), Utc::now()).next().unwrap()
+    }
+
+    #[tokio::test]
+    async fn only_shows_beats_in_window() -> Result<()> {
+        let (server, state) = base().await;
+
+        for hours in [1, 30] {
+            Beat {
+                id: 0,
+                device: 1,
+                timestamp: (Utc::now() - TimeDelta::hours(hours)).naive_utc(),
+            }
+            .create(&state.pool)
+            .await?;
+        }
+
+        let response = server.get("/graph").add_query_param("window", "24h").await;
+        response.assert_status_ok();
+        assert_eq!(1, response.text().matches("class=\"beat\"").count());
+
+        let response = server.get("/graph").await;
+        response.assert_status_ok();
+        assert_eq!(2, response.text().matches("class=\"beat\"").count());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn groups_beats_in_buckets() -> Result<()> {
+        let (server, state) = base().await;
+
+        let timestamps = (0..MAX_BEATS as i64 + 1)
+            .map(|i| (Utc::now() - TimeDelta::seconds(i * 10)).naive_utc().into())
+            .collect::<Vec<_>>();
+        Beat::create_many(1, &timestamps, &mut *state.pool.acquire().await?).await?;
+
+        let response = server.get("/graph").add_query_param("window", "24h").await;
+        response.assert_status_ok();
+
+        let beats = response.text().matches("class=\"beat\"").count();
+        assert!(0 < beats && beats <= BUCKETS as usize + 1);
+        assert_contains!(response.text(), " beats from ");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fills_every_day_of_long_absences() -> Result<()> {
+        let (server, state) = base().await;
+
+        absence(&state, today() + TimeDelta::minutes(1), TimeDelta::days(3)).await?;
+
+        let response = server.get("/graph").await;
+
+        response.assert_status_ok();
+        // one segment for each of the four days the absence touches
+        assert_eq!(4, response.text().matches("class=\"length\"").count());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn filters_by_range() -> Result<()> {
+        let (server, state) = base().await;
+
+        absence(&state, today() - TimeDelta::days(10), TimeDelta::hours(2)).await?;
+        absence(&state, today() - TimeDelta::days(5), TimeDelta::hours(2)).await?;
+
+        let day = (today() - TimeDelta::days(5))
+            .format("%Y-%m-%d")
+            .to_string();
+        let response = server
+            .get("/graph")
+            .add_query_param("from", &day)
+            .add_query_param("to", &day)
+            .await;
+
+        response.assert_status_ok();
+        assert_eq!(1, response.text().matches("class=\"length\"").count());
+        // other windows keep the filter
+        assert_contains!(
+            response.text(),
+            &format!("href=\"?window=24h&amp;from={day}&amp;to={day}\"")
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn filters_by_duration() -> Result<()> {
+        let (server, state) = base().await;
+
+        absence(&state, today() - TimeDelta::days(3), TimeDelta::hours(2)).await?;
+
+        let response = server
+            .get("/graph")
+            .add_query_param("min", 3 * 60 * 60)
+            .await;
+
+        response.assert_status_ok();
+        assert_contains!(response.text(), "not enough absences");
+
+        // absences are only stored from an hour, so shorter minimums show all of them
+        let response = server.get("/graph").add_query_param("min", 60).await;
+        response.assert_status_ok();
+        assert_not_contains!(response.text(), "not enough absences");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn takes_the_last_day_there_is() -> Result<()> {
+        let (server, _state) = base().await;
+
+        let last = NaiveDate::MAX.format("%Y-%m-%d").to_string();
+        server
+            .get("/graph")
+            .add_query_param("to", &last)
+            .await
+            .assert_status_ok();
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn marks_planned_absences() -> Result<()> {
+        let (server, state) = base().await;
+
+        absence(&state, today() - TimeDelta::hours(68), TimeDelta::hours(2)).await?;
+        absence(&state, today() - TimeDelta::hours(116), TimeDelta::hours(2)).await?;
+        PlannedAbsence {
+            id: 0,
+            user: 1,
+            starts_at: (today() - TimeDelta::days(4)).naive_utc(),
+            ends_at: today().naive_utc(),
+            note: "hospital".to_string(),
+            exclude_from_record: false,
+        }
+        .create(&state.pool)
+        .await?;
+
+        let response = server.get("/graph").await;
+
+        response.assert_status_ok();
+        assert_eq!(
+            1,
+            response.text().matches("class=\"length planned\"").count()
+        );
+        assert_contains!(response.text(), "(planned: hospital)");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+use std::{collections::HashSet, sync::Arc};
+
+use anyhow::Result;
+use axum::{extract::State, http::StatusCode};
+
+use crate::{errors::AppError, AppState};
+
+/// The process is alive
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// The database is reachable, and all migrations have been applied
+pub async fn readyz(State(state): State<Arc<AppState>>) -> Result<&'static str, AppError> {
+    // sqlx creates this table itself, so it's not checked at compile time
+    let applied: HashSet<i64> =
+        sqlx::query_scalar("select version from _sqlx_migrations where success")
+            .fetch_all(&state.pool)
+            .await
+            .map_err(|_| {
+                AppError::Rejection(StatusCode::SERVICE_UNAVAILABLE, "database unreachable")
+            })?
+            .into_iter()
+            .collect();
+
+    let pending = sqlx::migrate!()
+        .iter()
+        .any(|migration| !applied.contains(&migration.version));
+    if pending {
+        return Err(AppError::Rejection(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "migrations are pending",
+        ));
+    }
+
+    Ok("ok")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::init_state;
+
+    use super::*;
+    use ::axum_test::TestServer;
+    use axum::{routing::get, Router};
+
+    #[tokio::test]
+    async fn reports_readiness() -> Result<()> {
+        let state = init_state().await;
+
+        let app = Router::new()
+            .route("/healthz", get(healthz))
+            .route("/readyz", get(readyz))
+            .with_state(state.clone());
+        let server = TestServer::new(app).unwrap();
+
+        server.get("/healthz").await.assert_status_ok();
+        server.get("/readyz").await.assert_status_ok();
+
+        sqlx::query("delete from _sqlx_migrations where version = 20240607120000")
+            .execute(&state.pool)
+            .await?;
+        let response = server.get("/readyz").await;
+        response.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        response.assert_text("migrations are pending");
+
+        state.pool.close().await;
+        server.get("/healthz").await.assert_status_ok();
+        let response = server.get("/readyz").await;
+        response.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        response.assert_text("database unreachable");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Result;
+use axum::{extract::State, response::Html};
+use chrono::{Datelike, Days, Utc};
+use maud::{html, PreEscaped};
+
+use crate::{beat::Beat, errors::AppError, html::base_template, viewer::Page, AppState};
+
+/// size of a cell in the heatmaps, in px
+const CELL: usize = 12;
+/// space left for the labels
+const LABEL: usize = 30;
+
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+pub async fn heatmap(
+    State(state): State<Arc<AppState>>,
+    Page { owner, viewer }: Page,
+) -> Result<Html<String>, AppError> {
+    // visitors don't see the beats of hidden devices
+    let with_hidden = viewer.is_private();
+    let content = html! {
+        h1 { "past year" }
+        (calendar(&state, owner.id, with_hidden).await?)
+
+        h1 { "hours of the week" }
+        (hours_of_week(&state, owner.id, with_hidden).await?)
+    };
+    let content = base_template(content);
+
+    Ok(Html(content.0))
+}
+
+/// intensity of a cell, from 0 (no beats) to 4
+fn level(count: i64, max: i64) -> i64 {
+    if count <= 0 || max <= 0 {
+        return 0;
+    }
+    (4 * count + max - 1) / max
+}
+
+async fn calendar(
+    state: &AppState,
+    user: i64,
+    with_hidden: bool,
+) -> Result<PreEscaped<String>, AppError> {
+    let today = Utc::now().date_naive();
+    let start = today.checked_sub_days(Days::new(52 * 7)).unwrap();
+    // start on a sunday, so every column is a full week
+    let start = start
+        .checked_sub_days(Days::new(start.weekday().num_days_from_sunday() as u64))
+        .unwrap();
+
+    let since = start.and_hms_opt(0, 0, 0).unwrap();
+    let counts = Beat::count_per_day(user, &since, with_hidden, &state.pool)
+        .await?
+        .into_iter()
+        .map(|c| (c.day, c.count))
+        .collect::<HashMap<_, _>>();
+    let max = counts.values().copied().max().unwrap_or_default();
+
+    let days = start.iter_days().take_while(|d| *d <= today);
+    let weeks = (today - start).num_days() as usize / 7 + 1;
+
+    Ok(html! {
+        svg.heatmap width=(LABEL + weeks * CELL) height=(LABEL + 7 * CELL) {
+            @for (i, name) in WEEKDAYS.iter().enumerate().skip(1).step_by(2) {
+                text x="0" y=(LABEL + i * CELL + CELL - 2) { (name) }
+            }
+            @for day in days {
+                @let week = (day - start).num_days() as usize / 7;
+                @let x = LABEL + week * CELL;
+
+                @if day.day() == 1 {
+                    text x=(x) y=(LABEL - 8) { (day.format("%b").to_string().to_lowercase()) }
+                }
+
+                @let count = counts.get(&day).copied().unwrap_or_default();
+                rect
+                    class={"heat-"(level(count, max))}
+                    x=(x)
+                    y=(LABEL + day.weekday().num_days_from_sunday() as usize * CELL)
+                    width=(CELL - 2)
+                    height=(CELL - 2)
+                {
+                    title { (day.format("%Y/%m/%d").to_string())": "(count)" beats" }
+                }
+            }
+        }
+    })
+}
+
+async fn hours_of_week(
+    state: &AppState,
+    user: i64,
+    with_hidden: bool,
+) -> Result<PreEscaped<String>, AppError> {
+    let mut counts = [[0; 24]; 7];
+    for c in Beat::count_per_hour_of_week(user, with_hidden, &state.pool).await? {
+        counts[c.weekday as usize][c.hour as usize] = c.count;
+    }
+    let max = counts.iter().flatten().copied().max().unwrap_or_default();
+
+    Ok(html! {
+        svg.heatmap width=(LABEL + 24 * CELL) height=(LABEL + 7 * CELL) {
+            @for hour in (0..24).step_by(3) {
+                text x=(LABEL + hour * CELL) y=(LABEL - 8) { (hour) }
+            }
+            @for (weekday, hours) in counts.iter().enumerate() {
+                text x="0" y=(LABEL + weekday * CELL + CELL - 2) { (WEEKDAYS[weekday]) }
+
+                @for (hour, count) in hours.iter().enumerate() {
+                    rect
+                        class={"heat-"(level(*count, max))}
+                        x=(LABEL + hour * CELL)
+                        y=(LABEL + weekday * CELL)
+                        width=(CELL - 2)
+                        height=(CELL - 2)
+                    {
+                        title { (WEEKDAYS[weekday])" "(hour)":00 UTC: "(count)" beats" }
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{device::Device, testing::init_state};
+
+    use super::*;
+    use ::axum_test::TestServer;
+    use assertables::*;
+    use axum::{
+        http::{HeaderName, HeaderValue},
+        routing::get,
+        Router,
+    };
+    use chrono::{NaiveDateTime, NaiveTime, TimeDelta};
+
+    async fn base() -> (TestServer, Arc<AppState>) {
+        let state = init_state().await;
+
+        Device {
+            id: 1,
+            user: 1,
+            name: "test device".to_string(),
+            token: "my_token".to_string(),
+            beat_count: 0,
+            visible: true,
+        }
+        .create(&state.pool)
+        .await
+        .unwrap();
+
+        let app = Router::new()
+            .route("/heatmap", get(heatmap))
+            .with_state(state.clone());
+        let server = TestServer::new(app).unwrap();
+
+        (server, state)
+    }
+
+    #[test]
+    fn levels() {
+        assert_eq!(0, level(0, 0));
+        assert_eq!(0, level(0, 10));
+        assert_eq!(1, level(1, 10));
+        assert_eq!(2, level(5, 10));
+        assert_eq!(4, level(10, 10));
+    }
+
+    #[tokio::test]
+    async fn doesnt_panic_with_no_beats() -> Result<()> {
+        let (server, _state) = base().await;
+
+        let response = server.get("/heatmap").await;
+
+        response.assert_status_ok();
+        assert_contains!(response.text(), "0 beats");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn counts_beats() -> Result<()> {
+        let (server, state) = base().await;
+
+        let day = (Utc::now() - TimeDelta::days(3)).date_naive();
+        for minute in 0..3 {
+            Beat {
+                id: 0,
+                device: 1,
+                timestamp: NaiveDateTime::new(day, NaiveTime::from_hms_opt(13, minute, 0).unwrap()),
+            }
+            .create(&state.pool)
+            .await?;
+        }
+
+        let response = server.get("/heatmap").await;
+
+        response.assert_status_ok();
+        assert_contains!(
+            response.text(),
+            &format!("<title>{}: 3 beats</title>", day.format("%Y/%m/%d"))
+        );
+        assert_contains!(
+            response.text(),
+            &format!(
+                "<title>{} 13:00 UTC: 3 beats</title>",
+                WEEKDAYS[day.weekday().num_days_from_sunday() as usize]
+            )
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn leaves_out_hidden_devices_for_visitors() -> Result<()> {
+        let (server, state) = base().await;
+
+        Device {
+            id: 2,
+            user: 1,
+            name: "hidden device".to_string(),
+            token: "hidden_token".to_string(),
+            beat_count: 0,
+            visible: false,
+        }
+        .create(&state.pool)
+        .await?;
+        let time = Utc::now() - TimeDelta::days(3);
+        for device in [1, 2] {
+            Beat {
+                id: 0,
+                device,
+                timestamp: time.naive_utc(),
+            }
+            .create(&state.pool)
+            .await?;
+        }
+        let title = format!("<title>{}: ", time.format("%Y/%m/%d"));
+
+        let response = server.get("/heatmap").await;
+        assert_contains!(response.text(), &format!("{title}1 beats</title>"));
+
+        let response = server
+            .get("/heatmap")
+            .add_header(
+                HeaderName::from_static("authorization"),
+                HeaderValue::from_static("my_token"),
+            )
+            .await;
+        assert_contains!(response.text(), &format!("{title}2 beats</title>"));
+
+        Ok(())
+    }
+}
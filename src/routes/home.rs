@@ -1,4 +1,4 @@
-use std::sync::{atomic::Ordering, Arc};
+use std::sync::Arc;
 
 use anyhow::Result;
 use axum::{extract::State, response::Html};
@@ -6,33 +6,53 @@ use chrono::Utc;
 use maud::html;
 
 use crate::{
-    beat::Beat, errors::AppError, helpers::format_relative, html::base_template, AppState,
+    device::Device,
+    errors::AppError,
+    helpers::format_relative,
+    html::base_template,
+    presence::{PlannedAbsence, Status},
+    proxy::base_path,
+    settings::Settings,
+    viewer::Page,
+    AppState,
 };
 
-pub async fn home(State(state): State<Arc<AppState>>) -> Result<Html<String>, AppError> {
-    let first_beat = Beat::first_beat(&state.pool)
-        .await?
-        .ok_or_else(|| AppError::html_from_str("there are no heartbeats yet :3"))?;
-    let last_beat = Beat::last_beat(&state.pool)
-        .await?
-        .ok_or_else(|| AppError::html_from_str("there are no heartbeats yet :3"))?;
+pub async fn home(
+    State(state): State<Arc<AppState>>,
+    page: Page,
+) -> Result<Html<String>, AppError> {
+    let Page { owner, viewer } = &page;
 
-    let total_beats = Beat::count(&state.pool).await?;
+    let snapshot = state.home_snapshots.get(owner.id, &state.pool).await?;
+    let no_beats = || AppError::html_from_str("there are no heartbeats yet :3");
+    let first_beat = snapshot.first_beat.as_ref().ok_or_else(no_beats)?;
+    let last_beat = snapshot.last_beat.as_ref().ok_or_else(no_beats)?;
+    let total_beats = snapshot.total_beats;
+
+    let devices = viewer.devices(Device::get_all(owner.id, &state.pool).await?);
 
     let last_beat_time = last_beat.timestamp.and_utc();
     let first_beat_time = first_beat.timestamp.and_utc();
     let now = Utc::now();
 
     let dur = (now - last_beat_time).num_seconds();
-    state.longest_absence.fetch_max(dur, Ordering::Relaxed);
+    state
+        .longest_absences
+        .record(owner.id, last_beat_time, now, &state.pool)
+        .await?;
 
-    let active = dur < 60 * 10; // 10 mins
+    let settings = Settings::get(owner.id, &state.pool).await?;
+    let active = dur < 60 * settings.active_minutes as i64;
+
+    let status = Status::get(owner.id, &state.pool).await?;
+    let planned = PlannedAbsence::get_upcoming(owner.id, &state.pool).await?;
+    let away = planned.iter().find(|planned| planned.contains(now));
 
     let content = html! {
         p {
             "this is "
-            a href="https://versary.town" target="_blank" {"my"}
-            " heartbeat service :3" br;
+            strong { (owner.name) }
+            "'s heartbeat service :3" br;
             "this page displays the last time that i have unlocked/used any of my devices"
         }
         ul {
@@ -42,22 +62,38 @@ pub async fn home(State(state): State<Arc<AppState>>) -> Result<Html<String>, Ap
                     span.active {
                         "active"
                     }
+                } @else if away.is_some() {
+                    span.away {
+                        "away"
+                    }
                 } @else {
                     span.inactive {
                         "inactive"
                     }
                 }
             }
+            @if let Some(status) = &status {
+                li.status { (status.message) }
+            }
+            @for planned in &planned {
+                li {
+                    @if planned.contains(now) { "away until " } @else { "away from " (planned.starts_at.and_utc().format("%Y/%m/%d %H:%M UTC").to_string()) " to " }
+                    strong { (planned.ends_at.and_utc().format("%Y/%m/%d %H:%M UTC").to_string()) }
+                    @if !planned.note.is_empty() {
+                        ": " (planned.note)
+                    }
+                }
+            }
             li {
                 "last beat: "
                     strong {
-                        (last_beat_time.format("%Y/%m/%d %H:%M UTC").to_string())
+                        (viewer.coarsen(last_beat_time).format("%Y/%m/%d %H:%M UTC").to_string())
                     }
             }
             li {
                 "time since last beat: "
                     strong {
-                        (format_relative(dur))
+                        (format_relative(viewer.coarsen_secs(dur)))
                     }
             }
 
@@ -65,7 +101,7 @@ pub async fn home(State(state): State<Arc<AppState>>) -> Result<Html<String>, Ap
             li title="longest absence since the server restarted" {
                 "longest absence: "
                     strong {
-                        (format_relative(state.longest_absence.load(Ordering::Relaxed)))
+                        (format_relative(viewer.coarsen_secs(state.longest_absences.get(owner.id))))
                     }
             }
             li {
@@ -77,7 +113,7 @@ pub async fn home(State(state): State<Arc<AppState>>) -> Result<Html<String>, Ap
             li {
                 "first beat: "
                     strong {
-                        (first_beat_time.format("%Y/%m/%d %H:%M UTC").to_string())
+                        (viewer.coarsen(first_beat_time).format("%Y/%m/%d %H:%M UTC").to_string())
                     }
             }
             li {
@@ -88,6 +124,18 @@ pub async fn home(State(state): State<Arc<AppState>>) -> Result<Html<String>, Ap
             }
         }
 
+        h4 { "devices" }
+        ul {
+            @for device in &devices {
+                li {
+                    a href={(base_path())"/device/"(device.id)} { (device.name) }
+                    ": "
+                    strong { (snapshot.device_beats.get(&device.id).copied().unwrap_or_default()) }
+                    " beats"
+                }
+            }
+        }
+
         @if active {
             p.small {
                 "im active right now! if i'm not replying to your messages,"
@@ -96,6 +144,10 @@ pub async fn home(State(state): State<Arc<AppState>>) -> Result<Html<String>, Ap
                 br;
                 "and i will get back to you once i can dedicate my full attention to you :3"
             }
+        } @else if away.is_some() {
+            p.small {
+                "i'm away right now, and will get back to you once i'm back :3"
+            }
         } @else if dur > 60 * 60 * 4 {
             p.small {
                 "i've been inactive for more than 4 hours, which probably means im asleep,"
@@ -106,6 +158,9 @@ pub async fn home(State(state): State<Arc<AppState>>) -> Result<Html<String>, Ap
                 a href="https://en.wikipedia.org/wiki/Non-24-hour_sleep%E2%80%93wake_disorder" target="_blank" {
                     "sleep disorder"
                 }
+                br;
+                "you can see how my sleep schedule has been moving "
+                a href=(page.link("/sleep")) { "here" }
             }
         }
     };
@@ -117,12 +172,16 @@ pub async fn home(State(state): State<Arc<AppState>>) -> Result<Html<String>, Ap
 
 #[cfg(test)]
 mod tests {
-    use crate::{device::Device, testing::init_state};
+    use crate::{beat::Beat, device::Device, testing::init_state, user::User};
 
     use super::*;
     use ::axum_test::TestServer;
     use assertables::*;
-    use axum::{routing::post, Router};
+    use axum::{
+        http::{HeaderName, HeaderValue},
+        routing::post,
+        Router,
+    };
     use chrono::TimeDelta;
 
     async fn base() -> (TestServer, Arc<AppState>) {
@@ -130,9 +189,11 @@ mod tests {
 
         Device {
             id: 1,
+            user: 1,
             name: "test device".to_string(),
             token: "my_token".to_string(),
             beat_count: 0,
+            visible: true,
         }
         .create(&state.pool)
         .await
@@ -140,6 +201,7 @@ mod tests {
 
         let app = Router::new()
             .route("/", post(home))
+            .route("/u/:name", post(home))
             .with_state(state.clone());
         let server = TestServer::new(app).unwrap();
 
@@ -180,6 +242,7 @@ mod tests {
                 response.text(),
                 &format!("total beats: <strong>{num}</strong>")
             );
+            assert_contains!(response.text(), "<a href=\"/device/1\">device 1</a>");
         }
 
         Ok(())
@@ -230,4 +293,193 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn shows_details_to_owner() -> Result<()> {
+        let (server, state) = base().await;
+
+        Device {
+            id: 2,
+            user: 1,
+            name: "hidden device".to_string(),
+            token: "hidden_token".to_string(),
+            beat_count: 0,
+            visible: false,
+        }
+        .create(&state.pool)
+        .await?;
+        Beat {
+            id: 0,
+            device: 1,
+            timestamp: (Utc::now() - TimeDelta::minutes(9)).naive_utc(),
+        }
+        .create(&state.pool)
+        .await?;
+
+        let response = server.post("/").await;
+        response.assert_status_ok();
+        assert_not_contains!(response.text(), "test device");
+        assert_not_contains!(response.text(), "device 2");
+
+        let response = server
+            .post("/")
+            .add_header(
+                HeaderName::from_bytes(b"Authorization")?,
+                HeaderValue::from_str("my_token")?,
+            )
+            .await;
+        response.assert_status_ok();
+        assert_contains!(response.text(), "test device");
+        assert_contains!(response.text(), "hidden device");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn each_user_has_a_page() -> Result<()> {
+        let (server, state) = base().await;
+
+        User::set_password("bob", "hunter2", &state.pool).await?;
+        let bob = User::get_by_name("bob", &state.pool).await?.unwrap();
+        Device {
+            id: 2,
+            user: bob.id,
+            name: "bob's device".to_string(),
+            token: "bob_token".to_string(),
+            beat_count: 0,
+            visible: true,
+        }
+        .create(&state.pool)
+        .await?;
+        for device in [1, 2, 2] {
+            Beat {
+                id: 0,
+                device,
+                timestamp: (Utc::now() - TimeDelta::minutes(20)).naive_utc(),
+            }
+            .create(&state.pool)
+            .await?;
+        }
+
+        // the root belongs to the first user
+        let response = server.post("/").await;
+        response.assert_status_ok();
+        assert_contains!(response.text(), "total beats: <strong>1</strong>");
+
+        let response = server.post("/u/bob").await;
+        response.assert_status_ok();
+        assert_contains!(response.text(), "total beats: <strong>2</strong>");
+        assert_contains!(response.text(), "<a href=\"/device/2\">device 2</a>");
+        assert_not_contains!(response.text(), "/device/1");
+
+        // device tokens only show details of their own user
+        let response = server
+            .post("/u/bob")
+            .add_header(
+                HeaderName::from_bytes(b"Authorization")?,
+                HeaderValue::from_str("my_token")?,
+            )
+            .await;
+        assert_not_contains!(response.text(), "bob's device");
+
+        let response = server.post("/u/nobody").await;
+        assert_contains!(response.text(), "there is no such user");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shows_status_and_planned_absences() -> Result<()> {
+        let (server, state) = base().await;
+
+        Beat {
+            id: 0,
+            device: 1,
+            timestamp: (Utc::now() - TimeDelta::days(2)).naive_utc(),
+        }
+        .create(&state.pool)
+        .await?;
+        Status::set(1, "at the beach", &state.pool).await?;
+        PlannedAbsence {
+            id: 0,
+            user: 1,
+            starts_at: (Utc::now() - TimeDelta::days(3)).naive_utc(),
+            ends_at: (Utc::now() + TimeDelta::days(3)).naive_utc(),
+            note: "vacation".to_string(),
+            exclude_from_record: true,
+        }
+        .create(&state.pool)
+        .await?;
+
+        let response = server.post("/").await;
+
+        response.assert_status_ok();
+        assert_contains!(response.text(), "at the beach");
+        assert_contains!(response.text(), "status: <span class=\"away\">away</span>");
+        assert_contains!(response.text(), "away until ");
+        // the absence happened during the vacation
+        assert_eq!(0, state.longest_absences.get(1));
+
+        Ok(())
+    }
+
+    /// run with `cargo test --release -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn stays_fast_with_a_million_beats() -> Result<()> {
+        let (_, state) = base().await;
+
+        Device {
+            id: 2,
+            user: 1,
+            name: "other device".to_string(),
+            token: "other_token".to_string(),
+            beat_count: 0,
+            visible: true,
+        }
+        .create(&state.pool)
+        .await?;
+
+        // a beat a minute from either device, for the last two years
+        let start = (Utc::now() - TimeDelta::minutes(1_000_000)).naive_utc();
+        sqlx::query!(
+            "with recursive n(i) as (select 0 union all select i + 1 from n where i < 999999)
+            insert into beats (device, user, timestamp)
+            select 1 + i % 2, 1, datetime(?, '+' || i || ' minutes') from n",
+            start
+        )
+        .execute(&state.pool)
+        .await?;
+
+        let app = Router::new()
+            .route("/", post(home))
+            .route("/api/beat", post(crate::routes::beat::beat))
+            .with_state(state.clone());
+        let server = TestServer::new(app).unwrap();
+
+        let time = std::time::Instant::now();
+        for _ in 0..10 {
+            server.post("/").await.assert_status_ok();
+        }
+        let home = time.elapsed() / 10;
+
+        let time = std::time::Instant::now();
+        for _ in 0..100 {
+            server
+                .post("/api/beat")
+                .add_header(
+                    HeaderName::from_static("authorization"),
+                    HeaderValue::from_static("my_token"),
+                )
+                .await
+                .assert_status_ok();
+        }
+        let beat = time.elapsed() / 100;
+
+        println!("home page: {home:?}, beat: {beat:?}");
+        assert!(home.as_millis() < 100, "home page took {home:?}");
+        assert!(beat.as_millis() < 10, "beat took {beat:?}");
+
+        Ok(())
+    }
 }
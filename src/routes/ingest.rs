@@ -0,0 +1,330 @@
+use std::sync::{Arc, LazyLock};
+
+use anyhow::Result;
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+};
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use heartbeat_client::api::BeatMeta;
+use serde_json::Value;
+
+use crate::{
+    audit::AuditEvent,
+    device::Device,
+    errors::AppError,
+    helpers::random_token,
+    ingest::{self, IngestSource},
+    proxy::ClientIp,
+    routes::beat::check_timestamp,
+    AppState,
+};
+
+/// header github and others put the signature of the payload in
+const SIGNATURE_HEADER: &str = "X-Hub-Signature-256";
+/// header with the unix time the payload was signed at, which is part of the signature
+const TIMESTAMP_HEADER: &str = "X-Signature-Timestamp";
+/// how far from now a payload can have been signed, in seconds
+const MAX_SIGNATURE_AGE: i64 = 5 * 60;
+
+/// checked against when there's no source with the name, so that takes as long as a wrong
+/// signature does
+static UNKNOWN_SOURCE: LazyLock<IngestSource> = LazyLock::new(|| IngestSource {
+    id: 0,
+    user: 0,
+    device: 0,
+    name: String::new(),
+    secret: random_token(),
+    timestamp_pointer: None,
+    require_pointer: None,
+    created_at: NaiveDateTime::default(),
+});
+
+/// Receives a webhook of an ingest source, and stores a beat for its device
+pub async fn ingest(
+    State(state): State<Arc<AppState>>,
+    ClientIp(ip): ClientIp,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<String, AppError> {
+    // unknown sources and wrong signatures look the same and take as long, so names can't be
+    // guessed
+    let unauthorized = || {
+        AppError::Rejection(
+            StatusCode::UNAUTHORIZED,
+            "no such source, or the signature is wrong",
+        )
+    };
+    let source = IngestSource::get_by_name(&name, &state.pool).await?;
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+    };
+    let signed_at = header(TIMESTAMP_HEADER);
+    let payload = IngestSource::signed_payload(signed_at, &body);
+    let verified = source
+        .as_ref()
+        .unwrap_or(&UNKNOWN_SOURCE)
+        .verify(&payload, header(SIGNATURE_HEADER));
+    if !verified {
+        AuditEvent::DeviceAuthFailed {
+            reason: format!("wrong signature for webhook {name}"),
+        }
+        .record(source.as_ref().map(|source| source.user), ip, &state.pool)
+        .await?;
+        return Err(unauthorized());
+    }
+    let source = source.ok_or_else(unauthorized)?;
+
+    // the signature is only good for a few minutes, so captured requests can't be sent again
+    let signed_at = signed_at
+        .parse()
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0));
+    if signed_at.is_none_or(|signed_at| {
+        (Utc::now() - signed_at).abs() > TimeDelta::seconds(MAX_SIGNATURE_AGE)
+    }) {
+        return Err(AppError::Rejection(
+            StatusCode::UNAUTHORIZED,
+            "the signature is too old",
+        ));
+    }
+
+    let payload = serde_json::from_slice::<Value>(&body)
+        .map_err(|_| AppError::Rejection(StatusCode::BAD_REQUEST, "the body isn't json"))?;
+
+    if let Some(pointer) = &source.require_pointer {
+        if payload
+            .pointer(pointer)
+            .is_none_or(|value| matches!(value, Value::Null | Value::Bool(false)))
+        {
+            return Ok("ignored".to_string());
+        }
+    }
+
+    let timestamp = match &source.timestamp_pointer {
+        Some(pointer) => {
+            payload
+                .pointer(pointer)
+                .and_then(parse_timestamp)
+                .ok_or(AppError::Rejection(
+                    StatusCode::BAD_REQUEST,
+                    "the payload doesn't have a timestamp where the source expects it",
+                ))?
+        }
+        None => Utc::now(),
+    };
+    check_timestamp(timestamp)?;
+
+    let device = Device::get_by_id(source.device, &state.pool)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("the device of webhook {} is gone", source.name))?;
+    // beats of a webhook can be told apart in the stats by their client version
+    let meta = BeatMeta {
+        client_version: Some(format!("webhook {}", source.name)),
+        ..Default::default()
+    };
+    let tx = state.pool.begin().await?;
+    ingest::record_beat(&state, &device, timestamp, &meta, ip, tx).await?;
+
+    Ok(timestamp.timestamp().to_string())
+}
+
+/// Reads rfc 3339 dates, dates without a timezone in UTC, and unix timestamps in seconds
+fn parse_timestamp(value: &Value) -> Option<DateTime<Utc>> {
+    match value {
+        Value::Number(secs) => DateTime::from_timestamp(secs.as_i64()?, 0),
+        Value::String(date) => DateTime::parse_from_rfc3339(date)
+            .map(|date| date.with_timezone(&Utc))
+            .or_else(|_| date.parse::<NaiveDateTime>().map(|date| date.and_utc()))
+            .ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{audit::AuditEntry, beat::Beat, testing::init_state};
+
+    use super::*;
+    use ::axum_test::TestServer;
+    use axum::{
+        http::{HeaderName, HeaderValue},
+        routing::post,
+        Router,
+    };
+    use axum_test::TestResponse;
+    use chrono::TimeDelta;
+
+    async fn base() -> (TestServer, Arc<AppState>, IngestSource) {
+        let state = init_state().await;
+
+        Device {
+            id: 1,
+            user: 1,
+            name: "test device".to_string(),
+            token: "my_token".to_string(),
+            beat_count: 0,
+            visible: true,
+        }
+        .create(&state.pool)
+        .await
+        .unwrap();
+        let source = IngestSource::create(
+            1,
+            1,
+            "github",
+            Some("/head_commit/timestamp".to_string()),
+            Some("/head_commit".to_string()),
+            &state.pool,
+        )
+        .await
+        .unwrap();
+
+        let app = Router::new()
+            .route("/api/ingest/:name", post(ingest))
+            .with_state(state.clone());
+        let server = TestServer::new(app).unwrap();
+
+        (server, state, source)
+    }
+
+    /// signs `payload` with `source` as of `signed_at`
+    fn sign(source: &IngestSource, signed_at: DateTime<Utc>, payload: &Value) -> String {
+        let payload = IngestSource::signed_payload(
+            &signed_at.timestamp().to_string(),
+            payload.to_string().as_bytes(),
+        );
+        format!("sha256={}", source.sign(&payload))
+    }
+
+    async fn request(
+        server: &TestServer,
+        signed_at: DateTime<Utc>,
+        signature: &str,
+        payload: &Value,
+    ) -> TestResponse {
+        server
+            .post("/api/ingest/github")
+            .add_header(
+                HeaderName::from_static("x-hub-signature-256"),
+                HeaderValue::from_str(signature).unwrap(),
+            )
+            .add_header(
+                HeaderName::from_static("x-signature-timestamp"),
+                HeaderValue::from_str(&signed_at.timestamp().to_string()).unwrap(),
+            )
+            .bytes(payload.to_string().into())
+            .await
+    }
+
+    #[tokio::test]
+    async fn creates_beats_from_signed_payloads() -> Result<()> {
+        let (server, state, source) = base().await;
+
+        let time = Utc::now() - TimeDelta::minutes(1);
+        let payload = serde_json::json!({
+            "ref": "refs/heads/main",
+            "head_commit": { "timestamp": time.to_rfc3339() },
+        });
+        let now = Utc::now();
+        request(&server, now, &sign(&source, now, &payload), &payload)
+            .await
+            .assert_status_ok();
+
+        let beat = Beat::last_beat(1, &state.pool).await?.unwrap();
+        assert_eq!(time.timestamp(), beat.timestamp.and_utc().timestamp());
+        let version = sqlx::query_scalar!(
+            "select version from client_versions join beats on beats.client_version = client_versions.id"
+        )
+        .fetch_one(&state.pool)
+        .await?;
+        assert_eq!("webhook github", version);
+
+        // pings don't have a commit
+        let ping = serde_json::json!({ "zen": "keep it logically awesome" });
+        let response = request(&server, now, &sign(&source, now, &ping), &ping).await;
+        response.assert_status_ok();
+        assert_eq!("ignored", response.text());
+        assert_eq!(1, Beat::count(1, &state.pool).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_signatures() -> Result<()> {
+        let (server, state, source) = base().await;
+
+        let payload =
+            serde_json::json!({ "head_commit": { "timestamp": Utc::now().to_rfc3339() } });
+        let now = Utc::now();
+        let response = request(&server, now, "sha256=0123", &payload).await;
+        response.assert_status_unauthorized();
+        let response = server
+            .post("/api/ingest/gitlab")
+            .bytes(payload.to_string().into())
+            .await;
+        response.assert_status_unauthorized();
+
+        // signed by another source
+        let other = IngestSource {
+            secret: "not the secret".to_string(),
+            ..source
+        };
+        request(&server, now, &sign(&other, now, &payload), &payload)
+            .await
+            .assert_status_unauthorized();
+
+        assert_eq!(0, Beat::count(1, &state.pool).await?);
+        // the same failure from the same address is only stored once
+        let entries = AuditEntry::get_recent(1, 10, &state.pool).await?;
+        assert_eq!(1, entries.len());
+        assert_eq!(
+            AuditEvent::DeviceAuthFailed {
+                reason: "wrong signature for webhook github".to_string()
+            },
+            entries[0].event
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_old_signatures() -> Result<()> {
+        let (server, state, source) = base().await;
+
+        let payload =
+            serde_json::json!({ "head_commit": { "timestamp": Utc::now().to_rfc3339() } });
+        let signed_at = Utc::now() - TimeDelta::minutes(10);
+        let signature = sign(&source, signed_at, &payload);
+        request(&server, signed_at, &signature, &payload)
+            .await
+            .assert_status_unauthorized();
+        // the time is part of the signature, so it can't be changed
+        request(&server, Utc::now(), &signature, &payload)
+            .await
+            .assert_status_unauthorized();
+        assert_eq!(0, Beat::count(1, &state.pool).await?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        let expected = DateTime::from_timestamp(1717236000, 0);
+        for value in [
+            serde_json::json!(1717236000),
+            serde_json::json!("2024-06-01T10:00:00Z"),
+            serde_json::json!("2024-06-01T12:00:00+02:00"),
+            serde_json::json!("2024-06-01T10:00:00"),
+        ] {
+            assert_eq!(expected, parse_timestamp(&value), "{value}");
+        }
+        assert_eq!(None, parse_timestamp(&serde_json::json!("soon")));
+        assert_eq!(None, parse_timestamp(&serde_json::json!(null)));
+    }
+}
@@ -1,5 +1,12 @@
+pub mod admin;
+pub mod api;
 pub mod batch;
 pub mod beat;
+pub mod device;
 pub mod graph;
+pub mod health;
+pub mod heatmap;
 pub mod home;
+pub mod ingest;
 pub mod report;
+pub mod sleep;
@@ -8,40 +8,51 @@ use axum::{
 use chrono::Duration;
 use maud::html;
 
-use crate::{errors::AppError, helpers::format_relative, html::base_template, AppState};
+use crate::{
+    errors::AppError, helpers::format_relative, html::base_template, presence::PlannedAbsence,
+    viewer::Page, AppState,
+};
 
 pub async fn report(
     State(state): State<Arc<AppState>>,
+    Page { owner, viewer }: Page,
     Query(q): Query<HashMap<String, String>>,
 ) -> Result<Html<String>, AppError> {
     struct Absence {
         start: String,
         end: String,
         length: String,
+        /// the planned absence this happened during
+        planned: Option<String>,
     }
 
+    let planned = PlannedAbsence::get_all(owner.id, &state.pool).await?;
+
     let duration = q
         .get("duration")
         .and_then(|s| s.parse::<u32>().ok())
         .unwrap_or_default() as i64;
 
     let absences: Vec<Absence> = sqlx::query!(
-        "select * from absences where duration > ? order by id desc limit 1000",
+        "select * from absences where user = ? and duration > ? order by id desc limit 1000",
+        owner.id,
         duration
     )
     .fetch_all(&state.pool)
     .await?
     .into_iter()
-    .map(|a| Absence {
-        end: a
-            .timestamp
-            .and_utc()
-            .format("%Y/%m/%d %H:%M UTC")
-            .to_string(),
-        start: (a.timestamp.and_utc() - Duration::seconds(a.duration))
-            .format("%Y/%m/%d %H:%M UTC")
-            .to_string(),
-        length: format_relative(a.duration),
+    .map(|a| {
+        let end = viewer.coarsen(a.timestamp.and_utc());
+        let start = viewer.coarsen(a.timestamp.and_utc() - Duration::seconds(a.duration));
+        Absence {
+            end: end.format("%Y/%m/%d %H:%M UTC").to_string(),
+            start: start.format("%Y/%m/%d %H:%M UTC").to_string(),
+            length: format_relative((end - start).num_seconds()),
+            planned: planned
+                .iter()
+                .find(|planned| planned.overlaps(start, end))
+                .map(|planned| planned.desc()),
+        }
     })
     .collect();
 
@@ -50,6 +61,9 @@ pub async fn report(
             @for a in &absences {
                 li {
                     "Absence from "(a.start)" to "(a.end)" of "(a.length)
+                    @if let Some(planned) = &a.planned {
+                        " (" (planned) ")"
+                    }
                 }
             }
         }
@@ -0,0 +1,192 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Result;
+use axum::{
+    extract::{Query, State},
+    response::Html,
+};
+use chrono::Duration;
+use maud::html;
+
+use crate::{
+    absence::LongAbsences,
+    errors::AppError,
+    helpers::format_relative,
+    html::base_template,
+    settings::Settings,
+    sleep::{SleepAnalysis, SleepConfig},
+    viewer::Page,
+    AppState,
+};
+
+pub async fn sleep(
+    State(state): State<Arc<AppState>>,
+    Page { owner, viewer }: Page,
+    Query(q): Query<HashMap<String, String>>,
+) -> Result<Html<String>, AppError> {
+    let settings = Settings::get(owner.id, &state.pool).await?;
+    let mut config = SleepConfig {
+        min: Duration::hours(settings.sleep_min_hours as i64),
+        max: Duration::hours(settings.sleep_max_hours as i64),
+    };
+    if let Some(min) = q.get("min").and_then(|s| s.parse::<u32>().ok()) {
+        config.min = Duration::hours(min as i64);
+    }
+    if let Some(max) = q.get("max").and_then(|s| s.parse::<u32>().ok()) {
+        config.max = Duration::hours(max as i64);
+    }
+
+    let mut absences = LongAbsences::get(owner.id, &state.pool).await?;
+    absences.coarsen(viewer);
+    let analysis = SleepAnalysis::new(absences.absences(), config);
+
+    let days = analysis.days();
+    if days.is_empty() {
+        return Err(AppError::html_from_str("not enough sleep data :3"));
+    }
+
+    let content = html! {
+        h1 { "sleep" }
+        p.small {
+            "absences between "
+            (format_relative(config.min.num_seconds()))
+            "and "
+            (format_relative(config.max.num_seconds()))
+            "are counted as sleep"
+        }
+
+        ul {
+            li {
+                "sleep episodes: "
+                strong { (analysis.episodes().len()) }
+            }
+            @if let Some(drift) = analysis.drift() {
+                li {
+                    "estimated cycle length: "
+                    strong { (format!("{:.2}h", drift.period())) }
+                }
+                li {
+                    "drift: "
+                    strong { (format!("{:+.1}h per week", drift.hours_per_week())) }
+                }
+                li {
+                    "based on: "
+                    strong { (drift.samples) " nights" }
+                }
+            } @else {
+                li { "not enough nights to estimate the schedule drift yet" }
+            }
+        }
+
+        h4 { "nights" }
+        ul {
+            @for day in &days {
+                li {
+                    (day.date.format("%Y/%m/%d").to_string())
+                    ": asleep from "
+                    strong { (day.onset.format("%H:%M").to_string()) }
+                    " to "
+                    strong { (day.offset.format("%H:%M UTC").to_string()) }
+                    " ("
+                    (format_relative((day.offset - day.onset).num_seconds()).trim_end())
+                    ")"
+                }
+            }
+        }
+    };
+    let content = base_template(content);
+
+    Ok(Html(content.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{absence::Absence, beat::Beat, device::Device, testing::init_state};
+
+    use super::*;
+    use ::axum_test::TestServer;
+    use assertables::*;
+    use axum::{routing::get, Router};
+    use chrono::{TimeDelta, Utc};
+
+    async fn base() -> (TestServer, Arc<AppState>) {
+        let state = init_state().await;
+
+        Device {
+            id: 1,
+            user: 1,
+            name: "test device".to_string(),
+            token: "my_token".to_string(),
+            beat_count: 0,
+            visible: true,
+        }
+        .create(&state.pool)
+        .await
+        .unwrap();
+
+        let app = Router::new()
+            .route("/sleep", get(sleep))
+            .with_state(state.clone());
+        let server = TestServer::new(app).unwrap();
+
+        (server, state)
+    }
+
+    #[tokio::test]
+    async fn doesnt_panic_with_no_absences() -> Result<()> {
+        let (server, _state) = base().await;
+
+        let response = server.get("/sleep").await;
+
+        response.assert_status_ok();
+        assert_contains!(response.text(), "not enough sleep data");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lists_nights() -> Result<()> {
+        let (server, state) = base().await;
+
+        for i in 0..5 {
+            let end = Utc::now() - TimeDelta::days(i);
+            let begin = Beat {
+                id: 0,
+                device: 1,
+                timestamp: (end - TimeDelta::hours(8)).naive_utc(),
+            }
+            .create(&state.pool)
+            .await?;
+            let end_beat = Beat {
+                id: 0,
+                device: 1,
+                timestamp: end.naive_utc(),
+            }
+            .create(&state.pool)
+            .await?;
+            Absence {
+                id: 0,
+                timestamp: end.naive_utc(),
+                duration: 8 * 60 * 60,
+                begin_beat: begin.id,
+                end_beat: end_beat.id,
+            }
+            .create(&state.pool)
+            .await?;
+        }
+
+        let response = server.get("/sleep").await;
+        response.assert_status_ok();
+        assert_contains!(
+            response.text(),
+            "estimated cycle length: <strong>24.00h</strong>"
+        );
+
+        // nothing is long enough to count as sleep
+        let response = server.get("/sleep").add_query_param("min", 10).await;
+        response.assert_status_ok();
+        assert_contains!(response.text(), "not enough sleep data");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, HeaderMap, StatusCode},
+    response::Redirect,
+};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use sqlx::{Executor, Sqlite};
+
+use crate::{
+    errors::AppError,
+    helpers::{constant_time_eq, get_cookie, random_token},
+    proxy::{base_path, url},
+    AppState,
+};
+
+pub const SESSION_COOKIE: &str = "heartbeat_session";
+
+/// failed logins from an address before it has to wait
+pub const MAX_FAILED_LOGINS: u32 = 5;
+/// how long an address has to wait after too many failed logins
+const LOGIN_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+/// A logged in owner
+pub struct Session {
+    pub token: String,
+    pub user: i64,
+    /// token that has to be sent with every form, to protect against csrf
+    pub csrf_token: String,
+    pub expires_at: NaiveDateTime,
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Session {
+    type Rejection = Redirect;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        match Session::from_headers(&parts.headers, state).await {
+            Some(session) => Ok(session),
+            None => Err(Redirect::to(&url("/login"))),
+        }
+    }
+}
+
+impl Session {
+    /// how long a session lasts after logging in
+    const DURATION: TimeDelta = TimeDelta::days(30);
+
+    pub async fn from_headers(headers: &HeaderMap, state: &AppState) -> Option<Self> {
+        let token = get_cookie(headers, SESSION_COOKIE)?;
+        Session::get_valid(token, &state.pool).await.ok().flatten()
+    }
+
+    pub async fn create<'c, E>(user: i64, executor: E) -> Result<Self>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let session = Session {
+            token: random_token(),
+            user,
+            csrf_token: random_token(),
+            expires_at: (Utc::now() + Self::DURATION).naive_utc(),
+        };
+
+        sqlx::query!(
+            "insert into sessions (token, user, csrf_token, expires_at) values (?, ?, ?, ?)",
+            session.token,
+            session.user,
+            session.csrf_token,
+            session.expires_at,
+        )
+        .execute(executor)
+        .await?;
+
+        Ok(session)
+    }
+
+    /// Gets a session that hasn't expired yet
+    pub async fn get_valid<'c, E>(token: &str, executor: E) -> Result<Option<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let now = Utc::now().naive_utc();
+        let session = sqlx::query_as!(
+            Session,
+            "select token, user, csrf_token, expires_at from sessions where token = ? and expires_at > ?",
+            token,
+            now
+        )
+        .fetch_optional(executor)
+        .await?;
+
+        Ok(session)
+    }
+
+    pub async fn delete<'c, E>(&self, executor: E) -> Result<()>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        sqlx::query!("delete from sessions where token = ?", self.token)
+            .execute(executor)
+            .await?;
+
+        Ok(())
+    }
+
+    pub fn check_csrf(&self, token: &str) -> Result<(), AppError> {
+        if constant_time_eq(self.csrf_token.as_bytes(), token.as_bytes()) {
+            Ok(())
+        } else {
+            Err(AppError::Rejection(
+                StatusCode::FORBIDDEN,
+                "invalid csrf token, try reloading the page",
+            ))
+        }
+    }
+
+    /// value for the `Set-Cookie` header that stores this session. over https, browsers are told
+    /// to never send it without
+    pub fn cookie(&self, https: bool) -> String {
+        format!(
+            "{SESSION_COOKIE}={}; Path={}/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+            self.token,
+            base_path(),
+            Self::DURATION.num_seconds(),
+            secure(https)
+        )
+    }
+
+    /// value for the `Set-Cookie` header that removes the session
+    pub fn removal_cookie(https: bool) -> String {
+        format!(
+            "{SESSION_COOKIE}=; Path={}/; Max-Age=0; HttpOnly; SameSite=Lax{}",
+            base_path(),
+            secure(https)
+        )
+    }
+}
+
+fn secure(https: bool) -> &'static str {
+    if https {
+        "; Secure"
+    } else {
+        ""
+    }
+}
+
+/// Failed logins of each address, so passwords can't be guessed quickly
+#[derive(Default)]
+pub struct LoginAttempts(Mutex<HashMap<Option<IpAddr>, (u32, Instant)>>);
+
+impl LoginAttempts {
+    /// Whether `ip` failed to log in too often lately
+    pub fn is_locked(&self, ip: Option<IpAddr>) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .get(&ip)
+            .is_some_and(|(failures, last)| {
+                *failures >= MAX_FAILED_LOGINS && last.elapsed() < LOGIN_LOCKOUT
+            })
+    }
+
+    pub fn failed(&self, ip: Option<IpAddr>) {
+        let mut attempts = self.0.lock().unwrap();
+        // failures are forgotten once an address stops for a while
+        attempts.retain(|_, (_, last)| last.elapsed() < LOGIN_LOCKOUT);
+        let (failures, last) = attempts.entry(ip).or_insert((0, Instant::now()));
+        *failures += 1;
+        *last = Instant::now();
+    }
+
+    pub fn succeeded(&self, ip: Option<IpAddr>) {
+        self.0.lock().unwrap().remove(&ip);
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use sqlx::{Executor, Sqlite};
+
+/// Settings of a user, that can be changed from the admin dashboard
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    /// default shortest absence that counts as sleep, in hours
+    pub sleep_min_hours: u32,
+    /// default longest absence that counts as sleep, in hours
+    pub sleep_max_hours: u32,
+    /// minutes since the last beat during which the status shows as active
+    pub active_minutes: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            sleep_min_hours: 4,
+            sleep_max_hours: 16,
+            active_minutes: 10,
+        }
+    }
+}
+
+impl Settings {
+    pub async fn get<'c, E>(user: i64, executor: E) -> Result<Self>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let values: HashMap<String, String> =
+            sqlx::query!("select key, value from settings where user = ?", user)
+                .fetch_all(executor)
+                .await?
+                .into_iter()
+                .map(|row| (row.key, row.value))
+                .collect();
+
+        let mut settings = Self::default();
+        macro_rules! read {
+            ($field:ident) => {
+                if let Some(value) = values.get(stringify!($field)).and_then(|v| v.parse().ok()) {
+                    settings.$field = value;
+                }
+            };
+        }
+        read!(sleep_min_hours);
+        read!(sleep_max_hours);
+        read!(active_minutes);
+
+        Ok(settings)
+    }
+
+    pub async fn save<'c, E>(&self, user: i64, executor: E) -> Result<()>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let values = [
+            ("sleep_min_hours", self.sleep_min_hours.to_string()),
+            ("sleep_max_hours", self.sleep_max_hours.to_string()),
+            ("active_minutes", self.active_minutes.to_string()),
+        ];
+
+        let mut query_builder = sqlx::QueryBuilder::new("insert into settings (user, key, value) ");
+        query_builder.push_values(values, |mut b, (key, value)| {
+            b.push_bind(user).push_bind(key).push_bind(value);
+        });
+        query_builder.push(" on conflict (user, key) do update set value = excluded.value");
+        query_builder.build().execute(executor).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::init_state, user::User};
+
+    #[tokio::test]
+    async fn can_save_settings() -> Result<()> {
+        let state = init_state().await;
+
+        assert_eq!(Settings::default(), Settings::get(1, &state.pool).await?);
+
+        let settings = Settings {
+            sleep_min_hours: 5,
+            sleep_max_hours: 12,
+            active_minutes: 3,
+        };
+        settings.save(1, &state.pool).await?;
+        assert_eq!(settings, Settings::get(1, &state.pool).await?);
+
+        // other users still have the defaults
+        User::set_password("bob", "hunter2", &state.pool).await?;
+        let bob = User::get_by_name("bob", &state.pool).await?.unwrap();
+        assert_eq!(
+            Settings::default(),
+            Settings::get(bob.id, &state.pool).await?
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Duration, NaiveDate, Timelike, Utc};
+
+use crate::absence::Absence;
+
+/// Bounds used to decide whether an absence counts as sleep
+#[derive(Clone, Copy, Debug)]
+pub struct SleepConfig {
+    /// shortest absence that counts as sleep
+    pub min: Duration,
+    /// longest absence that counts as sleep. anything longer is probably a trip or a dead device
+    pub max: Duration,
+}
+
+impl Default for SleepConfig {
+    fn default() -> Self {
+        Self {
+            min: Duration::hours(4),
+            max: Duration::hours(16),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SleepEpisode {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+impl SleepEpisode {
+    pub fn duration(&self) -> Duration {
+        self.end - self.start
+    }
+}
+
+/// Main sleep episode for a day, keyed by the day we woke up on
+pub struct SleepDay {
+    pub date: NaiveDate,
+    /// time we fell asleep
+    pub onset: DateTime<Utc>,
+    /// time we woke up
+    pub offset: DateTime<Utc>,
+}
+
+/// How the sleep schedule moves over time
+#[derive(Debug)]
+pub struct Drift {
+    /// how many hours later we fall asleep each day
+    pub hours_per_day: f64,
+    /// number of sleep onsets used for the estimate
+    pub samples: usize,
+}
+
+impl Drift {
+    pub fn hours_per_week(&self) -> f64 {
+        self.hours_per_day * 7.0
+    }
+
+    /// estimated length of a sleep-wake cycle, in hours
+    pub fn period(&self) -> f64 {
+        // each cycle moves the onset by `period - 24` hours, and a day holds `24 / period` cycles
+        24.0 * 24.0 / (24.0 - self.hours_per_day)
+    }
+}
+
+pub struct SleepAnalysis {
+    /// sleep episodes, oldest first
+    episodes: Vec<SleepEpisode>,
+}
+
+impl SleepAnalysis {
+    pub fn new<'a>(absences: impl IntoIterator<Item = &'a Absence>, config: SleepConfig) -> Self {
+        let mut episodes = absences
+            .into_iter()
+            .filter(|abs| {
+                let duration = Duration::seconds(abs.duration);
+                config.min <= duration && duration <= config.max
+            })
+            .map(|abs| SleepEpisode {
+                start: abs.start(),
+                end: abs.end(),
+            })
+            .collect::<Vec<_>>();
+        episodes.sort_unstable_by_key(|ep| ep.start);
+
+        Self { episodes }
+    }
+
+    pub fn episodes(&self) -> &[SleepEpisode] {
+        &self.episodes
+    }
+
+    /// get the main (longest) sleep episode for each day, newest first
+    pub fn days(&self) -> Vec<SleepDay> {
+        let mut days: BTreeMap<NaiveDate, &SleepEpisode> = BTreeMap::new();
+        for ep in &self.episodes {
+            let date = ep.end.date_naive();
+            match days.get(&date) {
+                Some(other) if other.duration() >= ep.duration() => {}
+                _ => {
+                    days.insert(date, ep);
+                }
+            }
+        }
+
+        days.into_iter()
+            .rev()
+            .map(|(date, ep)| SleepDay {
+                date,
+                onset: ep.start,
+                offset: ep.end,
+            })
+            .collect()
+    }
+
+    /// Estimate how much the sleep onset moves each day
+    ///
+    /// Onset times are unwrapped around the clock (so falling asleep at 23:00 and then at 01:00
+    /// counts as two hours later, not 22 hours earlier), then fitted with a least squares line
+    /// against the day they happened on
+    pub fn drift(&self) -> Option<Drift> {
+        let days = self.days();
+        if days.len() < 3 {
+            return None;
+        }
+
+        let first = days.last()?.onset;
+
+        let mut points: Vec<(f64, f64)> = Vec::with_capacity(days.len());
+        for day in days.iter().rev() {
+            let x = (day.onset - first).num_seconds() as f64 / 86400.0;
+            let clock = hour_of_day(day.onset);
+
+            // pick the representation of this clock time closest to the previous onset
+            let y = match points.last() {
+                Some((_, prev)) => clock + 24.0 * ((prev - clock) / 24.0).round(),
+                None => clock,
+            };
+
+            points.push((x, y));
+        }
+
+        let n = points.len() as f64;
+        let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+        let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+        let cov = points
+            .iter()
+            .map(|(x, y)| (x - mean_x) * (y - mean_y))
+            .sum::<f64>();
+        let var = points
+            .iter()
+            .map(|(x, _)| (x - mean_x).powi(2))
+            .sum::<f64>();
+
+        if var == 0.0 {
+            return None;
+        }
+
+        Some(Drift {
+            hours_per_day: cov / var,
+            samples: points.len(),
+        })
+    }
+}
+
+fn hour_of_day(date: DateTime<Utc>) -> f64 {
+    date.hour() as f64 + date.minute() as f64 / 60.0 + date.second() as f64 / 3600.0
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDateTime, TimeZone};
+
+    use super::*;
+
+    fn absence(end: DateTime<Utc>, duration: Duration) -> Absence {
+        Absence {
+            id: 0,
+            timestamp: end.naive_utc(),
+            duration: duration.num_seconds(),
+            begin_beat: 0,
+            end_beat: 0,
+        }
+    }
+
+    fn start() -> DateTime<Utc> {
+        Utc.from_utc_datetime(
+            &NaiveDateTime::parse_from_str("2024-01-01 23:00", "%Y-%m-%d %H:%M").unwrap(),
+        )
+    }
+
+    #[test]
+    fn only_keeps_absences_within_bounds() {
+        let now = start();
+        let absences = [
+            absence(now, Duration::hours(2)),
+            absence(now - Duration::days(1), Duration::hours(8)),
+            absence(now - Duration::days(2), Duration::hours(30)),
+        ];
+
+        let analysis = SleepAnalysis::new(&absences, SleepConfig::default());
+
+        assert_eq!(1, analysis.episodes().len());
+        assert_eq!(Duration::hours(8), analysis.episodes()[0].duration());
+    }
+
+    #[test]
+    fn picks_longest_episode_per_day() {
+        let now = start();
+        let absences = [
+            absence(now, Duration::hours(5)),
+            absence(now - Duration::hours(6), Duration::hours(7)),
+        ];
+
+        let days = SleepAnalysis::new(&absences, SleepConfig::default()).days();
+
+        assert_eq!(1, days.len());
+        assert_eq!(now - Duration::hours(13), days[0].onset);
+    }
+
+    #[test]
+    fn entrained_schedule_has_no_drift() {
+        let absences = (0..14)
+            .map(|i| absence(start() + Duration::days(i), Duration::hours(8)))
+            .collect::<Vec<_>>();
+
+        let drift = SleepAnalysis::new(&absences, SleepConfig::default())
+            .drift()
+            .unwrap();
+
+        assert_eq!(14, drift.samples);
+        assert!(drift.hours_per_day.abs() < 0.01);
+        assert!((drift.period() - 24.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn estimates_non_24_period() {
+        // a 25h cycle wraps around the clock in under a month
+        let absences = (0..30)
+            .map(|i| absence(start() + Duration::hours(25 * i), Duration::hours(8)))
+            .collect::<Vec<_>>();
+
+        let drift = SleepAnalysis::new(&absences, SleepConfig::default())
+            .drift()
+            .unwrap();
+
+        assert!((drift.period() - 25.0).abs() < 0.05);
+        assert!((drift.hours_per_week() - 7.0).abs() < 0.35);
+    }
+}
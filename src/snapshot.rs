@@ -0,0 +1,295 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::Result;
+use sqlx::SqlitePool;
+
+use crate::{beat::Beat, device::Device};
+
+/// What the home page shows about the beats of a user
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HomeSnapshot {
+    pub first_beat: Option<Beat>,
+    pub last_beat: Option<Beat>,
+    pub total_beats: i64,
+    /// number of beats of each device
+    pub device_beats: HashMap<i64, i64>,
+}
+
+impl HomeSnapshot {
+    pub async fn load(user: i64, pool: &SqlitePool) -> Result<Self> {
+        let first_beat = Beat::first_beat(user, pool).await?;
+        let last_beat = Beat::last_beat(user, pool).await?;
+        // beat counts of devices are kept up to date by the database
+        let device_beats: HashMap<i64, i64> = Device::get_all(user, pool)
+            .await?
+            .into_iter()
+            .map(|device| (device.id, device.beat_count))
+            .collect();
+
+        Ok(Self {
+            first_beat,
+            last_beat,
+            total_beats: device_beats.values().sum(),
+            device_beats,
+        })
+    }
+
+    fn record(&mut self, beat: &Beat) {
+        if self
+            .first_beat
+            .as_ref()
+            .is_none_or(|first| beat.timestamp < first.timestamp)
+        {
+            self.first_beat = Some(beat.clone());
+        }
+        if self
+            .last_beat
+            .as_ref()
+            .is_none_or(|last| beat.timestamp >= last.timestamp)
+        {
+            self.last_beat = Some(beat.clone());
+        }
+
+        self.total_beats += 1;
+        *self.device_beats.entry(beat.device).or_default() += 1;
+    }
+}
+
+/// Home page snapshot of each user, so viewing the home page doesn't need to look through all beats
+#[derive(Default)]
+pub struct HomeSnapshots(Mutex<Snapshots>);
+
+#[derive(Default)]
+struct Snapshots {
+    users: HashMap<i64, HomeSnapshot>,
+    /// how often the beats of each user changed, so a snapshot that was loaded while they did
+    /// isn't kept
+    generations: HashMap<i64, u64>,
+    /// writes of beats of each user that are being committed
+    writing: HashMap<i64, usize>,
+}
+
+impl Snapshots {
+    fn changed(&mut self, user: i64) {
+        *self.generations.entry(user).or_default() += 1;
+    }
+}
+
+/// New beats of a user that are being stored. a snapshot loaded meanwhile might already have them
+/// or not, so it isn't kept until the write is recorded or dropped
+pub struct SnapshotWrite<'a> {
+    snapshots: &'a HomeSnapshots,
+    user: i64,
+}
+
+impl SnapshotWrite<'_> {
+    /// Adds the beats to the snapshot, once they're committed
+    pub fn record(self, beats: &[Beat]) {
+        let mut snapshots = self.snapshots.0.lock().unwrap();
+        // snapshots that aren't loaded yet will see the beats when they are
+        if let Some(snapshot) = snapshots.users.get_mut(&self.user) {
+            for beat in beats {
+                snapshot.record(beat);
+            }
+        }
+    }
+}
+
+impl Drop for SnapshotWrite<'_> {
+    fn drop(&mut self) {
+        let mut snapshots = self.snapshots.0.lock().unwrap();
+        snapshots.changed(self.user);
+        if let Some(writing) = snapshots.writing.get_mut(&self.user) {
+            *writing -= 1;
+        }
+    }
+}
+
+impl HomeSnapshots {
+    /// Loads the snapshots of every user
+    pub async fn load(pool: &SqlitePool) -> Result<Self> {
+        let users = sqlx::query_scalar!("select id as \"id!\" from users")
+            .fetch_all(pool)
+            .await?;
+
+        let mut snapshots = HashMap::new();
+        for user in users {
+            snapshots.insert(user, HomeSnapshot::load(user, pool).await?);
+        }
+
+        Ok(Self(Mutex::new(Snapshots {
+            users: snapshots,
+            ..Default::default()
+        })))
+    }
+
+    /// Gets the snapshot of `user`, loading it if it isn't there
+    pub async fn get(&self, user: i64, pool: &SqlitePool) -> Result<HomeSnapshot> {
+        let generation = {
+            let snapshots = self.0.lock().unwrap();
+            if let Some(snapshot) = snapshots.users.get(&user) {
+                return Ok(snapshot.clone());
+            }
+            snapshots.generations.get(&user).copied()
+        };
+
+        let snapshot = HomeSnapshot::load(user, pool).await?;
+        let mut snapshots = self.0.lock().unwrap();
+        // beats stored while it was loading might be missing from it, or be added to it again
+        // once they're recorded, so it's loaded again next time
+        let writing = snapshots.writing.get(&user).copied().unwrap_or_default();
+        if snapshots.generations.get(&user).copied() == generation && writing == 0 {
+            snapshots.users.insert(user, snapshot.clone());
+        }
+        Ok(snapshot)
+    }
+
+    /// Starts storing new beats of `user`, before they're committed
+    pub fn write(&self, user: i64) -> SnapshotWrite<'_> {
+        let mut snapshots = self.0.lock().unwrap();
+        snapshots.changed(user);
+        *snapshots.writing.entry(user).or_default() += 1;
+
+        SnapshotWrite {
+            snapshots: self,
+            user,
+        }
+    }
+
+    /// Drops the snapshot of `user` after beats were removed, so it's loaded again when needed
+    pub fn invalidate(&self, user: i64) {
+        let mut snapshots = self.0.lock().unwrap();
+        snapshots.changed(user);
+        snapshots.users.remove(&user);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        routes::{batch::batch, beat::beat},
+        testing::init_state,
+    };
+
+    use ::axum_test::TestServer;
+    use axum::{
+        http::{HeaderName, HeaderValue},
+        routing::post,
+        Router,
+    };
+    use chrono::{TimeDelta, Utc};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn snapshots_stay_consistent() -> Result<()> {
+        let state = init_state().await;
+
+        for (id, token) in [(1, "my_token"), (2, "other_token")] {
+            Device {
+                id,
+                user: 1,
+                name: "test device".to_string(),
+                token: token.to_string(),
+                beat_count: 0,
+                visible: true,
+            }
+            .create(&state.pool)
+            .await?;
+        }
+        Beat {
+            id: 0,
+            device: 1,
+            timestamp: (Utc::now() - TimeDelta::days(2)).naive_utc(),
+        }
+        .create(&state.pool)
+        .await?;
+
+        let app = Router::new()
+            .route("/api/beat", post(beat))
+            .route("/api/batch", post(batch))
+            .with_state(Arc::clone(&state));
+        let server = TestServer::new(app).unwrap();
+
+        // load it before the beats are sent, so they have to be recorded
+        let snapshot = state.home_snapshots.get(1, &state.pool).await?;
+        assert_eq!(1, snapshot.total_beats);
+
+        let now = Utc::now();
+        let timestamps = [
+            now - TimeDelta::days(3),
+            now - TimeDelta::hours(5),
+            now - TimeDelta::minutes(1),
+        ]
+        .map(|timestamp| timestamp.naive_utc());
+        server
+            .post("/api/batch")
+            .add_header(
+                HeaderName::from_static("authorization"),
+                HeaderValue::from_static("other_token"),
+            )
+            .json(&json!({ "timestamps": timestamps }))
+            .await
+            .assert_status_ok();
+        for _ in 0..2 {
+            server
+                .post("/api/beat")
+                .add_header(
+                    HeaderName::from_static("authorization"),
+                    HeaderValue::from_static("my_token"),
+                )
+                .await
+                .assert_status_ok();
+        }
+
+        let snapshot = state.home_snapshots.get(1, &state.pool).await?;
+        assert_eq!(HomeSnapshot::load(1, &state.pool).await?, snapshot);
+        assert_eq!(6, snapshot.total_beats);
+        assert_eq!(Some(&3), snapshot.device_beats.get(&2));
+        assert_eq!(
+            Some(timestamps[0]),
+            snapshot.first_beat.map(|beat| beat.timestamp)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn doesnt_keep_snapshots_loaded_during_a_write() -> Result<()> {
+        let state = init_state().await;
+
+        Device {
+            id: 1,
+            user: 1,
+            name: "test device".to_string(),
+            token: "my_token".to_string(),
+            beat_count: 0,
+            visible: true,
+        }
+        .create(&state.pool)
+        .await?;
+
+        // the beat is committed before it's recorded, so a snapshot loaded in between has it
+        let write = state.home_snapshots.write(1);
+        let beat = Beat {
+            id: 0,
+            device: 1,
+            timestamp: Utc::now().naive_utc(),
+        }
+        .create(&state.pool)
+        .await?;
+        assert_eq!(
+            1,
+            state.home_snapshots.get(1, &state.pool).await?.total_beats
+        );
+        write.record(&[beat]);
+
+        let snapshot = state.home_snapshots.get(1, &state.pool).await?;
+        assert_eq!(1, snapshot.total_beats);
+        assert_eq!(HomeSnapshot::load(1, &state.pool).await?, snapshot);
+
+        Ok(())
+    }
+}
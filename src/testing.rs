@@ -1,10 +1,10 @@
 #![allow(dead_code)]
-use std::sync::{atomic::AtomicI64, Arc};
+use std::sync::Arc;
 
 use chrono::Utc;
 use sqlx::sqlite::SqlitePoolOptions;
 
-use crate::AppState;
+use crate::{absence::LongestAbsences, session::LoginAttempts, snapshot::HomeSnapshots, AppState};
 
 pub async fn init_state() -> Arc<AppState> {
     let pool = SqlitePoolOptions::new()
@@ -18,9 +18,17 @@ pub async fn init_state() -> Arc<AppState> {
         .await
         .expect("couldn't run migrations");
 
+    // everything in tests belongs to this user unless it says otherwise
+    sqlx::query!("insert into users (id, name, password_hash) values (1, 'annie', '')")
+        .execute(&pool)
+        .await
+        .expect("couldn't create test user");
+
     Arc::new(AppState {
         pool,
-        longest_absence: AtomicI64::new(0),
+        longest_absences: LongestAbsences::default(),
+        home_snapshots: HomeSnapshots::default(),
+        login_attempts: LoginAttempts::default(),
         start_time: Utc::now(),
     })
 }
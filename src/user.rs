@@ -0,0 +1,118 @@
+use anyhow::{anyhow, Result};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use sqlx::{Executor, Sqlite};
+
+/// A person with their own devices and heartbeat, who can log into the admin dashboard
+pub struct User {
+    pub id: i64,
+    pub name: String,
+    pub password_hash: String,
+}
+
+impl User {
+    pub fn hash_password(password: &str) -> Result<String> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|err| anyhow!("failed to hash password: {err}"))?;
+        Ok(hash.to_string())
+    }
+
+    pub fn verify_password(&self, password: &str) -> bool {
+        let Ok(hash) = PasswordHash::new(&self.password_hash) else {
+            return false;
+        };
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    }
+
+    pub async fn get_by_id<'c, E>(id: i64, executor: E) -> Result<Option<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let user = sqlx::query_as!(
+            User,
+            "select id as \"id!\", name, password_hash from users where id = ?",
+            id
+        )
+        .fetch_optional(executor)
+        .await?;
+
+        Ok(user)
+    }
+
+    /// Gets the first user, who owns the pages at the root of the site
+    pub async fn get_default<'c, E>(executor: E) -> Result<Option<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let user = sqlx::query_as!(
+            User,
+            "select id as \"id!\", name, password_hash from users order by id limit 1"
+        )
+        .fetch_optional(executor)
+        .await?;
+
+        Ok(user)
+    }
+
+    pub async fn get_by_name<'c, E>(name: &str, executor: E) -> Result<Option<Self>>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let user = sqlx::query_as!(
+            User,
+            "select id as \"id!\", name, password_hash from users where name = ?",
+            name
+        )
+        .fetch_optional(executor)
+        .await?;
+
+        Ok(user)
+    }
+
+    /// Creates the user, or changes its password if it already exists
+    pub async fn set_password<'c, E>(name: &str, password: &str, executor: E) -> Result<()>
+    where
+        E: Executor<'c, Database = Sqlite>,
+    {
+        let hash = Self::hash_password(password)?;
+        sqlx::query!(
+            "insert into users (name, password_hash) values (?, ?)
+            on conflict (name) do update set password_hash = excluded.password_hash",
+            name,
+            hash
+        )
+        .execute(executor)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::init_state;
+
+    #[tokio::test]
+    async fn can_set_password() -> Result<()> {
+        let state = init_state().await;
+
+        User::set_password("annie", "hunter2", &state.pool).await?;
+        let user = User::get_by_name("annie", &state.pool).await?.unwrap();
+        assert!(user.verify_password("hunter2"));
+        assert!(!user.verify_password("hunter3"));
+
+        User::set_password("annie", "hunter3", &state.pool).await?;
+        let user = User::get_by_name("annie", &state.pool).await?.unwrap();
+        assert!(!user.verify_password("hunter2"));
+        assert!(user.verify_password("hunter3"));
+
+        Ok(())
+    }
+}
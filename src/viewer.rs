@@ -0,0 +1,215 @@
+use std::{collections::HashMap, convert::Infallible, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path},
+    http::request::Parts,
+};
+use chrono::{DateTime, Utc};
+
+use crate::{
+    device::Device, errors::AppError, proxy::base_path, session::Session, user::User, AppState,
+};
+
+/// Granularity of times shown to anonymous visitors, in seconds
+pub const PUBLIC_GRANULARITY: i64 = 15 * 60;
+
+/// How someone sees a user's pages
+///
+/// The user themselves, when logged in or sending one of their device tokens, is a private viewer. Everyone else gets
+/// a coarsened view: hidden devices are left out, device names are replaced, and times are rounded to
+/// [`PUBLIC_GRANULARITY`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Viewer {
+    Public,
+    Private,
+}
+
+/// The user making a request, if they're logged in or send a device token
+pub struct Visitor(pub Option<i64>);
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Visitor {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Visitor::from_parts(parts, state).await)
+    }
+}
+
+impl Visitor {
+    async fn from_parts(parts: &Parts, state: &AppState) -> Self {
+        if let Some(session) = Session::from_headers(&parts.headers, state).await {
+            return Visitor(Some(session.user));
+        }
+
+        let Some(auth) = parts
+            .headers
+            .get("Authorization")
+            .and_then(|auth| auth.to_str().ok())
+        else {
+            return Visitor(None);
+        };
+
+        match Device::get_by_auth(auth, &state.pool).await {
+            Ok(Some(device)) => Visitor(Some(device.user)),
+            _ => Visitor(None),
+        }
+    }
+
+    /// how this visitor sees the pages of `user`
+    pub fn viewer_of(&self, user: i64) -> Viewer {
+        if self.0 == Some(user) {
+            Viewer::Private
+        } else {
+            Viewer::Public
+        }
+    }
+}
+
+/// The user whose heartbeat a page shows, and how the visitor sees it
+///
+/// Pages under `/u/:name` belong to that user. Pages at the root belong to the first user, so
+/// instances with a single user keep their urls
+pub struct Page {
+    pub owner: User,
+    pub viewer: Viewer,
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Page {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let params = Path::<HashMap<String, String>>::from_request_parts(parts, state)
+            .await
+            .map(|Path(params)| params)
+            .unwrap_or_default();
+
+        let owner = match params.get("name") {
+            Some(name) => User::get_by_name(name, &state.pool).await?,
+            None => User::get_default(&state.pool).await?,
+        }
+        .ok_or_else(|| AppError::html_from_str("there is no such user :3"))?;
+
+        let visitor = Visitor::from_parts(parts, state).await;
+
+        Ok(Page {
+            viewer: visitor.viewer_of(owner.id),
+            owner,
+        })
+    }
+}
+
+impl Page {
+    /// link to one of the owner's pages
+    pub fn link(&self, path: &str) -> String {
+        format!("{}/u/{}{path}", base_path(), self.owner.name)
+    }
+}
+
+impl Viewer {
+    pub fn is_private(&self) -> bool {
+        *self == Viewer::Private
+    }
+
+    /// amount of seconds times are rounded to for this viewer
+    pub fn granularity(&self) -> i64 {
+        match self {
+            Viewer::Public => PUBLIC_GRANULARITY,
+            Viewer::Private => 1,
+        }
+    }
+
+    /// rounds a time down to what this viewer is allowed to see
+    pub fn coarsen(&self, date: DateTime<Utc>) -> DateTime<Utc> {
+        let timestamp = date.timestamp();
+        let timestamp = timestamp - timestamp.rem_euclid(self.granularity());
+        DateTime::from_timestamp(timestamp, 0).unwrap_or(date)
+    }
+
+    /// rounds a duration in seconds down to what this viewer is allowed to see
+    pub fn coarsen_secs(&self, secs: i64) -> i64 {
+        secs - secs.rem_euclid(self.granularity())
+    }
+
+    /// filters and renames devices for this viewer
+    pub fn devices(&self, devices: Vec<Device>) -> Vec<Device> {
+        match self {
+            Viewer::Private => devices,
+            Viewer::Public => devices
+                .into_iter()
+                .filter(|device| device.visible)
+                .map(|device| Device {
+                    name: format!("device {}", device.id),
+                    ..device
+                })
+                .collect(),
+        }
+    }
+
+    /// whether this viewer can see a device, and how it's named
+    pub fn device(&self, device: Device) -> Option<Device> {
+        self.devices(vec![device]).pop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+
+    #[test]
+    fn coarsens_public_times() {
+        let date = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+
+        assert_eq!(date, Viewer::Private.coarsen(date));
+        assert_eq!(
+            0,
+            Viewer::Public.coarsen(date).timestamp() % PUBLIC_GRANULARITY
+        );
+        assert!(date - Viewer::Public.coarsen(date) < TimeDelta::minutes(15));
+
+        assert_eq!(1000, Viewer::Private.coarsen_secs(1000));
+        assert_eq!(900, Viewer::Public.coarsen_secs(1000));
+    }
+
+    #[test]
+    fn hides_devices_from_public() {
+        let devices = || {
+            vec![
+                Device {
+                    id: 1,
+                    user: 1,
+                    name: "laptop".to_string(),
+                    token: "a".to_string(),
+                    beat_count: 0,
+                    visible: true,
+                },
+                Device {
+                    id: 2,
+                    user: 1,
+                    name: "phone".to_string(),
+                    token: "b".to_string(),
+                    beat_count: 0,
+                    visible: false,
+                },
+            ]
+        };
+
+        let private = Viewer::Private.devices(devices());
+        assert_eq!(2, private.len());
+        assert_eq!("laptop", private[0].name);
+
+        let public = Viewer::Public.devices(devices());
+        assert_eq!(1, public.len());
+        assert_eq!("device 1", public[0].name);
+    }
+}